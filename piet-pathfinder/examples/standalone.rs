@@ -65,14 +65,7 @@ fn main() {
     let mut renderer = Renderer::new(device, &EmbeddedResourceLoader, mode, options);
 
     // Make a canvas. We're going to draw a house.
-    // let font_source_mem = font_kit::sources::mem::MemSource::empty();
-    // let font_source_sys = font_kit::source::SystemSource::new();
-    // let font_source =
-    //     std::sync::Arc::new(font_kit::sources::multi::MultiSource::from_sources(vec![
-    //         Box::new(font_source_mem),
-    //         Box::new(font_source_sys),
-    //     ]));
-    let font_source = Arc::new(piet_pathfinder::FontSource::new(vec![Box::new(font_kit::source::SystemSource::new())]));
+    let font_source = Arc::new(piet_pathfinder::FontSource::system());
     let font_context = CanvasFontContext::new(font_source.clone());
     let mut canvas = Canvas::new(window_size.to_f32()).get_context_2d(font_context);
     let mut piet_canvas = piet_pathfinder::PathFinderRenderContext::new(&mut canvas, font_source);
@@ -80,7 +73,7 @@ fn main() {
     draw_a_picture(&mut piet_canvas);
 
     // Render the canvas to screen.
-    let mut scene = SceneProxy::from_scene(canvas.into_canvas().into_scene(), level, RayonExecutor);
+    let mut scene = SceneProxy::from_scene(piet_canvas.into_scene().unwrap(), level, RayonExecutor);
     scene.build_and_render(&mut renderer, BuildOptions::default());
     gl_context.swap_buffers().unwrap();
 
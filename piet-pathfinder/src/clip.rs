@@ -0,0 +1,257 @@
+//! Composition of nested clips.
+//!
+//! Pathfinder's canvas replaces the clip path on every `clip_path` call
+//! instead of intersecting it with the one before, so the backend keeps the
+//! effective clip region itself and hands the canvas a single path for it.
+
+use std::cmp::Ordering;
+
+use pathfinder_canvas::{FillRule, Path2D};
+use piet::kurbo::{self, Affine, PathEl, Point, Shape};
+
+use crate::vec2f_from_point;
+
+/// A flattened clip region in device space.
+#[derive(Clone, Debug)]
+pub(crate) struct ClipRegion {
+    contours: Vec<Vec<Point>>,
+    fill_rule: FillRule,
+}
+
+impl ClipRegion {
+    /// Flattens `shape`, transformed into device space by `transform`.
+    pub(crate) fn new(
+        shape: &impl Shape,
+        transform: Affine,
+        tolerance: f64,
+        fill_rule: FillRule,
+    ) -> ClipRegion {
+        let mut contours: Vec<Vec<Point>> = Vec::new();
+        let elements = shape
+            .path_elements(tolerance)
+            .map(|element| transform * element);
+        kurbo::flatten(elements, tolerance, |element| match element {
+            PathEl::MoveTo(point) => contours.push(vec![point]),
+            PathEl::LineTo(point) => match contours.last_mut() {
+                Some(contour) => contour.push(point),
+                None => contours.push(vec![point]),
+            },
+            // Contours are always treated as closed when filling.
+            _ => {}
+        });
+        contours.retain(|contour| contour.len() > 2);
+        ClipRegion {
+            contours,
+            fill_rule,
+        }
+    }
+
+    pub(crate) fn fill_rule(&self) -> FillRule {
+        self.fill_rule
+    }
+
+    /// The region inside both `self` and `other`.
+    ///
+    /// When either region is a single convex polygon, which covers rects,
+    /// rounded rects and circles, the other is clipped to it. Otherwise
+    /// `other` is clipped to each of the trapezoids that make up `self`.
+    pub(crate) fn intersect(&self, other: &ClipRegion) -> ClipRegion {
+        if let Some(polygon) = self.convex_polygon() {
+            other.clipped_to(polygon)
+        } else if let Some(polygon) = other.convex_polygon() {
+            self.clipped_to(polygon)
+        } else {
+            // The trapezoids don't overlap, so inside each one only the
+            // contours clipped to it count towards the winding, which is
+            // `other`'s winding there.
+            let contours = self
+                .trapezoids()
+                .iter()
+                .flat_map(|trapezoid| other.clipped_to(trapezoid).contours)
+                .collect();
+            ClipRegion {
+                contours,
+                fill_rule: other.fill_rule,
+            }
+        }
+    }
+
+    /// Builds a device space path for the region.
+    pub(crate) fn to_path(&self) -> Path2D {
+        let mut path = Path2D::new();
+        for contour in &self.contours {
+            path.move_to(vec2f_from_point(contour[0]));
+            for point in &contour[1..] {
+                path.line_to(vec2f_from_point(*point));
+            }
+            path.close_path();
+        }
+        path
+    }
+
+    /// Splits the region into trapezoids that don't overlap and together
+    /// cover exactly what it fills.
+    ///
+    /// The region is cut into horizontal bands at every vertex and every
+    /// crossing of two edges, so that within a band the edges keep their
+    /// order, and the spans between them that the fill rule fills are kept.
+    fn trapezoids(&self) -> Vec<[Point; 4]> {
+        let edges: Vec<(Point, Point)> = self
+            .contours
+            .iter()
+            .flat_map(|contour| {
+                (0..contour.len()).map(move |i| (contour[i], contour[(i + 1) % contour.len()]))
+            })
+            .filter(|(a, b)| a.y != b.y)
+            .collect();
+        let mut ys: Vec<f64> = edges.iter().flat_map(|(a, b)| vec![a.y, b.y]).collect();
+        for (i, first) in edges.iter().enumerate() {
+            ys.extend(
+                edges[i + 1..]
+                    .iter()
+                    .filter_map(|second| crossing_y(*first, *second)),
+            );
+        }
+        ys.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        ys.dedup();
+
+        let mut trapezoids = Vec::new();
+        for band in ys.windows(2) {
+            let (y0, y1) = (band[0], band[1]);
+            let mut crossings: Vec<(f64, f64, i32)> = edges
+                .iter()
+                .filter(|(a, b)| a.y.min(b.y) <= y0 && a.y.max(b.y) >= y1)
+                .map(|&(a, b)| {
+                    let x_at = |y: f64| a.x + (b.x - a.x) * (y - a.y) / (b.y - a.y);
+                    let direction = if b.y > a.y { 1 } else { -1 };
+                    (x_at(y0), x_at(y1), direction)
+                })
+                .collect();
+            crossings.sort_by(|a, b| {
+                (a.0 + a.1)
+                    .partial_cmp(&(b.0 + b.1))
+                    .unwrap_or(Ordering::Equal)
+            });
+            let mut winding = 0;
+            for pair in crossings.windows(2) {
+                let (left, right) = (pair[0], pair[1]);
+                winding += left.2;
+                let filled = match self.fill_rule {
+                    FillRule::Winding => winding != 0,
+                    FillRule::EvenOdd => winding % 2 != 0,
+                };
+                // a span without area would give the clip no orientation.
+                if filled && (right.0 - left.0) + (right.1 - left.1) > 1e-9 {
+                    trapezoids.push([
+                        Point::new(left.0, y0),
+                        Point::new(right.0, y0),
+                        Point::new(right.1, y1),
+                        Point::new(left.1, y1),
+                    ]);
+                }
+            }
+        }
+        trapezoids
+    }
+
+    /// The region's only contour, if it is a convex polygon.
+    fn convex_polygon(&self) -> Option<&[Point]> {
+        match self.contours.as_slice() {
+            [contour] if is_convex(contour) => Some(contour),
+            _ => None,
+        }
+    }
+
+    /// Clips every contour against the convex `polygon`.
+    ///
+    /// Inside the polygon each clipped contour winds around a point as often
+    /// as the original did, so the result keeps `self`'s fill rule.
+    fn clipped_to(&self, polygon: &[Point]) -> ClipRegion {
+        let orientation = signed_area(polygon).signum();
+        let contours = self
+            .contours
+            .iter()
+            .map(|contour| {
+                let mut contour = contour.clone();
+                for (i, &a) in polygon.iter().enumerate() {
+                    let b = polygon[(i + 1) % polygon.len()];
+                    contour = clip_to_edge(&contour, a, b, orientation);
+                }
+                contour
+            })
+            .filter(|contour| contour.len() > 2)
+            .collect();
+        ClipRegion {
+            contours,
+            fill_rule: self.fill_rule,
+        }
+    }
+}
+
+/// One Sutherland-Hodgman step: keeps the part of `contour` on the inner side
+/// of the line through `a` and `b`.
+fn clip_to_edge(contour: &[Point], a: Point, b: Point, orientation: f64) -> Vec<Point> {
+    let side = |point: Point| (b - a).cross(point - a) * orientation;
+    let mut clipped = Vec::with_capacity(contour.len() + 1);
+    for (i, &current) in contour.iter().enumerate() {
+        let previous = contour[(i + contour.len() - 1) % contour.len()];
+        let (previous_side, current_side) = (side(previous), side(current));
+        if (previous_side >= 0.0) != (current_side >= 0.0) {
+            let t = previous_side / (previous_side - current_side);
+            clipped.push(previous.lerp(current, t));
+        }
+        if current_side >= 0.0 {
+            clipped.push(current);
+        }
+    }
+    clipped
+}
+
+/// The height at which two edges cross, if they do.
+fn crossing_y((a, b): (Point, Point), (c, d): (Point, Point)) -> Option<f64> {
+    let (ab, cd, ac) = (b - a, d - c, c - a);
+    let denominator = ab.cross(cd);
+    if denominator == 0.0 {
+        return None;
+    }
+    let t = ac.cross(cd) / denominator;
+    let u = ac.cross(ab) / denominator;
+    if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) {
+        Some(a.y + ab.y * t)
+    } else {
+        None
+    }
+}
+
+fn signed_area(polygon: &[Point]) -> f64 {
+    let mut area = 0.0;
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        area += a.to_vec2().cross(b.to_vec2());
+    }
+    area / 2.0
+}
+
+/// Whether `polygon` turns the same way at every vertex, and only once
+/// around in total, ignoring collinear points.
+fn is_convex(polygon: &[Point]) -> bool {
+    let mut orientation = 0.0;
+    let mut total_turn = 0.0;
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let c = polygon[(i + 2) % polygon.len()];
+        let (ab, bc) = (b - a, c - b);
+        let turn = ab.cross(bc).atan2(ab.dot(bc));
+        if turn.abs() < 1e-9 {
+            continue;
+        }
+        if orientation == 0.0 {
+            orientation = turn.signum();
+        } else if turn.signum() != orientation {
+            return false;
+        }
+        total_turn += turn;
+    }
+    // Stars also turn one way at every vertex, but more than once around.
+    (total_turn.abs() - 2.0 * std::f64::consts::PI).abs() < 1e-6
+}
@@ -0,0 +1,1852 @@
+//! Offscreen rendering helpers, for tests and image export.
+
+use std::sync::Arc;
+
+use euclid::default::Size2D;
+use gl::types::GLvoid;
+use pathfinder_canvas::{vec2f, vec2i, Canvas, CanvasFontContext, ColorF, Transform2F};
+use pathfinder_gl::{GLDevice, GLVersion};
+use pathfinder_gpu::{Device, TextureFormat};
+use pathfinder_renderer::concurrent::rayon::RayonExecutor;
+use pathfinder_renderer::gpu::options::{DestFramebuffer, RendererMode, RendererOptions};
+use pathfinder_renderer::gpu::renderer::Renderer;
+use pathfinder_renderer::options::{BuildOptions, RenderTransform};
+use pathfinder_resources::embedded::EmbeddedResourceLoader;
+use piet::kurbo::Size;
+use piet::{Error, RenderContext};
+use surfman::{Connection, ContextAttributeFlags, ContextAttributes, SurfaceAccess, SurfaceType};
+
+use crate::{unpremultiply, FontSource, PathFinderRenderContext};
+
+/// Renders `draw` offscreen and returns the result encoded as a PNG.
+///
+/// `size` is the size of the drawing in piet's coordinate space; the output
+/// image is `size * scale` pixels, with a transparent background. The
+/// context's device pixel ratio is set to `scale`.
+///
+/// Like [`render_to_image`], this renders through the software adapter, so
+/// that every entry point draws a scene the same way.
+pub fn render_to_png(
+    size: Size,
+    scale: f64,
+    draw: impl FnOnce(&mut PathFinderRenderContext) -> Result<(), Error>,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = device_size(size, scale);
+    let data = render_pixels(size, scale, draw)?;
+    encode_png(&data, width, height)
+}
+
+/// Encodes non-premultiplied RGBA rows, starting from the top, as a PNG.
+fn encode_png(data: &[u8], width: usize, height: usize) -> Result<Vec<u8>, Error> {
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(data))
+        .map_err(|err| Error::BackendError(Box::new(err)))?;
+    Ok(png_data)
+}
+
+/// Renders `draw` offscreen into an in-memory RGBA image, without a GPU.
+///
+/// Pathfinder has no CPU rasterizer of its own, so this goes through surfman's
+/// software OpenGL adapter (e.g. Mesa's llvmpipe), which must be installed. This
+/// makes it usable in CI and other environments without a graphics device.
+///
+/// The image has straight alpha, like the images of [`Image::raw`], so it can
+/// be passed back to `make_image` as [`ImageFormat::RgbaSeparate`].
+///
+/// [`Image::raw`]: crate::Image::raw
+/// [`ImageFormat::RgbaSeparate`]: piet::ImageFormat::RgbaSeparate
+pub fn render_to_image(
+    size: Size,
+    scale: f64,
+    draw: impl FnOnce(&mut PathFinderRenderContext) -> Result<(), Error>,
+) -> Result<image::RgbaImage, Error> {
+    let (width, height) = device_size(size, scale);
+    let data = render_pixels(size, scale, draw)?;
+    image::RgbaImage::from_raw(width as u32, height as u32, data).ok_or(Error::InvalidInput)
+}
+
+/// Renders `draw` like [`render_to_image`], but at `samples` times the scale,
+/// then averages each `samples` × `samples` block of pixels into one.
+///
+/// This smooths edges beyond what pathfinder's antialiasing does, such as
+/// for exported images, at the cost of rendering `samples`² as many pixels.
+/// A `samples` of 1 is the same as `render_to_image`.
+pub fn render_to_image_supersampled(
+    size: Size,
+    scale: f64,
+    samples: usize,
+    draw: impl FnOnce(&mut PathFinderRenderContext) -> Result<(), Error>,
+) -> Result<image::RgbaImage, Error> {
+    if samples == 0 {
+        return Err(Error::InvalidInput);
+    }
+    let (width, height) = device_size(size, scale);
+    let sampled_scale = scale * samples as f64;
+    let (sampled_width, _) = device_size(size, sampled_scale);
+    let sampled = render_premultiplied_pixels(size, sampled_scale, draw)?;
+    let mut data = downsample(&sampled, sampled_width, samples, (width, height));
+    unpremultiply(&mut data);
+    image::RgbaImage::from_raw(width as u32, height as u32, data).ok_or(Error::InvalidInput)
+}
+
+/// Renders `draw` into non-premultiplied RGBA rows, starting from the top.
+fn render_pixels(
+    size: Size,
+    scale: f64,
+    draw: impl FnOnce(&mut PathFinderRenderContext) -> Result<(), Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut data = render_premultiplied_pixels(size, scale, draw)?;
+    unpremultiply(&mut data);
+    Ok(data)
+}
+
+/// Renders `draw` into premultiplied RGBA rows, starting from the top, as
+/// pathfinder leaves them in the framebuffer.
+fn render_premultiplied_pixels(
+    size: Size,
+    scale: f64,
+    draw: impl FnOnce(&mut PathFinderRenderContext) -> Result<(), Error>,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = device_size(size, scale);
+
+    let font_source = Arc::new(FontSource::system());
+    let font_context = CanvasFontContext::new(font_source.clone());
+    let mut rendering_context =
+        Canvas::new(vec2f(size.width as f32, size.height as f32)).get_context_2d(font_context);
+    let mut piet_context = PathFinderRenderContext::builder(&mut rendering_context, font_source)
+        .device_pixel_ratio(scale)
+        .build();
+    draw(&mut piet_context)?;
+    let antialias = piet_context.antialias();
+    let mut scene = piet_context.into_scene()?;
+
+    let connection = Connection::new().map_err(surfman_error)?;
+    let adapter = connection
+        .create_software_adapter()
+        .map_err(surfman_error)?;
+    let mut device = connection.create_device(&adapter).map_err(surfman_error)?;
+    let context_attributes = ContextAttributes {
+        version: surfman::GLVersion::new(3, 3),
+        flags: ContextAttributeFlags::empty(),
+    };
+    let context_descriptor = device
+        .create_context_descriptor(&context_attributes)
+        .map_err(surfman_error)?;
+    let mut context = device
+        .create_context(&context_descriptor, None)
+        .map_err(surfman_error)?;
+
+    let result = (|| -> Result<Vec<u8>, Error> {
+        let surface = device
+            .create_surface(
+                &context,
+                SurfaceAccess::GPUOnly,
+                SurfaceType::Generic {
+                    size: Size2D::new(width as i32, height as i32),
+                },
+            )
+            .map_err(surfman_error)?;
+        device
+            .bind_surface_to_context(&mut context, surface)
+            .map_err(|(err, _surface)| surfman_error(err))?;
+        device
+            .make_context_current(&context)
+            .map_err(surfman_error)?;
+        gl::load_with(|symbol_name| device.get_proc_address(&context, symbol_name));
+
+        let gl_device = GLDevice::new(GLVersion::GL3, 0);
+        let texture =
+            gl_device.create_texture(TextureFormat::RGBA8, vec2i(width as i32, height as i32));
+        let framebuffer = gl_device.create_framebuffer(texture);
+        let mode = RendererMode::default_for_device(&gl_device);
+        let options = RendererOptions {
+            background_color: Some(ColorF::transparent_black()),
+            dest: DestFramebuffer::Other(framebuffer),
+            ..RendererOptions::default()
+        };
+        let mut renderer = Renderer::new(gl_device, &EmbeddedResourceLoader, mode, options);
+        scene.build_and_render(
+            &mut renderer,
+            BuildOptions {
+                transform: RenderTransform::Transform2D(
+                    Transform2F::default().scale(vec2f(scale as f32, scale as f32)),
+                ),
+                ..BuildOptions::default()
+            },
+            RayonExecutor,
+        );
+
+        let mut data: Vec<u8> = vec![0; width * height * 4];
+        unsafe {
+            gl::ReadPixels(
+                0,
+                0,
+                width as i32,
+                height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                data.as_mut_ptr() as *mut GLvoid,
+            );
+        }
+        // GL reads rows starting from the bottom of the framebuffer.
+        flip_rows(&mut data, width * 4);
+        if !antialias {
+            snap_coverage(&mut data);
+        }
+        Ok(data)
+    })();
+
+    device
+        .destroy_context(&mut context)
+        .map_err(surfman_error)?;
+    result
+}
+
+fn device_size(size: Size, scale: f64) -> (usize, usize) {
+    let size = (size * scale).ceil();
+    (size.width as usize, size.height as usize)
+}
+
+/// Reverses the order of the rows in `data`, keeping each row's pixels intact.
+fn flip_rows(data: &mut [u8], stride: usize) {
+    let height = data.len() / stride;
+    for row in 0..height / 2 {
+        let (top, bottom) = data.split_at_mut((height - row - 1) * stride);
+        top[row * stride..(row + 1) * stride].swap_with_slice(&mut bottom[..stride]);
+    }
+}
+
+/// Box filters premultiplied RGBA `data`, whose rows are `width` pixels long,
+/// averaging each `factor` × `factor` block into one pixel of an image of
+/// `size`. Parts of the blocks past the edges of `data` count as transparent.
+fn downsample(data: &[u8], width: usize, factor: usize, size: (usize, usize)) -> Vec<u8> {
+    let height = data.len() / 4 / width;
+    let (out_width, out_height) = size;
+    let mut out = Vec::with_capacity(out_width * out_height * 4);
+    for out_y in 0..out_height {
+        for out_x in 0..out_width {
+            let mut sum = [0u32; 4];
+            for y in (out_y * factor..(out_y + 1) * factor).filter(|y| *y < height) {
+                for x in (out_x * factor..(out_x + 1) * factor).filter(|x| *x < width) {
+                    let pixel = &data[(y * width + x) * 4..][..4];
+                    for (sum, channel) in sum.iter_mut().zip(pixel) {
+                        *sum += *channel as u32;
+                    }
+                }
+            }
+            let count = (factor * factor) as u32;
+            out.extend(sum.iter().map(|sum| ((sum + count / 2) / count) as u8));
+        }
+    }
+    out
+}
+
+/// Makes every pixel of premultiplied RGBA `data` either opaque or fully
+/// transparent, depending on whether it was at least half covered.
+fn snap_coverage(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha < 128 {
+            pixel.copy_from_slice(&[0, 0, 0, 0]);
+        } else {
+            for channel in &mut pixel[..3] {
+                *channel = (*channel as u32 * 255 / alpha).min(255) as u8;
+            }
+            pixel[3] = 255;
+        }
+    }
+}
+
+fn surfman_error(err: surfman::Error) -> Error {
+    Error::BackendError(format!("surfman error: {:?}", err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BlendMode, Brush, GradientInterpolation, GradientSpread, ImageBrush, ImageSmoothingQuality,
+        SweepGradient, TileMode,
+    };
+    use pathfinder_canvas::FillStyle;
+    use pathfinder_color::ColorU;
+    use piet::kurbo::{
+        Affine, BezPath, Circle, Ellipse, Line, Point, QuadBez, Rect, RoundedRect, Shape, Vec2,
+    };
+    use piet::{
+        Color, FixedLinearGradient, GradientStop, GradientStops, ImageFormat, InterpolationMode,
+        RadialGradient, StrokeStyle, Text as _, TextAttribute, TextLayout as _,
+        TextLayoutBuilder as _,
+    };
+    use std::f64::consts::FRAC_PI_4;
+
+    /// Draws a 2x2 black and white checkerboard scaled up to 16x16 pixels.
+    fn scaled_checkerboard(interp: InterpolationMode) -> image::RgbaImage {
+        #[rustfmt::skip]
+        let pixels = [
+            0, 0, 0, 255, 255, 255, 255, 255,
+            255, 255, 255, 255, 0, 0, 0, 255,
+        ];
+        render_to_image(Size::new(16.0, 16.0), 1.0, |rc| {
+            let image = rc.make_image(2, 2, &pixels, ImageFormat::RgbaSeparate)?;
+            rc.draw_image(&image, Rect::new(0.0, 0.0, 16.0, 16.0), interp);
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    /// Fills a 16x16 canvas through a clip with a hole in the middle under the
+    /// even-odd rule, and returns the alpha at the center.
+    fn donut_clip_center_alpha(even_odd: bool) -> u8 {
+        let mut donut = Rect::new(0.0, 0.0, 16.0, 16.0).to_path(0.1);
+        donut.extend(Rect::new(4.0, 4.0, 12.0, 12.0).path_elements(0.1));
+        let image = render_to_image(Size::new(16.0, 16.0), 1.0, |rc| {
+            if even_odd {
+                rc.clip_even_odd(donut);
+            } else {
+                rc.clip(donut);
+            }
+            rc.fill(Rect::new(0.0, 0.0, 16.0, 16.0), &Color::WHITE);
+            Ok(())
+        })
+        .unwrap();
+        image.get_pixel(8, 8)[3]
+    }
+
+    #[test]
+    fn even_odd_clip_leaves_a_hole() {
+        assert_eq!(donut_clip_center_alpha(false), 255);
+        assert_eq!(donut_clip_center_alpha(true), 0);
+    }
+
+    #[test]
+    fn nested_clips_of_different_rules_intersect() {
+        let mut donut = Circle::new((30.0, 30.0), 25.0).to_path(0.1);
+        donut.extend(Circle::new((30.0, 30.0), 10.0).path_elements(0.1));
+        let image = render_to_image(Size::new(60.0, 60.0), 1.0, |rc| {
+            rc.clip(Rect::new(10.0, 10.0, 50.0, 50.0));
+            rc.save()?;
+            rc.clip_even_odd(donut);
+            rc.fill(Rect::new(0.0, 0.0, 60.0, 30.0), &Color::WHITE);
+            rc.restore()?;
+            // Only the rect clip is left after restoring.
+            rc.fill(Rect::new(0.0, 30.0, 60.0, 60.0), &Color::WHITE);
+            Ok(())
+        })
+        .unwrap();
+        // The ring inside the rect is painted, but not the hole, nor the part
+        // of the ring outside the rect.
+        assert_eq!(image.get_pixel(30, 15)[3], 255);
+        assert_eq!(image.get_pixel(30, 27)[3], 0);
+        assert_eq!(image.get_pixel(30, 7)[3], 0);
+        assert_eq!(image.get_pixel(7, 25)[3], 0);
+        assert_eq!(image.get_pixel(30, 33)[3], 255);
+        assert_eq!(image.get_pixel(45, 48)[3], 255);
+    }
+
+    #[test]
+    fn nested_ring_clips_intersect_exactly() {
+        let ring = |center: (f64, f64)| {
+            let mut ring = Circle::new(center, 25.0).to_path(0.1);
+            ring.extend(Circle::new(center, 10.0).path_elements(0.1));
+            ring
+        };
+        let image = render_to_image(Size::new(90.0, 60.0), 1.0, |rc| {
+            rc.clip_even_odd(ring((30.0, 30.0)));
+            rc.clip_even_odd(ring((60.0, 30.0)));
+            rc.fill(Rect::new(0.0, 0.0, 90.0, 60.0), &Color::WHITE);
+            Ok(())
+        })
+        .unwrap();
+        // only where the rings overlap is painted.
+        assert_eq!(image.get_pixel(45, 30)[3], 255);
+        assert_eq!(image.get_pixel(8, 30)[3], 0);
+        assert_eq!(image.get_pixel(82, 30)[3], 0);
+        // the first ring's hole, and the second's.
+        assert_eq!(image.get_pixel(30, 30)[3], 0);
+        assert_eq!(image.get_pixel(52, 30)[3], 0);
+        // the second ring, inside the first one's bounding box but not in it.
+        assert_eq!(image.get_pixel(52, 8)[3], 0);
+    }
+
+    #[test]
+    fn even_odd_fill_of_two_subpaths_leaves_a_hole() {
+        let mut ring = Rect::new(2.0, 2.0, 18.0, 18.0).to_path(0.1);
+        ring.extend(Rect::new(6.0, 6.0, 14.0, 14.0).path_elements(0.1));
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            rc.fill_even_odd(ring, &Color::BLACK);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(4, 10)[3], 255);
+        assert_eq!(image.get_pixel(10, 10)[3], 0);
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+    }
+
+    #[test]
+    fn batched_lines_match_individual_strokes() {
+        let lines: Vec<Line> = (0..100)
+            .map(|i| {
+                let (x, y) = ((i % 10) as f64 * 10.0 + 1.5, (i / 10) as f64 * 10.0 + 5.0);
+                Line::new((x, y), (x + 6.0, y + (i % 3) as f64))
+            })
+            .collect();
+        let draw = |batched: bool| {
+            render_to_image(Size::new(100.0, 100.0), 1.0, |rc| {
+                if batched {
+                    rc.stroke_lines(&lines, &Color::BLACK, 1.5);
+                } else {
+                    for line in &lines {
+                        rc.stroke(*line, &Color::BLACK, 1.5);
+                    }
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(draw(true).into_raw(), draw(false).into_raw());
+    }
+
+    #[test]
+    fn hairlines_are_one_pixel_wide_at_any_scale() {
+        let column_coverage = |scale: f64| {
+            let image = render_to_image(Size::new(20.0, 20.0), scale, |rc| {
+                rc.stroke_hairline(Line::new((0.0, 10.3), (20.0, 10.3)), &Color::BLACK);
+                Ok(())
+            })
+            .unwrap();
+            let x = image.width() / 2;
+            (0..image.height())
+                .map(|y| image.get_pixel(x, y)[3] as f64 / 255.0)
+                .sum::<f64>()
+        };
+        for &scale in &[1.0, 2.0] {
+            let coverage = column_coverage(scale);
+            assert!(
+                (coverage - 1.0).abs() < 0.05,
+                "coverage {} at {}x",
+                coverage,
+                scale
+            );
+        }
+    }
+
+    #[test]
+    fn lines_without_antialiasing_have_hard_edges() {
+        let draw = |antialias: bool| {
+            render_to_image(Size::new(40.0, 40.0), 1.0, |rc| {
+                rc.set_antialias(antialias);
+                rc.stroke(Line::new((2.0, 3.0), (37.0, 31.0)), &Color::BLACK, 2.0);
+                Ok(())
+            })
+            .unwrap()
+        };
+        let is_partial = |pixel: &image::Rgba<u8>| pixel[3] != 0 && pixel[3] != 255;
+        assert!(draw(true).pixels().any(is_partial));
+        let aliased = draw(false);
+        assert!(!aliased.pixels().any(is_partial));
+        assert!(aliased.pixels().any(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn strokes_without_width_paint_nothing() {
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            let rect = Rect::new(2.0, 2.0, 18.0, 18.0);
+            rc.stroke(rect, &Color::BLACK, 0.0);
+            rc.stroke(rect, &Color::BLACK, -3.0);
+            let style = StrokeStyle::new().dash_pattern(&[2.0, 2.0]);
+            rc.stroke_styled(rect, &Color::BLACK, 0.0, &style);
+            rc.stroke_lines(&[Line::new((0.0, 10.0), (20.0, 10.0))], &Color::BLACK, 0.0);
+            Ok(())
+        })
+        .unwrap();
+        assert!(image.pixels().all(|pixel| pixel[3] == 0));
+    }
+
+    #[test]
+    fn dashed_lines_alternate_with_the_pattern() {
+        let image = render_to_image(Size::new(100.0, 40.0), 1.0, |rc| {
+            let style = StrokeStyle::new()
+                .dash_pattern(&[10.0, 5.0])
+                .dash_offset(3.0);
+            rc.stroke_styled(
+                Line::new((0.0, 10.0), (100.0, 10.0)),
+                &Color::BLACK,
+                4.0,
+                &style,
+            );
+            // the next stroke is solid again.
+            rc.stroke(Line::new((0.0, 30.0), (100.0, 30.0)), &Color::BLACK, 4.0);
+            Ok(())
+        })
+        .unwrap();
+        for x in 0..100 {
+            // the pattern starts 3 into the first dash, so its edges are at
+            // whole pixels.
+            let painted = (x as f64 + 0.5 + 3.0) % 15.0 < 10.0;
+            let alpha = image.get_pixel(x, 10)[3];
+            if painted {
+                assert!(alpha >= 250, "gap at {}", x);
+            } else {
+                assert!(alpha <= 5, "dash at {}", x);
+            }
+            assert!(
+                image.get_pixel(x, 30)[3] >= 250,
+                "gap in solid line at {}",
+                x
+            );
+        }
+    }
+
+    #[test]
+    fn round_capped_empty_dashes_are_dots() {
+        let image = render_to_image(Size::new(100.0, 20.0), 1.0, |rc| {
+            let style = StrokeStyle::new()
+                .dash_pattern(&[0.0, 15.0])
+                .line_cap(piet::LineCap::Round);
+            rc.stroke_styled(
+                Line::new((5.0, 10.0), (95.0, 10.0)),
+                &Color::BLACK,
+                6.0,
+                &style,
+            );
+            Ok(())
+        })
+        .unwrap();
+        for i in 0..6 {
+            let center = 5 + i * 15;
+            assert_eq!(image.get_pixel(center, 10)[3], 255, "dot at {}", center);
+            // A square cap would cover this corner of the dot.
+            assert!(image.get_pixel(center + 2, 7)[3] < 128);
+            assert_eq!(image.get_pixel(center + 7, 10)[3], 0);
+        }
+    }
+
+    #[test]
+    fn clearing_everything_fills_it_with_the_color() {
+        let image = render_to_image(Size::new(20.0, 10.0), 2.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 5.0, 5.0), &Color::BLACK);
+            rc.clear(None, Color::rgb8(255, 0, 0));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.dimensions(), (40, 20));
+        assert!(image.pixels().all(|pixel| pixel.0 == [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn clearing_ignores_the_clip() {
+        let image = render_to_image(Size::new(20.0, 10.0), 1.0, |rc| {
+            rc.clip(Rect::new(0.0, 0.0, 5.0, 5.0));
+            rc.clear(None, Color::rgb8(255, 0, 0));
+            rc.clear(Rect::new(10.0, 0.0, 20.0, 10.0), Color::BLACK);
+            // the clip is still there for what is drawn afterwards.
+            rc.fill(Rect::new(0.0, 5.0, 10.0, 10.0), &Color::WHITE);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(2, 2).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(7, 7).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(15, 5).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn cleared_regions_ignore_the_transform() {
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            rc.transform(Affine::translate((7.0, 7.0)));
+            rc.clear(Rect::new(0.0, 0.0, 10.0, 10.0), Color::BLACK);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(2, 2)[3], 255);
+        assert_eq!(image.get_pixel(9, 9)[3], 255);
+        assert_eq!(image.get_pixel(12, 12)[3], 0);
+
+        // a translucent color replaces what was there instead of blending.
+        let translucent = Color::rgba8(0, 0, 255, 128);
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 20.0, 20.0), &Color::rgb8(255, 0, 0));
+            rc.transform(Affine::translate((7.0, 7.0)));
+            rc.clear(Rect::new(0.0, 0.0, 10.0, 10.0), translucent);
+            Ok(())
+        })
+        .unwrap();
+        let [r, g, b, a] = image.get_pixel(5, 5).0;
+        assert_eq!((r, g), (0, 0));
+        assert!(
+            b >= 254 && (a as i32 - 128).abs() <= 1,
+            "{:?}",
+            [r, g, b, a]
+        );
+        assert_eq!(image.get_pixel(15, 15).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn solid_brushes_keep_their_alpha() {
+        let draw = |color: Color| {
+            render_to_image(Size::new(10.0, 10.0), 1.0, |rc| {
+                rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::WHITE);
+                let brush = rc.solid_brush(color);
+                rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &brush);
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            draw(Color::rgb8(0, 0, 255)).get_pixel(5, 5).0,
+            [0, 0, 255, 255]
+        );
+        let blended = draw(Color::rgba8(255, 0, 0, 128)).get_pixel(5, 5).0;
+        assert_eq!((blended[0], blended[3]), (255, 255));
+        for &channel in &blended[1..3] {
+            assert!((channel as i32 - 127).abs() <= 1, "{:?}", blended);
+        }
+    }
+
+    #[test]
+    fn brush_with_alpha_scales_solid_colors() {
+        let image = render_to_image(Size::new(10.0, 10.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::WHITE);
+            let brush = rc.solid_brush(Color::rgb8(255, 0, 0)).with_alpha(0.5);
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+        let blended = image.get_pixel(5, 5).0;
+        assert_eq!((blended[0], blended[3]), (255, 255));
+        for &channel in &blended[1..3] {
+            assert!((channel as i32 - 127).abs() <= 1, "{:?}", blended);
+        }
+        assert!(matches!(
+            Brush::Solid(0xff00_00ff).with_alpha(2.0),
+            Brush::Solid(0xff00_00ff)
+        ));
+        assert!(matches!(
+            Brush::Solid(0xff00_00ff).with_alpha(-1.0),
+            Brush::Solid(0xff00_0000)
+        ));
+    }
+
+    /// Fills a pentagram, drawn as one continuous path, and returns the alpha
+    /// at its center and inside its top point.
+    fn pentagram_coverage(even_odd: bool) -> (u8, u8) {
+        let image = render_to_image(Size::new(60.0, 60.0), 1.0, |rc| {
+            let mut star = BezPath::new();
+            for i in 0..5 {
+                let angle =
+                    -std::f64::consts::FRAC_PI_2 + i as f64 * 4.0 * std::f64::consts::PI / 5.0;
+                let point = Point::new(30.0, 30.0) + Vec2::from_angle(angle) * 25.0;
+                if i == 0 {
+                    star.move_to(point);
+                } else {
+                    star.line_to(point);
+                }
+            }
+            star.close_path();
+            if even_odd {
+                rc.fill_even_odd(&star, &Color::BLACK);
+            } else {
+                rc.fill(&star, &Color::BLACK);
+            }
+            Ok(())
+        })
+        .unwrap();
+        (image.get_pixel(30, 30)[3], image.get_pixel(30, 12)[3])
+    }
+
+    #[test]
+    fn circles_and_ellipses_are_symmetric() {
+        let image = render_to_image(Size::new(80.0, 40.0), 1.0, |rc| {
+            rc.fill(Circle::new((20.0, 20.0), 15.0), &Color::BLACK);
+            rc.fill_ellipse(Ellipse::new((60.0, 20.0), (15.0, 10.0), 0.0), &Color::BLACK);
+            Ok(())
+        })
+        .unwrap();
+        let alpha = |x: u32, y: u32| image.get_pixel(x, y)[3] as i32;
+        for y in 0..40 {
+            for x in 0..40 {
+                // the circle is symmetric about both axes and its diagonal,
+                // the ellipse about both axes.
+                assert!((alpha(x, y) - alpha(39 - x, y)).abs() <= 2, "{}, {}", x, y);
+                assert!((alpha(x, y) - alpha(x, 39 - y)).abs() <= 2, "{}, {}", x, y);
+                assert!((alpha(x, y) - alpha(y, x)).abs() <= 2, "{}, {}", x, y);
+                let (ex, ey) = (x + 40, y);
+                assert!((alpha(ex, ey) - alpha(119 - ex, ey)).abs() <= 2);
+                assert!((alpha(ex, ey) - alpha(ex, 39 - ey)).abs() <= 2);
+            }
+        }
+        assert_eq!(alpha(20, 20), 255);
+        assert_eq!(alpha(20, 3), 0);
+        assert_eq!(alpha(60, 12), 255);
+        assert_eq!(alpha(60, 8), 0);
+    }
+
+    #[test]
+    fn rounded_rects_match_their_curves() {
+        let rounded_rect = RoundedRect::new(10.0, 10.0, 70.0, 50.0, 20.0);
+        let draw = |native: bool| {
+            render_to_image(Size::new(80.0, 60.0), 1.0, |rc| {
+                if native {
+                    rc.fill(rounded_rect, &Color::BLACK);
+                } else {
+                    rc.fill(rounded_rect.to_path(0.01), &Color::BLACK);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        let (native, curves) = (draw(true), draw(false));
+        for (a, b) in native.pixels().zip(curves.pixels()) {
+            assert!((a[3] as i32 - b[3] as i32).abs() <= 8, "{:?} {:?}", a, b);
+        }
+        // the corners are round, and the middle of each edge filled.
+        assert_eq!(native.get_pixel(11, 11)[3], 0);
+        assert_eq!(native.get_pixel(40, 10)[3], 255);
+        assert_eq!(native.get_pixel(10, 30)[3], 255);
+    }
+
+    #[test]
+    fn rounded_rects_without_radii_match_rects() {
+        let rect = Rect::new(2.5, 3.25, 17.75, 12.5);
+        let draw = |rounded: bool| {
+            render_to_image(Size::new(20.0, 16.0), 1.0, |rc| {
+                if rounded {
+                    rc.fill(RoundedRect::from_rect(rect, 0.0), &Color::BLACK);
+                } else {
+                    rc.fill(rect, &Color::BLACK);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(draw(true).into_raw(), draw(false).into_raw());
+    }
+
+    #[test]
+    fn pentagram_center_depends_on_the_fill_rule() {
+        // the central pentagon is wound around twice, so only the nonzero
+        // winding rule fills it; the points are wound once and always filled.
+        assert_eq!(pentagram_coverage(false), (255, 255));
+        assert_eq!(pentagram_coverage(true), (0, 255));
+    }
+
+    #[test]
+    fn layer_images_can_be_drawn_more_than_once() {
+        let image = render_to_image(Size::new(50.0, 20.0), 1.0, |rc| {
+            rc.begin_layer(Size::new(20.0, 20.0));
+            rc.fill(Rect::new(0.0, 0.0, 20.0, 20.0), &Color::rgb8(255, 0, 0));
+            let layer = rc.end_layer_as_image()?;
+            rc.draw_layer_image(&layer, Rect::new(0.0, 0.0, 20.0, 20.0));
+            rc.draw_layer_image(&layer, Rect::new(30.0, 0.0, 50.0, 20.0));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(10, 10).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(25, 10)[3], 0);
+        assert_eq!(image.get_pixel(40, 10).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn layer_images_match_drawing_directly() {
+        let draw = |rc: &mut PathFinderRenderContext| {
+            rc.fill(Rect::new(5.0, 5.0, 25.0, 25.0), &Color::rgb8(255, 0, 0));
+            rc.fill(
+                Circle::new((25.0, 25.0), 10.0),
+                &Color::rgba8(0, 0, 255, 128),
+            );
+        };
+        let direct = render_to_image(Size::new(40.0, 40.0), 1.0, |rc| {
+            draw(rc);
+            Ok(())
+        })
+        .unwrap();
+        let layered = render_to_image(Size::new(40.0, 40.0), 1.0, |rc| {
+            rc.begin_layer(Size::new(40.0, 40.0));
+            draw(rc);
+            let image = rc.end_layer_as_image()?;
+            rc.draw_layer_image(&image, Rect::new(0.0, 0.0, 40.0, 40.0));
+            Ok(())
+        })
+        .unwrap();
+        // compared premultiplied, since the colors of nearly clear pixels
+        // don't matter.
+        let premultiplied = |image: image::RgbaImage| {
+            crate::Image::from_rgba(40, 40, image.into_raw())
+                .unwrap()
+                .premultiplied_bytes()
+        };
+        let (direct, layered) = (premultiplied(direct), premultiplied(layered));
+        for (direct, layered) in direct.chunks(4).zip(layered.chunks(4)) {
+            for (a, b) in direct.iter().zip(layered) {
+                assert!(
+                    (*a as i32 - *b as i32).abs() <= 2,
+                    "{:?} != {:?}",
+                    direct,
+                    layered
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn open_paths_are_stroked_without_closing_them() {
+        let draw = |closed: bool| {
+            render_to_image(Size::new(60.0, 60.0), 1.0, |rc| {
+                let mut path = BezPath::new();
+                path.move_to((10.0, 10.0));
+                path.line_to((10.0, 50.0));
+                path.line_to((50.0, 50.0));
+                if closed {
+                    path.close_path();
+                }
+                rc.stroke(path, &Color::BLACK, 4.0);
+                Ok(())
+            })
+            .unwrap()
+        };
+        // The middle of the segment a closed path adds between the ends.
+        assert_eq!(draw(false).get_pixel(30, 30)[3], 0);
+        assert_eq!(draw(true).get_pixel(30, 30)[3], 255);
+    }
+
+    #[test]
+    fn long_miters_fall_back_to_bevels() {
+        let draw = |limit: f64| {
+            render_to_image(Size::new(140.0, 90.0), 1.0, |rc| {
+                let mut path = BezPath::new();
+                path.move_to((10.0, 50.0));
+                path.line_to((90.0, 45.0));
+                path.line_to((10.0, 40.0));
+                let style = StrokeStyle::new().line_join(piet::LineJoin::Miter { limit });
+                rc.stroke_styled(path, &Color::BLACK, 4.0, &style);
+                Ok(())
+            })
+            .unwrap()
+        };
+        // The miter of this corner is about 16 times the stroke width long.
+        assert_eq!(draw(4.0).get_pixel(98, 45)[3], 0);
+        assert_eq!(draw(20.0).get_pixel(98, 45)[3], 255);
+    }
+
+    #[test]
+    fn quadratic_curves_match_their_cubic_forms() {
+        let quads = [
+            QuadBez::new((5.0, 30.0), (30.0, -10.0), (55.0, 30.0)),
+            QuadBez::new((55.0, 30.0), (30.0, 70.0), (5.0, 30.0)),
+        ];
+        let draw = |cubic: bool| {
+            render_to_image(Size::new(60.0, 60.0), 1.0, |rc| {
+                let mut path = BezPath::new();
+                path.move_to(quads[0].p0);
+                for quad in &quads {
+                    if cubic {
+                        let raised = quad.raise();
+                        path.curve_to(raised.p1, raised.p2, raised.p3);
+                    } else {
+                        path.quad_to(quad.p1, quad.p2);
+                    }
+                }
+                rc.fill(path, &Color::BLACK);
+                Ok(())
+            })
+            .unwrap()
+        };
+        let (quadratic, cubic) = (draw(false), draw(true));
+        for (a, b) in quadratic.pixels().zip(cubic.pixels()) {
+            assert!((a[3] as i32 - b[3] as i32).abs() <= 8, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn supersampling_smooths_hard_edges() {
+        let draw = |rc: &mut PathFinderRenderContext| {
+            rc.set_antialias(false);
+            let mut path = BezPath::new();
+            path.move_to((0.0, 0.0));
+            path.line_to((40.0, 0.0));
+            path.line_to((0.0, 30.0));
+            rc.fill(path, &Color::BLACK);
+            Ok(())
+        };
+        let size = Size::new(40.0, 40.0);
+        let is_partial = |pixel: &image::Rgba<u8>| pixel[3] != 0 && pixel[3] != 255;
+        let aliased = render_to_image(size, 1.0, draw).unwrap();
+        assert!(!aliased.pixels().any(is_partial));
+        let supersampled = render_to_image_supersampled(size, 1.0, 4, draw).unwrap();
+        assert_eq!(supersampled.dimensions(), aliased.dimensions());
+        let mut levels: Vec<u8> = supersampled
+            .pixels()
+            .filter(|pixel| is_partial(pixel))
+            .map(|pixel| pixel[3])
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+        assert!(levels.len() > 4, "{:?}", levels);
+    }
+
+    #[test]
+    fn downsampling_averages_blocks() {
+        let data = [
+            [255, 0, 0, 255],
+            [0, 0, 0, 0],
+            [0, 0, 255, 255],
+            [255, 0, 0, 255],
+            [0, 0, 0, 0],
+            [0, 0, 255, 255],
+        ]
+        .concat();
+        assert_eq!(
+            downsample(&data, 3, 2, (2, 1)),
+            vec![128, 0, 0, 128, 0, 0, 128, 128]
+        );
+    }
+
+    #[test]
+    fn flipping_rows_keeps_their_pixels_in_order() {
+        let mut data = vec![1, 2, 3, 4, 5, 6];
+        flip_rows(&mut data, 2);
+        assert_eq!(data, vec![5, 6, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn exported_images_are_the_right_way_up() {
+        let image = render_to_image(Size::new(8.0, 8.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 8.0, 2.0), &Color::rgb8(255, 0, 0));
+            rc.fill(Rect::new(0.0, 6.0, 8.0, 8.0), &Color::rgb8(0, 0, 255));
+            Ok(())
+        })
+        .unwrap();
+        let png_data = encode_png(image.as_raw(), 8, 8).unwrap();
+        let (info, mut reader) = png::Decoder::new(png_data.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(pixels[..4], [255, 0, 0, 255]);
+        assert_eq!(pixels[pixels.len() - 4..], [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn rendered_images_can_be_drawn_again() {
+        let color = Color::rgba8(0, 0, 255, 128);
+        let first = render_to_image(Size::new(4.0, 4.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 4.0, 4.0), &color);
+            Ok(())
+        })
+        .unwrap();
+        let pixel = first.get_pixel(2, 2).0;
+        assert!(pixel[2] >= 254, "{:?}", pixel);
+        assert!((pixel[3] as i32 - 128).abs() <= 1, "{:?}", pixel);
+
+        let second = render_to_image(Size::new(4.0, 4.0), 1.0, |rc| {
+            let image = rc.make_image(4, 4, first.as_raw(), ImageFormat::RgbaSeparate)?;
+            rc.draw_image(
+                &image,
+                Rect::new(0.0, 0.0, 4.0, 4.0),
+                InterpolationMode::NearestNeighbor,
+            );
+            Ok(())
+        })
+        .unwrap();
+        let (before, after) = (first.get_pixel(2, 2).0, second.get_pixel(2, 2).0);
+        for (a, b) in before.iter().zip(&after) {
+            assert!(
+                (*a as i32 - *b as i32).abs() <= 2,
+                "{:?} {:?}",
+                before,
+                after
+            );
+        }
+    }
+
+    #[test]
+    fn exported_pngs_have_straight_alpha() {
+        let png_data = render_to_png(Size::new(4.0, 4.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 4.0, 4.0), &Color::rgba8(255, 0, 0, 128));
+            Ok(())
+        })
+        .unwrap();
+        let (info, mut reader) = png::Decoder::new(png_data.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; info.buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let pixel = &pixels[(2 * 4 + 2) * 4..][..4];
+        assert!(pixel[0] >= 254, "{:?}", pixel);
+        assert_eq!(pixel[1..3], [0, 0]);
+        assert!((pixel[3] as i32 - 128).abs() <= 1, "{:?}", pixel);
+    }
+
+    #[test]
+    fn restored_fill_styles_are_set_again() {
+        let red = Color::rgb8(255, 0, 0);
+        let image = render_to_image(Size::new(30.0, 10.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &red);
+            rc.save()?;
+            rc.fill(Rect::new(10.0, 0.0, 20.0, 10.0), &Color::rgb8(0, 0, 255));
+            rc.restore()?;
+            rc.fill(Rect::new(20.0, 0.0, 30.0, 10.0), &red);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(5, 5).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(15, 5).0, [0, 0, 255, 255]);
+        assert_eq!(image.get_pixel(25, 5).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn opacity_layer_does_not_double_darken_overlaps() {
+        let image = render_to_image(Size::new(30.0, 10.0), 1.0, |rc| {
+            rc.push_opacity_layer(0.5);
+            rc.fill(Rect::new(0.0, 0.0, 20.0, 10.0), &Color::BLACK);
+            rc.fill(Rect::new(10.0, 0.0, 30.0, 10.0), &Color::BLACK);
+            rc.pop_layer()
+        })
+        .unwrap();
+        let single = image.get_pixel(5, 5)[3];
+        let overlap = image.get_pixel(15, 5)[3];
+        assert!((single as i32 - 128).abs() <= 1, "alpha {}", single);
+        assert_eq!(single, overlap);
+    }
+
+    #[test]
+    fn opacity_layers_keep_the_clip() {
+        let image = render_to_image(Size::new(30.0, 10.0), 1.0, |rc| {
+            rc.clip(Rect::new(0.0, 0.0, 10.0, 10.0));
+            rc.push_opacity_layer(0.5);
+            rc.fill(Rect::new(0.0, 0.0, 30.0, 10.0), &Color::BLACK);
+            rc.pop_layer()
+        })
+        .unwrap();
+        assert!((image.get_pixel(5, 5)[3] as i32 - 128).abs() <= 1);
+        assert_eq!(image.get_pixel(20, 5)[3], 0);
+    }
+
+    /// Blends a gray fill over a gray background and returns the result's red.
+    fn blended_gray(mode: BlendMode) -> u8 {
+        let gray = Color::grey(0.5);
+        let image = render_to_image(Size::new(10.0, 10.0), 1.0, |rc| {
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &gray);
+            rc.fill_blended(Rect::new(0.0, 0.0, 10.0, 10.0), &gray, mode);
+            Ok(())
+        })
+        .unwrap();
+        image.get_pixel(5, 5)[0]
+    }
+
+    #[test]
+    fn multiply_darkens_and_screen_lightens() {
+        let normal = blended_gray(BlendMode::Normal);
+        assert!(blended_gray(BlendMode::Multiply) < normal);
+        assert!(blended_gray(BlendMode::Screen) > normal);
+    }
+
+    /// Fills a wide rect with a black to white gradient that spans its first
+    /// tenth, and returns the red at `x`.
+    fn short_gradient_at(spread: GradientSpread, x: u32) -> u8 {
+        let image = render_to_image(Size::new(100.0, 10.0), 1.0, |rc| {
+            let gradient = FixedLinearGradient {
+                start: Point::new(0.0, 0.0),
+                end: Point::new(10.0, 0.0),
+                stops: (Color::BLACK, Color::WHITE).to_gradient_stops(),
+            };
+            let brush = rc.gradient_with_spread(gradient, spread)?;
+            rc.fill(Rect::new(0.0, 0.0, 100.0, 10.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+        image.get_pixel(x, 5)[0]
+    }
+
+    #[test]
+    fn repeat_gradient_tiles_its_stops() {
+        assert!(short_gradient_at(GradientSpread::Pad, 95) > 250);
+        let repeated = short_gradient_at(GradientSpread::Repeat, 95);
+        assert!(repeated > 100 && repeated < 160, "red {}", repeated);
+        assert!(short_gradient_at(GradientSpread::Repeat, 51) < 40);
+    }
+
+    /// Returns the red in the middle of a black to white gradient.
+    fn gradient_midpoint(interpolation: GradientInterpolation) -> u8 {
+        let image = render_to_image(Size::new(100.0, 4.0), 1.0, |rc| {
+            rc.set_gradient_interpolation(interpolation);
+            let gradient = FixedLinearGradient {
+                start: Point::new(0.0, 0.0),
+                end: Point::new(100.0, 0.0),
+                stops: (Color::BLACK, Color::WHITE).to_gradient_stops(),
+            };
+            let brush = rc.gradient(gradient)?;
+            rc.fill(Rect::new(0.0, 0.0, 100.0, 4.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+        image.get_pixel(50, 2)[0]
+    }
+
+    #[test]
+    fn linear_gradient_interpolation_lightens_the_midpoint() {
+        let srgb = gradient_midpoint(GradientInterpolation::Srgb);
+        assert!(srgb > 118 && srgb < 138, "red {}", srgb);
+        // half of the light is about 188 once encoded as sRGB.
+        let linear = gradient_midpoint(GradientInterpolation::Linear);
+        assert!(linear > 178 && linear < 198, "red {}", linear);
+    }
+
+    #[test]
+    fn unsorted_gradient_stops_render_like_sorted_ones() {
+        let render = |stops: Vec<GradientStop>| {
+            render_to_image(Size::new(40.0, 4.0), 1.0, |rc| {
+                let gradient = FixedLinearGradient {
+                    start: Point::new(0.0, 0.0),
+                    end: Point::new(40.0, 0.0),
+                    stops,
+                };
+                let brush = rc.gradient(gradient)?;
+                rc.fill(Rect::new(0.0, 0.0, 40.0, 4.0), &brush);
+                Ok(())
+            })
+            .unwrap()
+        };
+        let stop = |pos, color| GradientStop { pos, color };
+        let sorted = render(vec![
+            stop(0.0, Color::rgb8(255, 0, 0)),
+            stop(0.5, Color::rgb8(0, 255, 0)),
+            stop(1.0, Color::rgb8(0, 0, 255)),
+        ]);
+        let unsorted = render(vec![
+            stop(1.0, Color::rgb8(0, 0, 255)),
+            stop(0.0, Color::rgb8(255, 0, 0)),
+            stop(0.5, Color::rgb8(0, 255, 0)),
+        ]);
+        assert_eq!(sorted.into_raw(), unsorted.into_raw());
+    }
+
+    #[test]
+    fn fill_path_iter_matches_fill() {
+        let mut path = BezPath::new();
+        path.move_to((4.0, 4.0));
+        path.quad_to((30.0, 0.0), (28.0, 28.0));
+        path.curve_to((20.0, 30.0), (10.0, 20.0), (4.0, 28.0));
+        path.close_path();
+        let render = |iter: bool| {
+            render_to_image(Size::new(32.0, 32.0), 1.0, |rc| {
+                let brush = rc.solid_brush(Color::rgb8(0, 128, 255));
+                if iter {
+                    rc.fill_path_iter(path.iter(), &brush);
+                } else {
+                    rc.fill(&path, &brush);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(render(true).into_raw(), render(false).into_raw());
+    }
+
+    #[test]
+    fn nan_coordinates_dont_poison_later_draws() {
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            let mut path = BezPath::new();
+            path.move_to((0.0, 0.0));
+            path.line_to((0.0, 20.0));
+            path.line_to((f64::NAN, 20.0));
+            path.line_to((20.0, 20.0));
+            path.close_path();
+            rc.fill(&path, &Color::WHITE);
+            assert!(matches!(rc.status(), Err(piet::Error::InvalidInput)));
+            rc.fill(Rect::new(10.0, 0.0, 20.0, 20.0), &Color::rgb8(255, 0, 0));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(15, 5).0, [255, 0, 0, 255]);
+        // the valid part of the path, below its diagonal, is still drawn.
+        assert_eq!(image.get_pixel(5, 15).0, [255, 255, 255, 255]);
+        assert_eq!(image.get_pixel(5, 2).0[3], 0);
+    }
+
+    #[test]
+    fn repeated_brushes_keep_the_fill_style_they_set() {
+        let image = render_to_image(Size::new(4.0, 1.0), 1.0, |rc| {
+            let brush = rc.solid_brush(Color::BLACK);
+            rc.fill(Rect::new(0.0, 0.0, 1.0, 1.0), &brush);
+            // changed behind the context's back, so the next fill only paints
+            // black if it sets the style again.
+            rc.canvas
+                .set_fill_style(FillStyle::Color(ColorU::new(255, 0, 0, 255)));
+            rc.fill(Rect::new(1.0, 0.0, 2.0, 1.0), &brush);
+            // restoring brings the red back, which isn't the white brush.
+            rc.save()?;
+            rc.fill(Rect::new(2.0, 0.0, 3.0, 1.0), &Color::WHITE);
+            rc.restore()?;
+            rc.fill(Rect::new(3.0, 0.0, 4.0, 1.0), &Color::WHITE);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(2, 0).0, [255, 255, 255, 255]);
+        assert_eq!(image.get_pixel(3, 0).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn sweep_gradient_follows_the_angle() {
+        let stops = vec![
+            GradientStop {
+                pos: 0.0,
+                color: Color::rgb8(255, 0, 0),
+            },
+            GradientStop {
+                pos: 1.0 / 3.0,
+                color: Color::rgb8(0, 255, 0),
+            },
+            GradientStop {
+                pos: 2.0 / 3.0,
+                color: Color::rgb8(0, 0, 255),
+            },
+            GradientStop {
+                pos: 1.0,
+                color: Color::rgb8(255, 0, 0),
+            },
+        ];
+        let image = render_to_image(Size::new(100.0, 100.0), 1.0, |rc| {
+            let brush = Brush::Sweep(SweepGradient::new((50.0, 50.0), 0.0, stops));
+            rc.fill(Circle::new((50.0, 50.0), 50.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+
+        // Sample at 0, 120 and 240 degrees, going clockwise.
+        let dominant_channel = |angle: f64| {
+            let point = Point::new(50.0, 50.0) + Vec2::from_angle(angle.to_radians()) * 40.0;
+            let pixel = image.get_pixel(point.x as u32, point.y as u32);
+            (0..3).max_by_key(|&channel| pixel[channel]).unwrap()
+        };
+        assert_eq!(dominant_channel(0.0), 0);
+        assert_eq!(dominant_channel(120.0), 1);
+        assert_eq!(dominant_channel(240.0), 2);
+    }
+
+    #[test]
+    fn unsorted_sweep_stops_render_like_sorted_ones() {
+        let render = |stops: Vec<GradientStop>| {
+            render_to_image(Size::new(40.0, 40.0), 1.0, |rc| {
+                let brush = Brush::Sweep(SweepGradient::new((20.0, 20.0), 0.0, stops));
+                rc.fill(Rect::new(0.0, 0.0, 40.0, 40.0), &brush);
+                Ok(())
+            })
+            .unwrap()
+        };
+        let stop = |pos, color| GradientStop { pos, color };
+        let sorted = render(vec![
+            stop(0.0, Color::rgb8(255, 0, 0)),
+            stop(0.5, Color::rgb8(0, 255, 0)),
+            stop(1.0, Color::rgb8(0, 0, 255)),
+        ]);
+        let unsorted = render(vec![
+            stop(0.5, Color::rgb8(0, 255, 0)),
+            stop(1.0, Color::rgb8(0, 0, 255)),
+            stop(0.0, Color::rgb8(255, 0, 0)),
+        ]);
+        assert_eq!(sorted.into_raw(), unsorted.into_raw());
+    }
+
+    #[test]
+    fn sweep_gradients_are_rasterized_at_the_device_scale() {
+        let stop = |pos, color| GradientStop { pos, color };
+        // red for the first half turn, then blue, meeting in hard edges
+        // along the horizontal through the center.
+        let stops = vec![
+            stop(0.0, Color::rgb8(255, 0, 0)),
+            stop(0.5, Color::rgb8(255, 0, 0)),
+            stop(0.5, Color::rgb8(0, 0, 255)),
+            stop(1.0, Color::rgb8(0, 0, 255)),
+        ];
+        let image = render_to_image(Size::new(100.0, 100.0), 2.0, |rc| {
+            let brush = Brush::Sweep(SweepGradient::new((50.0, 50.0), 0.0, stops));
+            rc.fill(Rect::new(0.0, 0.0, 100.0, 100.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+        // the device pixels on either side of the edge aren't blended, as
+        // they would be if the gradient had one pixel per unit.
+        let above = image.get_pixel(160, 99).0;
+        let below = image.get_pixel(160, 100).0;
+        assert!(above[2] >= 250 && above[0] <= 5, "{:?}", above);
+        assert!(below[0] >= 250 && below[2] <= 5, "{:?}", below);
+    }
+
+    #[test]
+    fn image_brush_tiles_the_image() {
+        // One red column and one blue one.
+        #[rustfmt::skip]
+        let pixels = [
+            255, 0, 0, 255, 0, 0, 255, 255,
+            255, 0, 0, 255, 0, 0, 255, 255,
+        ];
+        let image = render_to_image(Size::new(20.0, 2.0), 1.0, |rc| {
+            let image = rc.make_image(2, 2, &pixels, ImageFormat::RgbaSeparate)?;
+            let brush = Brush::Image(ImageBrush::new(image));
+            rc.fill(Rect::new(0.0, 0.0, 20.0, 2.0), &brush);
+            Ok(())
+        })
+        .unwrap();
+        for x in 0..20 {
+            let expected: [u8; 3] = if x % 2 == 0 { [255, 0, 0] } else { [0, 0, 255] };
+            assert_eq!(image.get_pixel(x, 1).0[..3], expected, "pixel {}", x);
+        }
+    }
+
+    #[test]
+    fn solid_rect_fast_path_matches_path_fill() {
+        let rect = Rect::new(2.5, 3.25, 17.75, 12.5);
+        let color = Color::rgba8(0x20, 0x80, 0xc0, 0xa0);
+        let draw = |as_path: bool| {
+            render_to_image(Size::new(20.0, 16.0), 1.0, |rc| {
+                if as_path {
+                    rc.fill(rect.to_path(0.1), &color);
+                } else {
+                    rc.fill(rect, &color);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(draw(false).into_raw(), draw(true).into_raw());
+    }
+
+    #[test]
+    fn unblurred_rect_is_crisp() {
+        let image = render_to_image(Size::new(20.0, 20.0), 1.0, |rc| {
+            rc.blurred_rect(Rect::new(5.0, 5.0, 15.0, 15.0), 0.0, &Color::BLACK);
+            rc.status()
+        })
+        .unwrap();
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let inside = (5..15).contains(&x) && (5..15).contains(&y);
+            assert_eq!(pixel[3], if inside { 255 } else { 0 }, "pixel {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn blurred_rect_fades_out() {
+        let image = render_to_image(Size::new(40.0, 40.0), 1.0, |rc| {
+            rc.blurred_rect(Rect::new(10.0, 10.0, 30.0, 30.0), 3.0, &Color::BLACK);
+            rc.status()
+        })
+        .unwrap();
+        assert!(image.get_pixel(20, 20)[3] > 250);
+        let edge = image.get_pixel(10, 20)[3];
+        assert!(edge > 64 && edge < 192, "alpha {}", edge);
+        assert_eq!(image.get_pixel(1, 20)[3], 0);
+    }
+
+    #[test]
+    fn text_is_drawn_below_its_origin() {
+        let mut height = 0.0;
+        let image = render_to_image(Size::new(100.0, 60.0), 1.0, |rc| {
+            let layout = rc.text().new_text_layout("Hello").build()?;
+            height = layout.size().height;
+            rc.draw_text(&layout, (10.0, 20.0));
+            Ok(())
+        })
+        .unwrap();
+        let painted: Vec<u32> = image
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[3] != 0)
+            .map(|(_, y, _)| y)
+            .collect();
+        assert!(!painted.is_empty());
+        assert!(painted.iter().all(|&y| y >= 20), "painted above the origin");
+        assert!(painted.iter().all(|&y| (y as f64) < 20.0 + height));
+    }
+
+    #[test]
+    fn text_in_missing_families_is_still_rendered() {
+        let image = render_to_image(Size::new(100.0, 60.0), 1.0, |rc| {
+            let layout = rc
+                .text()
+                .new_text_layout("Hello")
+                .font(
+                    piet::FontFamily::new_unchecked("Not A Real Font Family 5f3a"),
+                    16.0,
+                )
+                .build()?;
+            rc.draw_text(&layout, (10.0, 20.0));
+            Ok(())
+        })
+        .unwrap();
+        assert!(image.pixels().any(|pixel| pixel[3] != 0));
+    }
+
+    #[test]
+    fn wrapped_lines_are_drawn_in_their_own_bands() {
+        let mut lines = Vec::new();
+        let image = render_to_image(Size::new(100.0, 60.0), 1.0, |rc| {
+            let layout = rc
+                .text()
+                .new_text_layout("first line second line")
+                .max_width(80.0)
+                .build()?;
+            lines = (0..layout.line_count())
+                .filter_map(|line| layout.line_metric(line))
+                .collect();
+            rc.draw_text(&layout, (0.0, 0.0));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(lines.len(), 2);
+        let painted_rows: Vec<u32> = (0..image.height())
+            .filter(|&y| (0..image.width()).any(|x| image.get_pixel(x, y)[3] != 0))
+            .collect();
+        // runs of consecutive painted rows, as (first, last).
+        let mut bands: Vec<(u32, u32)> = Vec::new();
+        for &y in &painted_rows {
+            match bands.last_mut() {
+                Some(band) if band.1 + 1 == y => band.1 = y,
+                _ => bands.push((y, y)),
+            }
+        }
+        assert_eq!(bands.len(), 2, "{:?}", bands);
+        for (band, line) in bands.iter().zip(&lines) {
+            let (top, bottom) = (line.y_offset, line.y_offset + line.height);
+            assert!(
+                band.0 as f64 >= top && (band.1 as f64) < bottom,
+                "{:?}",
+                band
+            );
+        }
+    }
+
+    #[test]
+    fn baseline_origin_matches_top_left_origin() {
+        let draw = |at_baseline: bool| {
+            render_to_image(Size::new(100.0, 40.0), 1.0, |rc| {
+                let layout = rc.text().new_text_layout("Baseline").build()?;
+                let top_left = Point::new(10.0, 5.0);
+                if at_baseline {
+                    let baseline = layout.line_metric(0).unwrap().baseline;
+                    rc.draw_text_at_baseline(&layout, top_left + Vec2::new(0.0, baseline));
+                } else {
+                    rc.draw_text(&layout, top_left);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        assert_eq!(draw(true), draw(false));
+    }
+
+    #[test]
+    fn draw_text_with_offsets_each_glyph() {
+        let draw = |pos: Point, shift: Option<Vec2>| {
+            render_to_image(Size::new(100.0, 40.0), 1.0, |rc| {
+                let layout = rc.text().new_text_layout("Wavy").build()?;
+                match shift {
+                    Some(shift) => {
+                        let origins = layout.glyph_origins();
+                        rc.draw_text_with(&layout, pos, |index, position| {
+                            let expected = pos + origins[index].to_vec2();
+                            assert!((position - expected).hypot() < 1e-6);
+                            shift
+                        });
+                    }
+                    None => rc.draw_text(&layout, pos),
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        let close = |a: &image::RgbaImage, b: &image::RgbaImage| {
+            a.as_raw()
+                .iter()
+                .zip(b.as_raw())
+                .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1)
+        };
+        let plain = draw(Point::new(10.0, 5.0), None);
+        assert!(close(
+            &draw(Point::new(10.0, 5.0), Some(Vec2::ZERO)),
+            &plain
+        ));
+        // moving every glyph is the same as moving the layout.
+        let shifted = draw(Point::new(4.0, 2.0), Some(Vec2::new(6.0, 3.0)));
+        assert!(close(&shifted, &plain));
+    }
+
+    #[test]
+    fn shadows_of_centered_text_are_not_clipped() {
+        let image = render_to_image(Size::new(120.0, 80.0), 1.0, |rc| {
+            let layout = rc
+                .text()
+                .new_text_layout("Hi")
+                .default_attribute(TextAttribute::FontSize(24.0))
+                .max_width(120.0)
+                .alignment(piet::TextAlignment::Center)
+                .build()?;
+            let red = Color::rgb8(255, 0, 0);
+            rc.draw_text_with_shadow(&layout, (0.0, 0.0), red, Vec2::new(0.0, 40.0), 0.0);
+            rc.status()
+        })
+        .unwrap();
+        let columns = |is_match: &dyn Fn(&image::Rgba<u8>) -> bool| {
+            let matching = image
+                .enumerate_pixels()
+                .filter(|(_, _, pixel)| is_match(pixel))
+                .map(|(x, _, _)| x as i32);
+            matching.fold((i32::MAX, i32::MIN), |(x0, x1), x| (x0.min(x), x1.max(x)))
+        };
+        let text = columns(&|p| p[3] > 128 && p[0] < 100);
+        let shadow = columns(&|p| p[3] > 128 && p[0] > 150);
+        assert!(text.0 > 20 && text.0 < text.1, "text at {:?}", text);
+        assert!((shadow.0 - text.0).abs() <= 1, "{:?} {:?}", shadow, text);
+        assert!((shadow.1 - text.1).abs() <= 1, "{:?} {:?}", shadow, text);
+    }
+
+    #[test]
+    fn text_shadow_is_drawn_offset_beneath_the_text() {
+        let draw = |shadow: bool| {
+            render_to_image(Size::new(120.0, 50.0), 1.0, |rc| {
+                let layout = rc
+                    .text()
+                    .new_text_layout("Shadow")
+                    .default_attribute(TextAttribute::FontSize(24.0))
+                    .build()?;
+                if shadow {
+                    let red = Color::rgb8(255, 0, 0);
+                    rc.draw_text_with_shadow(&layout, (10.0, 5.0), red, Vec2::new(4.0, 4.0), 1.0);
+                } else {
+                    rc.draw_text(&layout, (10.0, 5.0));
+                }
+                rc.status()
+            })
+            .unwrap()
+        };
+        let (plain, shadowed) = (draw(false), draw(true));
+        let bounds = |image: &image::RgbaImage, is_match: &dyn Fn(&image::Rgba<u8>) -> bool| {
+            let matching = image
+                .enumerate_pixels()
+                .filter(|(_, _, pixel)| is_match(pixel))
+                .map(|(x, y, _)| (x as i32, y as i32));
+            matching.fold((i32::MAX, i32::MAX, i32::MIN, i32::MIN), |b, (x, y)| {
+                (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y))
+            })
+        };
+        let text = bounds(&plain, &|p| p[3] > 200);
+        let shadow = bounds(&shadowed, &|p| p[3] > 60 && p[0] > 150 && p[1] < 80);
+        assert!(text.0 < text.2, "no text drawn");
+        for (edge, (text, shadow)) in [
+            (text.0, shadow.0),
+            (text.1, shadow.1),
+            (text.2, shadow.2),
+            (text.3, shadow.3),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let shift = shadow - text;
+            assert!((1..=7).contains(&shift), "edge {} moved by {}", edge, shift);
+        }
+        // the text itself is drawn over its shadow.
+        for (plain, shadowed) in plain.pixels().zip(shadowed.pixels()) {
+            if plain[3] > 250 {
+                assert!(shadowed[0] < 60, "{:?}", shadowed);
+            }
+        }
+    }
+
+    #[test]
+    fn text_keeps_subpixel_positions() {
+        let draw = |x: f64| {
+            render_to_image(Size::new(100.0, 40.0), 1.0, |rc| {
+                let layout = rc.text().new_text_layout("Subpixel").build()?;
+                rc.draw_text(&layout, (x, 5.0));
+                Ok(())
+            })
+            .unwrap()
+        };
+        let whole = draw(10.0);
+        let half = draw(10.5);
+        assert_ne!(whole, half);
+        // a half pixel shift moves coverage right, rather than snapping.
+        let total_x = |image: &image::RgbaImage| -> f64 {
+            image
+                .enumerate_pixels()
+                .map(|(x, _, pixel)| x as f64 * pixel[3] as f64)
+                .sum::<f64>()
+                / image.pixels().map(|pixel| pixel[3] as f64).sum::<f64>()
+        };
+        let shift = total_x(&half) - total_x(&whole);
+        assert!((shift - 0.5).abs() < 0.1, "shifted by {}", shift);
+    }
+
+    #[test]
+    #[ignore = "needs an installed color emoji font"]
+    fn color_emoji_keep_their_colors() {
+        let mut has_color_font = false;
+        let image = render_to_image(Size::new(64.0, 64.0), 1.0, |rc| {
+            let layout = rc
+                .text()
+                .new_text_layout("\u{1f600}")
+                .default_attribute(TextAttribute::FontSize(48.0))
+                .build()?;
+            has_color_font = layout.color_glyphs().next().is_some();
+            rc.draw_text(&layout, (0.0, 0.0));
+            Ok(())
+        })
+        .unwrap();
+        assert!(has_color_font, "the emoji isn't from a color font");
+        let colors: std::collections::HashSet<_> = image
+            .pixels()
+            .filter(|p| p[3] > 200)
+            .map(|p| (p[0] >> 5, p[1] >> 5, p[2] >> 5))
+            .collect();
+        assert!(colors.len() >= 3, "only {} colors", colors.len());
+    }
+
+    #[test]
+    #[ignore = "needs an installed color emoji font"]
+    fn text_color_applies_to_text_but_not_color_emoji() {
+        let mut has_color_font = false;
+        let mut emoji_x = 0.0;
+        let image = render_to_image(Size::new(160.0, 48.0), 1.0, |rc| {
+            let layout = rc
+                .text()
+                .new_text_layout("Hi \u{1f600}")
+                .default_attribute(TextAttribute::FontSize(32.0))
+                .default_attribute(TextAttribute::TextColor(Color::rgb8(255, 0, 0)))
+                .build()?;
+            has_color_font = layout.color_glyphs().next().is_some();
+            emoji_x = layout.hit_test_text_position(3).point.x;
+            rc.draw_text(&layout, (0.0, 0.0));
+            Ok(())
+        })
+        .unwrap();
+        assert!(has_color_font, "the emoji isn't from a color font");
+        let opaque = |p: &&image::Rgba<u8>| p[3] > 200;
+        let (text, emoji): (Vec<_>, Vec<_>) = image
+            .enumerate_pixels()
+            .map(|(x, _, pixel)| (x as f64, pixel))
+            .partition(|(x, _)| *x < emoji_x);
+        let text: Vec<_> = text.iter().map(|(_, p)| *p).filter(opaque).collect();
+        let emoji: Vec<_> = emoji.iter().map(|(_, p)| *p).filter(opaque).collect();
+        assert!(!text.is_empty() && !emoji.is_empty());
+        assert!(text.iter().all(|p| p[0] > 200 && p[1] < 60 && p[2] < 60));
+        assert!(emoji.iter().any(|p| p[1] > 100), "the emoji is tinted red");
+    }
+
+    /// Draws a 64x64 one pixel checkerboard scaled down to 8x8 pixels, and
+    /// returns how far its pixels stray from the average gray.
+    fn downscaled_checkerboard_error(quality: ImageSmoothingQuality) -> u32 {
+        let pixels: Vec<u8> = (0..64 * 64)
+            .flat_map(|i| {
+                let value = if (i % 64 + i / 64) % 2 == 0 { 0 } else { 255 };
+                vec![value, value, value, 255]
+            })
+            .collect();
+        let image = render_to_image(Size::new(8.0, 8.0), 1.0, |rc| {
+            let image = rc.make_image(64, 64, &pixels, ImageFormat::RgbaSeparate)?;
+            rc.set_image_smoothing_quality(quality);
+            rc.draw_image(
+                &image,
+                Rect::new(0.0, 0.0, 8.0, 8.0),
+                InterpolationMode::Bilinear,
+            );
+            Ok(())
+        })
+        .unwrap();
+        image
+            .pixels()
+            .map(|p| (p[0] as i32 - 128).abs() as u32)
+            .sum()
+    }
+
+    #[test]
+    fn high_smoothing_quality_aliases_less() {
+        let low = downscaled_checkerboard_error(ImageSmoothingQuality::Low);
+        let high = downscaled_checkerboard_error(ImageSmoothingQuality::High);
+        assert!(high < low, "high {} >= low {}", high, low);
+    }
+
+    #[test]
+    fn transformed_image_is_rotated() {
+        let pixels = [255, 0, 0, 255].repeat(20 * 4);
+        let image = render_to_image(Size::new(50.0, 50.0), 1.0, |rc| {
+            let image = rc.make_image(20, 4, &pixels, ImageFormat::RgbaSeparate)?;
+            let transform = Affine::translate((25.0, 25.0)) * Affine::rotate(FRAC_PI_4);
+            rc.draw_image_transformed(&image, transform, InterpolationMode::Bilinear);
+            assert_eq!(rc.current_transform(), Affine::IDENTITY);
+            Ok(())
+        })
+        .unwrap();
+        // along the rotated image, and where it would have been unrotated.
+        assert_eq!(image.get_pixel(33, 34).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(40, 26)[3], 0);
+    }
+
+    #[test]
+    fn tiled_image_area_repeats_the_region() {
+        // The left half of each row is opaque, and the right half clear.
+        let pixels: Vec<u8> = (0..40 * 16)
+            .flat_map(|i| {
+                let alpha = if i % 40 < 8 { 255 } else { 0 };
+                vec![0, 0, 0, alpha]
+            })
+            .collect();
+        let draw = |tile_x: TileMode| {
+            render_to_image(Size::new(64.0, 16.0), 1.0, |rc| {
+                let image = rc.make_image(40, 16, &pixels, ImageFormat::RgbaSeparate)?;
+                rc.draw_image_tiled(
+                    &image,
+                    Rect::new(0.0, 0.0, 16.0, 16.0),
+                    Rect::new(0.0, 0.0, 64.0, 16.0),
+                    tile_x,
+                    TileMode::Repeat,
+                    InterpolationMode::NearestNeighbor,
+                );
+                Ok(())
+            })
+            .unwrap()
+        };
+        let repeated = draw(TileMode::Repeat);
+        for tile in 0..4 {
+            assert_eq!(repeated.get_pixel(tile * 16 + 4, 8)[3], 255);
+            assert_eq!(repeated.get_pixel(tile * 16 + 12, 8)[3], 0);
+        }
+        let mirrored = draw(TileMode::Mirror);
+        for tile in 0..4 {
+            let (opaque, clear) = if tile % 2 == 0 { (4, 12) } else { (12, 4) };
+            assert_eq!(mirrored.get_pixel(tile * 16 + opaque, 8)[3], 255);
+            assert_eq!(mirrored.get_pixel(tile * 16 + clear, 8)[3], 0);
+        }
+    }
+
+    #[test]
+    fn unit_radial_gradients_are_centered_on_each_shape() {
+        let circles = [
+            Circle::new((15.0, 15.0), 10.0),
+            Circle::new((60.0, 40.0), 30.0),
+        ];
+        let image = render_to_image(Size::new(100.0, 80.0), 1.0, |rc| {
+            let gradient = RadialGradient::new(0.5, (Color::WHITE, Color::BLACK));
+            for circle in &circles {
+                rc.fill(*circle, &gradient);
+            }
+            Ok(())
+        })
+        .unwrap();
+        for circle in &circles {
+            let bounds = circle.bounding_box();
+            let brightest = (bounds.y0 as u32..bounds.y1 as u32)
+                .flat_map(|y| (bounds.x0 as u32..bounds.x1 as u32).map(move |x| (x, y)))
+                .max_by_key(|&(x, y)| image.get_pixel(x, y)[0])
+                .unwrap();
+            let center = Point::new(brightest.0 as f64 + 0.5, brightest.1 as f64 + 0.5);
+            assert!(center.distance(circle.center) <= 1.0, "{:?}", brightest);
+            // The gradient is nearly black close to the edge of each circle.
+            let edge = circle.center + Vec2::new(circle.radius * 0.9, 0.0);
+            assert!(image.get_pixel(edge.x as u32, edge.y as u32)[0] < 51);
+        }
+    }
+
+    #[test]
+    fn large_images_drawn_many_times_match_their_source() {
+        let source = image::RgbaImage::from_fn(256, 256, |x, y| {
+            image::Rgba([x as u8, y as u8, (x ^ y) as u8, 255])
+        });
+        let image = render_to_image(Size::new(256.0, 256.0), 1.0, |rc| {
+            let image = rc.make_image(256, 256, source.as_raw(), ImageFormat::RgbaSeparate)?;
+            for _ in 0..100 {
+                rc.draw_image(
+                    &image,
+                    Rect::new(0.0, 0.0, 256.0, 256.0),
+                    InterpolationMode::NearestNeighbor,
+                );
+            }
+            Ok(())
+        })
+        .unwrap();
+        for (drawn, source) in image.pixels().zip(source.pixels()) {
+            for (a, b) in drawn.0.iter().zip(&source.0) {
+                assert!(
+                    (*a as i32 - *b as i32).abs() <= 1,
+                    "{:?} != {:?}",
+                    drawn,
+                    source
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_snapped_images_match_their_source() {
+        #[rustfmt::skip]
+        let pixels = [
+            0, 0, 0, 255, 255, 255, 255, 255,
+            255, 255, 255, 255, 0, 0, 0, 255,
+        ];
+        let draw = |snapped: bool| {
+            render_to_image(Size::new(10.0, 10.0), 1.0, |rc| {
+                let image = rc.make_image(2, 2, &pixels, ImageFormat::RgbaSeparate)?;
+                let rect = Rect::new(3.3, 4.7, 5.3, 6.7);
+                if snapped {
+                    rc.draw_image_pixel_snapped(&image, rect, InterpolationMode::Bilinear);
+                } else {
+                    rc.draw_image(&image, rect, InterpolationMode::Bilinear);
+                }
+                Ok(())
+            })
+            .unwrap()
+        };
+        let snapped = draw(true);
+        assert_eq!(snapped.get_pixel(3, 5).0, [0, 0, 0, 255]);
+        assert_eq!(snapped.get_pixel(4, 5).0, [255, 255, 255, 255]);
+        assert_eq!(snapped.get_pixel(3, 6).0, [255, 255, 255, 255]);
+        assert_eq!(snapped.get_pixel(4, 6).0, [0, 0, 0, 255]);
+        assert!(snapped
+            .enumerate_pixels()
+            .all(|(x, y, pixel)| ((3..5).contains(&x) && (5..7).contains(&y)) || pixel[3] == 0));
+        // without snapping, the image is spread over more pixels.
+        assert!(draw(false).pixels().filter(|pixel| pixel[3] != 0).count() > 4);
+    }
+
+    #[test]
+    fn tinted_white_icons_take_the_tint_color() {
+        // a white icon whose left half is opaque and right half clear.
+        let pixels: Vec<u8> = (0..8 * 8)
+            .flat_map(|i| {
+                let alpha = if i % 8 < 4 { 255 } else { 0 };
+                vec![255, 255, 255, alpha]
+            })
+            .collect();
+        let image = render_to_image(Size::new(8.0, 8.0), 1.0, |rc| {
+            let image = rc.make_image(8, 8, &pixels, ImageFormat::RgbaSeparate)?;
+            rc.draw_image_tinted(
+                &image,
+                Rect::new(0.0, 0.0, 8.0, 8.0),
+                Color::rgb8(0, 0, 255),
+                InterpolationMode::NearestNeighbor,
+            );
+            Ok(())
+        })
+        .unwrap();
+        for (x, _, pixel) in image.enumerate_pixels() {
+            if x < 4 {
+                assert_eq!(pixel.0, [0, 0, 255, 255]);
+            } else {
+                assert_eq!(pixel[3], 0);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_neighbor_keeps_hard_edges() {
+        let image = scaled_checkerboard(InterpolationMode::NearestNeighbor);
+        assert!(image.pixels().all(|p| p[0] == 0 || p[0] == 255));
+    }
+
+    #[test]
+    fn bilinear_blends_neighbors() {
+        let image = scaled_checkerboard(InterpolationMode::Bilinear);
+        assert!(image.pixels().any(|p| p[0] > 0 && p[0] < 255));
+    }
+}
@@ -1,14 +1,25 @@
+mod clip;
+#[cfg(feature = "headless")]
+mod headless;
+mod sweep;
+mod text;
+
 use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::ops::RangeBounds;
-use std::sync::{Arc, Mutex};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use pathfinder_canvas::{
-    CanvasFontContext, FillStyle, ImageSmoothingQuality, Transform2F, Vector2F, Vector2I,
+    ArcDirection, CanvasFontContext, CompositeOperation, FillRule, FillStyle, Transform2F, Vector2F,
 };
+use pathfinder_content::gradient::{Gradient, GradientWrap};
 use pathfinder_content::pattern::Pattern;
-use pathfinder_renderer::scene::RenderTarget;
-use skribo::FontCollection;
+use pathfinder_geometry::line_segment::LineSegment2F;
+use pathfinder_renderer::scene::Scene;
+use pathfinder_simd::default::F32x2;
 
 use font_kit::error::{FontLoadingError, SelectionError};
 use font_kit::family_handle::FamilyHandle;
@@ -17,19 +28,108 @@ use font_kit::handle::Handle;
 use font_kit::properties::Properties;
 use font_kit::source::Source;
 use pathfinder_color::ColorU;
-use piet::kurbo::{Affine, Line, PathEl, Point, Rect, Shape, Size};
+use piet::kurbo::{
+    Affine, Circle, Ellipse, Line, PathEl, Point, Rect, RoundedRect, Shape, Size, Vec2,
+};
 use piet::{
-    Color, Error, FixedGradient, FontFamily, FontFamilyInner, HitTestPoint, HitTestPosition,
-    ImageFormat, InterpolationMode, IntoBrush, LineMetric, RenderContext, StrokeStyle,
-    TextAlignment, TextAttribute, TextLayout, TextStorage,
+    Color, Error, FixedGradient, FontFamily, ImageFormat, InterpolationMode, IntoBrush,
+    RenderContext, StrokeStyle, TextLayout,
 };
 use std::any::Any;
 
-static TOLERANCE: f64 = 0.1;
+use crate::clip::ClipRegion;
+use crate::sweep::SweepCache;
+
+pub use crate::sweep::SweepGradient;
+pub use crate::text::{
+    FontMetrics, LineBreakOpportunity, PathfinderTextLayout, Text, TextLayoutBuilder, WrapMode,
+};
+pub use pathfinder_canvas::ImageSmoothingQuality;
+
+#[cfg(feature = "headless")]
+pub use crate::headless::{render_to_image, render_to_image_supersampled, render_to_png};
+
+/// The default flattening tolerance used when converting curved shapes to paths.
+static DEFAULT_TOLERANCE: f64 = 0.1;
 
 pub struct PathFinderRenderContext<'a> {
     canvas: &'a mut pathfinder_canvas::CanvasRenderingContext2D,
     text: Text,
+    tolerance: f64,
+    path_cache: Option<&'a mut PathCache>,
+    /// The bounding box of the current clip region, in device space.
+    clip_bounds: Option<Rect>,
+    /// The effective clip region, composed from every active clip.
+    clip_region: Option<ClipRegion>,
+    /// The clip bounds and region of each saved state, innermost last.
+    saved_clips: Vec<(Option<Rect>, Option<ClipRegion>)>,
+    /// The layers pushed with `push_opacity_layer`, innermost last.
+    layers: Vec<Layer>,
+    /// The first error since `status` was last called.
+    error: Result<(), Error>,
+    /// The smoothing quality used for `InterpolationMode::Bilinear`.
+    bilinear_quality: ImageSmoothingQuality,
+    /// The color space gradients blend their stops in.
+    gradient_interpolation: GradientInterpolation,
+    /// Whether edges should be antialiased; see `set_antialias`.
+    antialias: bool,
+    /// Device pixels per canvas unit; see `set_device_pixel_ratio`.
+    device_pixel_ratio: f64,
+    /// Set by `finish`, after which drawing does nothing.
+    finished: bool,
+    /// The brush the canvas's fill style was set from by `set_fill_brush`, as
+    /// long as the style hasn't changed since.
+    fill_brush: Option<Brush>,
+    sweep_cache: SweepCache,
+}
+
+/// An offscreen layer that is being drawn into.
+///
+/// While a layer is active, `PathFinderRenderContext::canvas` points to the
+/// layer's own canvas, and the canvas it replaced is kept here.
+struct Layer {
+    parent: pathfinder_canvas::CanvasRenderingContext2D,
+    /// The opacity the layer is composited at, or `None` for a layer from
+    /// `begin_layer`, which is turned into a [`LayerImage`] instead.
+    alpha: Option<f32>,
+    /// How many saves were active when the layer started, which must be
+    /// restored before it ends.
+    save_depth: usize,
+    /// The clip state from before the layer, restored when it ends.
+    clip: (Option<Rect>, Option<ClipRegion>),
+}
+
+/// The content of an offscreen layer, drawn between
+/// [`PathFinderRenderContext::begin_layer`] and
+/// [`PathFinderRenderContext::end_layer_as_image`].
+///
+/// Its pixels only exist once the scene is rendered, so it isn't an
+/// [`Image`]. It is kept as a render target of the scene it was ended in,
+/// and can be drawn any number of times until that scene is taken, or until
+/// the layer it was ended in is.
+#[derive(Clone)]
+pub struct LayerImage {
+    pattern: Pattern,
+    size: Size,
+}
+
+impl LayerImage {
+    /// The size the layer was begun with.
+    pub fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl pathfinder_canvas::CanvasImageSource for LayerImage {
+    fn to_pattern(
+        self,
+        _dest_context: &mut pathfinder_canvas::CanvasRenderingContext2D,
+        transform: pathfinder_canvas::Transform2F,
+    ) -> Pattern {
+        let mut pattern = self.pattern;
+        pattern.apply_transform(transform);
+        pattern
+    }
 }
 
 impl<'a> PathFinderRenderContext<'a> {
@@ -39,158 +139,987 @@ impl<'a> PathFinderRenderContext<'a> {
     ) -> Self {
         PathFinderRenderContext {
             canvas,
-            text: Text { font_source },
+            text: Text::new(font_source),
+            tolerance: DEFAULT_TOLERANCE,
+            path_cache: None,
+            clip_bounds: None,
+            clip_region: None,
+            saved_clips: Vec::new(),
+            layers: Vec::new(),
+            error: Ok(()),
+            // `Low` is left to the renderer to implement however it finds
+            // cheapest, so plain bilinear filtering is `Medium`.
+            bilinear_quality: ImageSmoothingQuality::Medium,
+            gradient_interpolation: GradientInterpolation::Srgb,
+            fill_brush: None,
+            sweep_cache: SweepCache::default(),
+            antialias: true,
+            device_pixel_ratio: 1.0,
+            finished: false,
         }
     }
-}
 
-#[derive(Clone)]
-pub enum Brush {
-    Solid(u32),
-    Gradient,
-}
+    /// Creates a context that reuses converted paths from `path_cache`.
+    ///
+    /// The cache outlives the context, so it can be kept across frames. Only
+    /// shapes that expose their elements (such as `BezPath`) are cached; every
+    /// other shape, and any cache miss, is converted as usual.
+    pub fn with_path_cache(
+        canvas: &'a mut pathfinder_canvas::CanvasRenderingContext2D,
+        font_source: Arc<FontSource>,
+        path_cache: &'a mut PathCache,
+    ) -> Self {
+        PathFinderRenderContext {
+            path_cache: Some(path_cache),
+            ..PathFinderRenderContext::new(canvas, font_source)
+        }
+    }
 
-impl IntoBrush<PathFinderRenderContext<'_>> for Brush {
-    fn make_brush<'b>(
-        &'b self,
-        _piet: &mut PathFinderRenderContext,
-        _bbox: impl FnOnce() -> Rect,
-    ) -> std::borrow::Cow<'b, Brush> {
-        Cow::Borrowed(self)
+    /// Starts configuring a context with more than the defaults of [`new`].
+    ///
+    /// [`new`]: PathFinderRenderContext::new
+    pub fn builder(
+        canvas: &'a mut pathfinder_canvas::CanvasRenderingContext2D,
+        font_source: Arc<FontSource>,
+    ) -> PathFinderRenderContextBuilder<'a> {
+        PathFinderRenderContextBuilder {
+            context: PathFinderRenderContext::new(canvas, font_source),
+        }
     }
-}
 
-#[derive(Clone)]
-pub struct Text {
-    font_source: Arc<FontSource>,
-}
+    /// Sets the tolerance used to flatten shapes that pathfinder can't draw natively.
+    ///
+    /// Larger values produce fewer segments at the cost of less accurate curves.
+    /// The default is `0.1`.
+    pub fn set_tolerance(&mut self, tolerance: f64) {
+        self.tolerance = tolerance;
+    }
 
-impl piet::Text for Text {
-    type TextLayoutBuilder = TextLayoutBuilder;
-    type TextLayout = PathfinderTextLayout;
+    /// Returns the tolerance used to flatten shapes.
+    pub fn tolerance(&self) -> f64 {
+        self.tolerance
+    }
 
-    fn font_family(&mut self, family_name: &str) -> Option<FontFamily> {
-        let family = self.font_source.select_family_by_name(family_name);
-        family
-            .ok()
-            .map(|_family| FontFamily::new_unchecked(family_name))
+    /// Sets the smoothing quality of images drawn with
+    /// [`InterpolationMode::Bilinear`].
+    ///
+    /// This is an extension specific to the pathfinder backend, since piet's
+    /// interpolation modes can't express it. `High` gives better results when
+    /// scaling images down. The default is `Medium`.
+    pub fn set_image_smoothing_quality(&mut self, quality: ImageSmoothingQuality) {
+        self.bilinear_quality = quality;
     }
 
-    fn load_font(&mut self, data: &[u8]) -> Result<FontFamily, Error> {
-        let font_handle = font_kit::handle::Handle::from_memory(Arc::new(data.to_owned()), 0);
-        let font = self
-            .font_source
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .add_font(font_handle)
-            .map_err(|err| match err {
-                FontLoadingError::NoSuchFontInCollection => Error::MissingFont,
-                FontLoadingError::UnknownFormat => Error::FontLoadingFailed,
-                FontLoadingError::Parse => Error::FontLoadingFailed,
-                _ => Error::BackendError(Box::new(err)),
-            })?;
-        Ok(FontFamily::new_unchecked(font.family_name()))
+    /// Sets the color space that gradients made by [`gradient`] and
+    /// [`gradient_with_spread`] blend their stops in.
+    ///
+    /// The default, `Srgb`, matches CSS and the other piet backends. Only
+    /// gradients made after the call are affected.
+    ///
+    /// [`gradient`]: RenderContext::gradient
+    /// [`gradient_with_spread`]: PathFinderRenderContext::gradient_with_spread
+    pub fn set_gradient_interpolation(&mut self, interpolation: GradientInterpolation) {
+        self.gradient_interpolation = interpolation;
+    }
+
+    /// Returns the color space that gradients blend their stops in.
+    pub fn gradient_interpolation(&self) -> GradientInterpolation {
+        self.gradient_interpolation
+    }
+
+    /// Sets whether edges are antialiased, which they are by default.
+    ///
+    /// With antialiasing off, every pixel is either fully covered or not
+    /// covered at all, giving the hard, aliased edges wanted for pixel art and
+    /// masks. Pathfinder's rasterizer always antialiases, so this can't be
+    /// recorded in the scene: [`render_to_image`] and [`render_to_png`] honor
+    /// it by snapping coverage after rendering, and other renderers should
+    /// check [`antialias`] and do the same.
+    ///
+    /// [`render_to_image`]: crate::render_to_image
+    /// [`render_to_png`]: crate::render_to_png
+    /// [`antialias`]: PathFinderRenderContext::antialias
+    pub fn set_antialias(&mut self, antialias: bool) {
+        self.antialias = antialias;
+    }
+
+    /// Returns whether edges are antialiased.
+    pub fn antialias(&self) -> bool {
+        self.antialias
+    }
+
+    /// Sets how many device pixels the scene will be rendered at per unit of
+    /// the canvas, such as `2.0` on a HiDPI display.
+    ///
+    /// Pathfinder scales the scene when it is rendered, so this doesn't
+    /// change what is drawn. It lets the context size the images it makes
+    /// itself, for blurred rects and color glyphs, and keep hairlines and
+    /// pixel snapped images aligned to device pixels. [`render_to_image`]
+    /// sets it to the scale it renders at. The default is `1.0`.
+    ///
+    /// [`render_to_image`]: crate::render_to_image
+    pub fn set_device_pixel_ratio(&mut self, ratio: f64) {
+        self.device_pixel_ratio = ratio;
+    }
+
+    /// Returns how many device pixels there are per unit of the canvas.
+    pub fn device_pixel_ratio(&self) -> f64 {
+        self.device_pixel_ratio
+    }
+
+    /// Like [`clip`], but uses the even-odd fill rule, so that overlapping
+    /// parts of `shape` cut holes into the clip region instead of filling it.
+    ///
+    /// [`clip`]: RenderContext::clip
+    pub fn clip_even_odd(&mut self, shape: impl Shape) {
+        if self.finished {
+            return;
+        }
+        self.clip_with_rule(shape, FillRule::EvenOdd)
+    }
+
+    /// Starts drawing into an offscreen layer, which [`pop_layer`] composites
+    /// back at `alpha` opacity.
+    ///
+    /// Unlike lowering the alpha of each brush, this treats everything drawn
+    /// until then as a single group, so overlapping shapes don't show through
+    /// each other.
+    ///
+    /// The layer starts with the current transform, clip and drawing state.
+    /// Saves made before the layer can't be restored until it is popped.
+    ///
+    /// [`pop_layer`]: PathFinderRenderContext::pop_layer
+    pub fn push_opacity_layer(&mut self, alpha: f64) {
+        if self.finished {
+            return;
+        }
+        let size = self.canvas.canvas().size().to_f32();
+        let mut layer_canvas =
+            pathfinder_canvas::Canvas::new(size).get_context_2d(self.canvas.font_context());
+        // the clip region is already in device space.
+        if let Some(region) = &self.clip_region {
+            layer_canvas.clip_path(region.to_path(), region.fill_rule());
+        }
+        layer_canvas.set_transform(&self.canvas.transform());
+        layer_canvas.set_line_width(self.canvas.line_width());
+        layer_canvas.set_line_cap(self.canvas.line_cap());
+        layer_canvas.set_line_join(self.canvas.line_join());
+        layer_canvas.set_miter_limit(self.canvas.miter_limit());
+        layer_canvas.set_global_alpha(self.canvas.global_alpha());
+        layer_canvas.set_global_composite_operation(self.canvas.global_composite_operation());
+        let parent = std::mem::replace(&mut *self.canvas, layer_canvas);
+        self.fill_brush = None;
+        self.layers.push(Layer {
+            parent,
+            alpha: Some(alpha as f32),
+            save_depth: self.saved_clips.len(),
+            clip: (self.clip_bounds, self.clip_region.clone()),
+        });
+    }
+
+    /// Starts drawing into an offscreen layer of `size`, with its own
+    /// coordinate space that starts at its top-left corner.
+    ///
+    /// Everything drawn until [`end_layer_as_image`] goes into the layer,
+    /// which can then be drawn back with [`draw_layer_image`]. This is the
+    /// building block for effects such as group opacity and blend groups.
+    ///
+    /// [`end_layer_as_image`]: PathFinderRenderContext::end_layer_as_image
+    /// [`draw_layer_image`]: PathFinderRenderContext::draw_layer_image
+    pub fn begin_layer(&mut self, size: Size) {
+        if self.finished {
+            return;
+        }
+        let layer_canvas = pathfinder_canvas::Canvas::new(vec2f_from_size(size))
+            .get_context_2d(self.canvas.font_context());
+        let parent = std::mem::replace(&mut *self.canvas, layer_canvas);
+        self.fill_brush = None;
+        self.layers.push(Layer {
+            parent,
+            alpha: None,
+            save_depth: self.saved_clips.len(),
+            clip: (self.clip_bounds.take(), self.clip_region.take()),
+        });
+    }
+
+    /// Ends the innermost layer started with [`begin_layer`] and returns what
+    /// was drawn into it, without drawing it anywhere.
+    ///
+    /// Returns [`Error::StackUnbalance`] if the innermost layer wasn't started
+    /// by `begin_layer`, if there is none, or if a save made in it hasn't been
+    /// restored.
+    ///
+    /// [`begin_layer`]: PathFinderRenderContext::begin_layer
+    pub fn end_layer_as_image(&mut self) -> Result<LayerImage, Error> {
+        match self.layers.last() {
+            Some(layer) if layer.alpha.is_none() && self.has_balanced_saves(layer) => {}
+            _ => return Err(Error::StackUnbalance),
+        }
+        let layer = self.layers.pop().unwrap();
+        let canvas = std::mem::replace(&mut *self.canvas, layer.parent).into_canvas();
+        let (bounds, region) = layer.clip;
+        self.clip_bounds = bounds;
+        self.clip_region = region;
+        self.fill_brush = None;
+        let size = canvas.size();
+        // this adds the layer's scene to the parent's as a render target, which
+        // the pattern can then refer to as often as it's drawn.
+        let pattern = pathfinder_canvas::CanvasImageSource::to_pattern(
+            canvas,
+            &mut *self.canvas,
+            Transform2F::default(),
+        );
+        Ok(LayerImage {
+            pattern,
+            size: Size::new(size.x() as f64, size.y() as f64),
+        })
+    }
+
+    /// Draws `image`, from [`end_layer_as_image`], into `dst_rect`.
+    ///
+    /// [`end_layer_as_image`]: PathFinderRenderContext::end_layer_as_image
+    pub fn draw_layer_image(&mut self, image: &LayerImage, dst_rect: impl Into<Rect>) {
+        if self.finished {
+            return;
+        }
+        self.canvas
+            .draw_image(image.clone(), rectf_from_rect(dst_rect.into()));
+    }
+
+    /// Composites the innermost layer pushed with [`push_opacity_layer`] onto
+    /// what was drawn before it.
+    ///
+    /// Returns [`Error::StackUnbalance`] if there is no layer to pop, if the
+    /// innermost layer was started by [`begin_layer`], or if a save made in
+    /// it hasn't been restored.
+    ///
+    /// [`push_opacity_layer`]: PathFinderRenderContext::push_opacity_layer
+    /// [`begin_layer`]: PathFinderRenderContext::begin_layer
+    pub fn pop_layer(&mut self) -> Result<(), Error> {
+        let alpha = match self.layers.last() {
+            Some(layer) if self.has_balanced_saves(layer) => match layer.alpha {
+                Some(alpha) => alpha,
+                None => return Err(Error::StackUnbalance),
+            },
+            _ => return Err(Error::StackUnbalance),
+        };
+        let layer = self.layers.pop().unwrap();
+        let layer_canvas = std::mem::replace(&mut *self.canvas, layer.parent).into_canvas();
+        let (bounds, region) = layer.clip;
+        self.clip_bounds = bounds;
+        self.clip_region = region;
+        self.fill_brush = None;
+        let dest =
+            pathfinder_geometry::rect::RectF::new(Vector2F::zero(), layer_canvas.size().to_f32());
+        self.canvas.save();
+        self.canvas.reset_transform();
+        self.canvas.set_global_alpha(alpha);
+        self.canvas.draw_image(layer_canvas, dest);
+        self.canvas.restore();
+        Ok(())
+    }
+
+    /// Creates a gradient brush like [`gradient`], painted past the ends of
+    /// its stops according to `spread`.
+    ///
+    /// [`gradient`]: RenderContext::gradient
+    pub fn gradient_with_spread(
+        &mut self,
+        gradient: impl Into<FixedGradient>,
+        spread: GradientSpread,
+    ) -> Result<Brush, Error> {
+        let (mut gradient, stops) = match gradient.into() {
+            FixedGradient::Linear(linear) => (
+                Gradient::linear_from_points(
+                    vec2f_from_point(linear.start),
+                    vec2f_from_point(linear.end),
+                ),
+                linear.stops,
+            ),
+            FixedGradient::Radial(radial) => {
+                // piet's gradient starts as a point at the origin and grows
+                // into the circle around the center.
+                let origin = radial.center + radial.origin_offset;
+                let line =
+                    LineSegment2F::new(vec2f_from_point(origin), vec2f_from_point(radial.center));
+                (
+                    Gradient::radial(line, F32x2::new(0.0, radial.radius as f32)),
+                    radial.stops,
+                )
+            }
+        };
+        let mut stops = sorted_stops(stops)?;
+        if self.gradient_interpolation == GradientInterpolation::Linear {
+            stops = linear_light_stops(&stops);
+        }
+        for stop in &stops {
+            gradient.add_color_stop(ColorU::from_u32(stop.color.as_rgba_u32()), stop.pos);
+        }
+        gradient.wrap = match spread {
+            GradientSpread::Pad => GradientWrap::Clamp,
+            GradientSpread::Repeat => GradientWrap::Repeat,
+        };
+        Ok(Brush::Gradient(gradient))
+    }
+
+    /// Draws `layout` like [`draw_text`], but with the baseline of its first
+    /// line at `baseline_origin` instead of its top-left corner.
+    ///
+    /// [`draw_text`]: RenderContext::draw_text
+    pub fn draw_text_at_baseline(
+        &mut self,
+        layout: &PathfinderTextLayout,
+        baseline_origin: impl Into<Point>,
+    ) {
+        let baseline = layout.line_metric(0).map_or(0.0, |metric| metric.baseline);
+        self.draw_text(layout, baseline_origin.into() - Vec2::new(0.0, baseline));
+    }
+
+    /// Draws `layout` like [`draw_text`], moving each glyph by the offset that
+    /// `offset` returns for it.
+    ///
+    /// `offset` is called with the index of each glyph, in the order of
+    /// [`PathfinderTextLayout::glyph_origins`], and with the position its
+    /// baseline origin would be drawn at. This allows effects such as wavy
+    /// text without laying out every glyph separately.
+    ///
+    /// [`draw_text`]: RenderContext::draw_text
+    pub fn draw_text_with(
+        &mut self,
+        layout: &PathfinderTextLayout,
+        pos: impl Into<Point>,
+        mut offset: impl FnMut(usize, Point) -> Vec2,
+    ) {
+        if self.finished {
+            return;
+        }
+        let pos = pos.into().to_vec2();
+        self.set_canvas_fill_style(FillStyle::Color(ColorU::from_u32(
+            layout.color().as_rgba_u32(),
+        )));
+        let mut index = 0;
+        let mut offset = |position: Point| {
+            let shift = offset(index, position);
+            index += 1;
+            shift
+        };
+        for (glyphs, origin) in layout.glyph_runs() {
+            let origin = origin + pos;
+            let glyphs = skribo::Layout {
+                size: glyphs.size,
+                glyphs: glyphs
+                    .glyphs
+                    .iter()
+                    .map(|glyph| {
+                        let (x, y) = (glyph.offset.x() as f64, glyph.offset.y() as f64);
+                        let shift = offset(origin + Vec2::new(x, y));
+                        skribo::Glyph {
+                            font: glyph.font.clone(),
+                            glyph_id: glyph.glyph_id,
+                            offset: glyph.offset + vec2f_from_point(shift.to_point()),
+                        }
+                    })
+                    .collect(),
+            };
+            let transform = Transform2F::from_translation(vec2f_from_point(origin));
+            self.canvas.fill_layout(&glyphs, transform);
+        }
+        self.draw_color_glyphs(layout, pos, offset);
+    }
+
+    /// Draws `layout` like [`draw_text`], over a copy of it in `shadow_color`
+    /// that is moved by `offset` and blurred by `blur_radius`.
+    ///
+    /// The shadow is rasterized with [`PathfinderTextLayout::to_image`] at
+    /// device resolution and blurred like [`blurred_rect`] blurs a rect. Like
+    /// `blurred_rect`, this sets an [`Error::InvalidInput`] status for a
+    /// negative or NaN radius.
+    ///
+    /// [`draw_text`]: RenderContext::draw_text
+    /// [`blurred_rect`]: RenderContext::blurred_rect
+    pub fn draw_text_with_shadow(
+        &mut self,
+        layout: &PathfinderTextLayout,
+        pos: impl Into<Point>,
+        shadow_color: Color,
+        offset: Vec2,
+        blur_radius: f64,
+    ) {
+        if self.finished {
+            return;
+        }
+        if blur_radius.is_nan() || blur_radius < 0.0 {
+            return self.set_error(Error::InvalidInput);
+        }
+        let pos = pos.into();
+        let scale = self.device_scale();
+        match text_shadow_image(layout, shadow_color, blur_radius, scale) {
+            Ok((buffer, rect)) => {
+                let rect = rect + pos.to_vec2() + offset;
+                self.canvas
+                    .draw_image(Image::new(buffer), rectf_from_rect(rect));
+            }
+            Err(err) => return self.set_error(err),
+        }
+        self.draw_text(layout, pos);
+    }
+
+    /// Creates an image from one decoded by the `image` crate, converting its
+    /// pixels to 8-bit RGBA, whatever their color type.
+    pub fn make_image_from_dynamic(&mut self, image: &image::DynamicImage) -> Result<Image, Error> {
+        Ok(Image::new(image.to_rgba8()))
+    }
+
+    /// Draws `image` mapped into user space by `transform`, which places the
+    /// image's top-left corner at the origin and each pixel in a unit square.
+    ///
+    /// Unlike `draw_image`, this can rotate and skew the image, without
+    /// changing the context's transform.
+    pub fn draw_image_transformed(
+        &mut self,
+        image: &Image,
+        transform: Affine,
+        interp: InterpolationMode,
+    ) {
+        if self.finished {
+            return;
+        }
+        let mut pattern = Pattern::from_image(image.pattern_image.clone());
+        pattern.apply_transform(transform2f_from_affine(transform));
+        pattern.set_smoothing_enabled(interp == InterpolationMode::Bilinear);
+        let quad = transform * piet::Image::size(image).to_rect().to_path(self.tolerance);
+        let path = self.path_from_shape(quad);
+        self.set_canvas_fill_style(FillStyle::Pattern(pattern));
+        self.canvas.fill_path(path, FillRule::Winding);
+    }
+
+    /// Draws `image` like `draw_image`, but with `dst_rect` rounded to whole
+    /// device pixels, so that an image drawn at its device size, such as an
+    /// icon, stays crisp even at a fractional position or with bilinear
+    /// interpolation.
+    ///
+    /// Under a rotation or skew, the image fills the rounded bounding box of
+    /// `dst_rect` in device space instead.
+    pub fn draw_image_pixel_snapped(
+        &mut self,
+        image: &Image,
+        dst_rect: impl Into<Rect>,
+        interp: InterpolationMode,
+    ) {
+        if self.finished {
+            return;
+        }
+        let transform = self.canvas.transform();
+        let ratio = self.device_pixel_ratio;
+        let device_rect = (Affine::scale(ratio) * self.current_transform())
+            .transform_rect_bbox(dst_rect.into())
+            .round();
+        let canvas_rect = Affine::scale(1.0 / ratio).transform_rect_bbox(device_rect);
+        self.set_interpolation(interp);
+        self.canvas.reset_transform();
+        self.canvas
+            .draw_image(image.clone(), rectf_from_rect(canvas_rect));
+        self.canvas.set_transform(&transform);
+    }
+
+    /// Draws `image` like `draw_image`, but with each pixel's color and
+    /// alpha multiplied by `tint`, so that a white icon is drawn in the color
+    /// of the tint, keeping its shape.
+    pub fn draw_image_tinted(
+        &mut self,
+        image: &Image,
+        dst_rect: impl Into<Rect>,
+        tint: Color,
+        interp: InterpolationMode,
+    ) {
+        if self.finished {
+            return;
+        }
+        self.set_interpolation(interp);
+        self.canvas
+            .draw_image(image.tinted(tint), rectf_from_rect(dst_rect.into()));
+    }
+
+    /// Fills `dst_rect` with copies of the `src_rect` region of `image`, each
+    /// the size of the region, starting at the top-left corner of `dst_rect`.
+    ///
+    /// `tile_x` and `tile_y` choose whether neighboring copies are mirrored
+    /// along each axis. The region is rounded to whole pixels, and like
+    /// `draw_image_area`, this sets an [`Error::InvalidInput`] status if it is
+    /// outside the image.
+    pub fn draw_image_tiled(
+        &mut self,
+        image: &Image,
+        src_rect: impl Into<Rect>,
+        dst_rect: impl Into<Rect>,
+        tile_x: TileMode,
+        tile_y: TileMode,
+        interp: InterpolationMode,
+    ) {
+        if self.finished {
+            return;
+        }
+        let src_rect = src_rect
+            .into()
+            .round()
+            .intersect(piet::Image::size(image).to_rect());
+        if !(src_rect.width() > 0.0 && src_rect.height() > 0.0) {
+            return self.set_error(Error::InvalidInput);
+        }
+        let tile = image.tile(src_rect, tile_x, tile_y);
+        let dst_rect = dst_rect.into();
+        let mut pattern = Pattern::from_image(tile.pattern_image);
+        pattern.apply_transform(Transform2F::from_translation(vec2f_from_point(
+            dst_rect.origin(),
+        )));
+        pattern.set_repeat_x(true);
+        pattern.set_repeat_y(true);
+        pattern.set_smoothing_enabled(interp == InterpolationMode::Bilinear);
+        let path = self.path_from_shape(dst_rect);
+        self.set_canvas_fill_style(FillStyle::Pattern(pattern));
+        self.canvas.fill_path(path, FillRule::Winding);
+    }
+
+    /// Strokes every line in `lines` with the same brush and width, as a
+    /// single path.
+    ///
+    /// This draws the same as stroking each line on its own, but is much
+    /// cheaper for many short lines, such as those of a grid or a graph. Like
+    /// other strokes, nothing is drawn for a `width` of zero or less.
+    pub fn stroke_lines(&mut self, lines: &[Line], brush: &impl IntoBrush<Self>, width: f64) {
+        if self.finished || width <= 0.0 {
+            return;
+        }
+        let bounds = match lines.split_first() {
+            Some((first, rest)) => rest.iter().fold(first.bounding_box(), |bounds, line| {
+                bounds.union(line.bounding_box())
+            }),
+            None => return,
+        };
+        self.set_stroke_style(&bounds, brush, width);
+        self.canvas.set_line_width(width as f32);
+        let mut path = pathfinder_canvas::Path2D::new();
+        for line in lines {
+            path.move_to(vec2f_from_point(line.p0));
+            path.line_to(vec2f_from_point(line.p1));
+        }
+        self.canvas.stroke_path(path);
+    }
+
+    /// Strokes `shape` one device pixel wide, whatever the current transform.
+    ///
+    /// This suits lines that shouldn't get thicker when zoomed in, such as the
+    /// grid of a CAD drawing. The width is divided by the transform's scale
+    /// factor, so under a non-uniform scale it is only one pixel on average.
+    pub fn stroke_hairline(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>) {
+        if self.finished || has_no_elements(&shape) {
+            return;
+        }
+        let scale = self.device_scale();
+        if scale > 0.0 {
+            self.stroke(shape, brush, 1.0 / scale);
+        }
+    }
+
+    /// Fills the path made of `elements` with the nonzero winding rule, like
+    /// [`fill`] would fill a `BezPath` of them.
+    ///
+    /// The elements are converted as they are produced, so callers that
+    /// generate paths on the fly don't need to collect them first. The brush
+    /// is given the bounds of every point, control points included.
+    ///
+    /// [`fill`]: RenderContext::fill
+    pub fn fill_path_iter(
+        &mut self,
+        elements: impl IntoIterator<Item = PathEl>,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        if self.finished {
+            return;
+        }
+        let mut path = pathfinder_canvas::Path2D::new();
+        let mut bbox: Option<Rect> = None;
+        let elements = elements.into_iter().inspect(|element| {
+            let mut add = |point: Point| {
+                bbox = Some(match bbox {
+                    Some(bbox) => bbox.union_pt(point),
+                    None => Rect::from_points(point, point),
+                });
+            };
+            match *element {
+                PathEl::MoveTo(p) | PathEl::LineTo(p) => add(p),
+                PathEl::QuadTo(p0, p1) => {
+                    add(p0);
+                    add(p1);
+                }
+                PathEl::CurveTo(p0, p1, p2) => {
+                    add(p0);
+                    add(p1);
+                    add(p2);
+                }
+                PathEl::ClosePath => {}
+            }
+        });
+        if !append_elements(&mut path, elements) {
+            self.set_error(Error::InvalidInput);
+        }
+        let bbox = match bbox {
+            Some(bbox) if bbox.area() != 0.0 => bbox,
+            _ => return,
+        };
+        let brush = brush.make_brush(self, || bbox);
+        self.set_fill_brush(&brush, bbox);
+        self.canvas.fill_path(path, FillRule::Winding);
+    }
+
+    /// Fills `ellipse` like [`fill`], but with the canvas's native ellipse
+    /// instead of the curves kurbo approximates it with.
+    ///
+    /// `Shape` can't tell an ellipse apart from other paths, so `fill` only
+    /// does this for circles.
+    ///
+    /// [`fill`]: RenderContext::fill
+    pub fn fill_ellipse(&mut self, ellipse: Ellipse, brush: &impl IntoBrush<Self>) {
+        if self.finished || ellipse.bounding_box().area() == 0.0 {
+            return;
+        }
+        let mut path = pathfinder_canvas::Path2D::new();
+        if !append_ellipse(&mut path, ellipse) {
+            return self.set_error(Error::InvalidInput);
+        }
+        self.set_fill_style(&ellipse, brush);
+        self.canvas.fill_path(path, FillRule::Winding);
+    }
+
+    /// Fills `shape` like [`fill`], blending it with the content beneath
+    /// according to `mode`.
+    ///
+    /// [`fill`]: RenderContext::fill
+    pub fn fill_blended(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        mode: BlendMode,
+    ) {
+        if self.finished {
+            return;
+        }
+        self.canvas.save();
+        self.canvas
+            .set_global_composite_operation(mode.composite_operation());
+        self.fill(shape, brush);
+        self.canvas.restore();
+        // the restored state has the fill style from before.
+        self.fill_brush = None;
+    }
+
+    /// Returns the canvas being drawn into, for adding content piet can't
+    /// express.
+    ///
+    /// While a layer from [`push_opacity_layer`] is active, this is the
+    /// layer's offscreen canvas rather than the one the context was created
+    /// with. Transforms set on it are seen by later piet calls, but clips
+    /// aren't tracked, so the next [`clip`] replaces them.
+    ///
+    /// [`push_opacity_layer`]: PathFinderRenderContext::push_opacity_layer
+    /// [`clip`]: RenderContext::clip
+    pub fn canvas_mut(&mut self) -> &mut pathfinder_canvas::CanvasRenderingContext2D {
+        self.fill_brush = None;
+        self.canvas
     }
 
-    fn new_text_layout(&mut self, text: impl TextStorage) -> Self::TextLayoutBuilder {
-        TextLayoutBuilder {
-            text: std::rc::Rc::new(text),
+    /// Takes the scene drawn so far out of the canvas.
+    ///
+    /// The canvas the context was created with is replaced by an empty one of
+    /// the same size, with no transform or clip, so the context can keep
+    /// drawing into it. The returned scene doesn't borrow from the canvas, so
+    /// it can outlive the context.
+    ///
+    /// Returns [`Error::StackUnbalance`] if a layer or saved state is still
+    /// active, since the new canvas couldn't restore it.
+    pub fn take_scene(&mut self) -> Result<Scene, Error> {
+        if !self.layers.is_empty() || !self.saved_clips.is_empty() {
+            return Err(Error::StackUnbalance);
         }
+        self.clip_bounds = None;
+        self.clip_region = None;
+        self.fill_brush = None;
+        let size = self.canvas.canvas().size().to_f32();
+        let empty = pathfinder_canvas::Canvas::new(size).get_context_2d(self.canvas.font_context());
+        Ok(std::mem::replace(&mut *self.canvas, empty)
+            .into_canvas()
+            .into_scene())
+    }
+
+    /// Finishes drawing and returns the scene, consuming the context.
+    ///
+    /// This replaces calling [`finish`], dropping the context to end its
+    /// borrow of the canvas, then turning the canvas into a scene. The canvas
+    /// is left empty, as by [`take_scene`], and can be drawn into again.
+    ///
+    /// [`finish`]: RenderContext::finish
+    /// [`take_scene`]: PathFinderRenderContext::take_scene
+    pub fn into_scene(mut self) -> Result<Scene, Error> {
+        self.finish()?;
+        self.take_scene()
+    }
+
+    /// Returns a rectangle, in the current coordinate space, that contains
+    /// everything the active clips let through, or `None` if nothing is clipped.
+    ///
+    /// This is the intersection of the clip shapes' bounding boxes, so it can
+    /// be larger than the actual clip region; it's meant for culling draws.
+    pub fn current_clip_bounds(&self) -> Option<Rect> {
+        let bounds = self.clip_bounds?;
+        Some(
+            self.current_transform()
+                .inverse()
+                .transform_rect_bbox(bounds),
+        )
     }
 }
 
-pub struct TextLayoutBuilder {
-    text: std::rc::Rc<dyn TextStorage>,
+/// Configures a [`PathFinderRenderContext`] before it is used.
+///
+/// Created with [`PathFinderRenderContext::builder`]; anything not set keeps
+/// the default that [`PathFinderRenderContext::new`] uses.
+pub struct PathFinderRenderContextBuilder<'a> {
+    context: PathFinderRenderContext<'a>,
 }
 
-impl piet::TextLayoutBuilder for TextLayoutBuilder {
-    type Out = PathfinderTextLayout;
+impl<'a> PathFinderRenderContextBuilder<'a> {
+    /// See [`PathFinderRenderContext::set_tolerance`].
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.context.set_tolerance(tolerance);
+        self
+    }
 
-    fn max_width(self, width: f64) -> Self {
+    /// See [`PathFinderRenderContext::set_image_smoothing_quality`].
+    pub fn image_smoothing_quality(mut self, quality: ImageSmoothingQuality) -> Self {
+        self.context.set_image_smoothing_quality(quality);
         self
     }
 
-    fn alignment(self, alignment: TextAlignment) -> Self {
+    /// See [`PathFinderRenderContext::set_device_pixel_ratio`].
+    pub fn device_pixel_ratio(mut self, ratio: f64) -> Self {
+        self.context.set_device_pixel_ratio(ratio);
         self
     }
 
-    fn default_attribute(self, attribute: impl Into<TextAttribute>) -> Self {
+    /// See [`PathFinderRenderContext::set_gradient_interpolation`].
+    pub fn gradient_interpolation(mut self, interpolation: GradientInterpolation) -> Self {
+        self.context.set_gradient_interpolation(interpolation);
         self
     }
 
-    fn range_attribute(
-        self,
-        range: impl RangeBounds<usize>,
-        attribute: impl Into<TextAttribute>,
-    ) -> Self {
+    /// See [`PathFinderRenderContext::with_path_cache`].
+    pub fn path_cache(mut self, path_cache: &'a mut PathCache) -> Self {
+        self.context.path_cache = Some(path_cache);
         self
     }
 
-    fn build(self) -> Result<Self::Out, Error> {
-        Ok(PathfinderTextLayout {
-            size: Default::default(),
-            inner: self.text,
-        })
+    pub fn build(self) -> PathFinderRenderContext<'a> {
+        self.context
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Brush {
+    Solid(u32),
+    Gradient(Gradient),
+    Sweep(SweepGradient),
+    Image(ImageBrush),
+}
+
+impl Brush {
+    /// Scales the alpha of a solid brush by `alpha`, clamped to `0.0..=1.0`.
+    ///
+    /// This suits fading a color in and out. Other brushes are returned as
+    /// they are.
+    pub fn with_alpha(self, alpha: f64) -> Brush {
+        match self {
+            Brush::Solid(rgba) => {
+                let scaled = ((rgba & 0xff) as f64 * alpha.max(0.0).min(1.0)).round() as u32;
+                Brush::Solid((rgba & !0xff) | scaled)
+            }
+            brush => brush,
+        }
+    }
+}
+
+/// How a gradient brush is painted past the ends of its stops.
+///
+/// Pathfinder can't mirror the stops, so there is no reflect mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientSpread {
+    /// Extends the first and last colors; this is what [`RenderContext::gradient`] uses.
+    Pad,
+    /// Repeats the stops.
+    Repeat,
+}
+
+/// The color space a gradient blends its stops in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GradientInterpolation {
+    /// Blends the sRGB encoded colors, like CSS does. Midpoints come out
+    /// darker than they would in light.
+    Srgb,
+    /// Blends the colors in linear light, which gives even brightness
+    /// steps. Pathfinder only blends sRGB colors, so the backend approximates
+    /// this with extra stops.
+    Linear,
+}
+
+impl IntoBrush<PathFinderRenderContext<'_>> for Brush {
+    fn make_brush<'b>(
+        &'b self,
+        _piet: &mut PathFinderRenderContext,
+        _bbox: impl FnOnce() -> Rect,
+    ) -> std::borrow::Cow<'b, Brush> {
+        Cow::Borrowed(self)
     }
 }
 
 #[derive(Clone)]
-pub struct PathfinderTextLayout {
-    size: Size,
-    inner: std::rc::Rc<dyn TextStorage>,
+pub struct Image {
+    inner: Arc<image::RgbaImage>,
+    pattern_image: pathfinder_content::pattern::Image,
 }
 
-impl TextLayout for PathfinderTextLayout {
-    fn size(&self) -> Size {
-        // todo!()
-        self.size
+/// Images are equal if they have the same pixels, which is quick to check for
+/// clones of the same image.
+impl PartialEq for Image {
+    fn eq(&self, other: &Image) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner) || self.inner == other.inner
     }
+}
 
-    fn trailing_whitespace_width(&self) -> f64 {
-        // todo!()
-        0.0
+impl Image {
+    fn new(buffer: image::RgbaImage) -> Self {
+        let pattern_image = pathfinder_content::pattern::Image::from_image_buffer(buffer.clone());
+        Image {
+            inner: Arc::new(buffer),
+            pattern_image,
+        }
     }
 
-    fn image_bounds(&self) -> Rect {
-        // todo!()
-        Default::default()
+    /// Returns the image's pixels, as non-premultiplied RGBA rows from the top.
+    pub fn to_rgba_bytes(&self) -> Vec<u8> {
+        self.inner.as_raw().clone()
     }
 
-    fn text(&self) -> &str {
-        // todo!()
-        self.inner.as_str()
+    /// Returns the underlying image buffer.
+    pub fn raw(&self) -> &image::RgbaImage {
+        &self.inner
     }
 
-    fn line_text(&self, line_number: usize) -> Option<&str> {
-        // todo!()
-        None
+    /// Returns the image's pixels like [`to_rgba_bytes`], but with the color
+    /// channels premultiplied by alpha.
+    ///
+    /// [`to_rgba_bytes`]: Image::to_rgba_bytes
+    pub fn premultiplied_bytes(&self) -> Vec<u8> {
+        let mut data = self.to_rgba_bytes();
+        for pixel in data.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            for channel in &mut pixel[..3] {
+                *channel = div_round_half_even(*channel as u32 * alpha, 255) as u8;
+            }
+        }
+        data
     }
 
-    fn line_metric(&self, line_number: usize) -> Option<LineMetric> {
-        // todo!()
-        None
+    /// Creates an image from RGBA rows whose color channels are premultiplied
+    /// by alpha, as `make_image` does for [`ImageFormat::RgbaPremul`].
+    pub fn from_premultiplied(width: usize, height: usize, buf: &[u8]) -> Result<Image, Error> {
+        let mut data = buf.to_owned();
+        unpremultiply(&mut data);
+        Image::from_rgba(width, height, data)
     }
 
-    fn line_count(&self) -> usize {
-        // todo!()
-        0
+    /// Returns a copy of the `rect` region of the image, such as one sprite of
+    /// a sprite sheet.
+    ///
+    /// `rect` is rounded to whole pixels. Returns [`Error::InvalidInput`] if
+    /// it is empty or reaches outside the image.
+    pub fn crop(&self, rect: Rect) -> Result<Image, Error> {
+        let rect = rect.round();
+        let bounds = piet::Image::size(self).to_rect();
+        let inside = rect.x0 >= bounds.x0
+            && rect.y0 >= bounds.y0
+            && rect.x1 <= bounds.x1
+            && rect.y1 <= bounds.y1;
+        if !(inside && rect.width() > 0.0 && rect.height() > 0.0) {
+            return Err(Error::InvalidInput);
+        }
+        let (x, y) = (rect.x0 as u32, rect.y0 as u32);
+        let (width, height) = (rect.width() as u32, rect.height() as u32);
+        let region = image::imageops::crop_imm(&*self.inner, x, y, width, height).to_image();
+        Ok(Image::new(region))
     }
 
-    fn hit_test_point(&self, point: Point) -> HitTestPoint {
-        // todo!()
-        HitTestPoint::default()
+    /// A copy of the image with every channel multiplied by the matching
+    /// channel of `tint`.
+    fn tinted(&self, tint: Color) -> Image {
+        let (r, g, b, a) = tint.as_rgba8();
+        let tint = [r, g, b, a];
+        let mut buffer = (*self.inner).clone();
+        for pixel in buffer.pixels_mut() {
+            for (channel, tint) in pixel.0.iter_mut().zip(&tint) {
+                *channel = div_round_half_even(*channel as u32 * *tint as u32, 255) as u8;
+            }
+        }
+        Image::new(buffer)
     }
 
-    fn hit_test_text_position(&self, idx: usize) -> HitTestPosition {
-        // todo!()
-        HitTestPosition::default()
+    /// The `rect` region of the image, followed by a flipped copy of it along
+    /// each axis whose mode is [`TileMode::Mirror`], so that it can be
+    /// repeated to tile the region.
+    fn tile(&self, rect: Rect, tile_x: TileMode, tile_y: TileMode) -> Image {
+        let (x, y) = (rect.x0 as u32, rect.y0 as u32);
+        let (width, height) = (rect.width() as u32, rect.height() as u32);
+        let region = image::imageops::crop_imm(&*self.inner, x, y, width, height).to_image();
+        let (mirror_x, mirror_y) = (tile_x == TileMode::Mirror, tile_y == TileMode::Mirror);
+        let tile_width = if mirror_x { width * 2 } else { width };
+        let tile_height = if mirror_y { height * 2 } else { height };
+        let buffer = image::RgbaImage::from_fn(tile_width, tile_height, |x, y| {
+            let x = if x < width { x } else { tile_width - 1 - x };
+            let y = if y < height { y } else { tile_height - 1 - y };
+            *region.get_pixel(x, y)
+        });
+        Image::new(buffer)
+    }
+
+    fn from_rgba(width: usize, height: usize, data: Vec<u8>) -> Result<Image, Error> {
+        let width = width.try_into().map_err(|_| Error::NotSupported)?;
+        let height = height.try_into().map_err(|_| Error::NotSupported)?;
+        let buffer = image::RgbaImage::from_raw(width, height, data).ok_or(Error::InvalidInput)?;
+        Ok(Image::new(buffer))
     }
 }
 
-#[derive(Clone)]
-pub struct Image {
-    inner: image::RgbaImage,
+/// Divides the color channels of premultiplied RGBA `data` by their alpha, in
+/// place.
+pub(crate) fn unpremultiply(data: &mut [u8]) {
+    for pixel in data.chunks_exact_mut(4) {
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel[..3] {
+            *channel = if alpha == 0 {
+                0
+            } else {
+                div_round_half_even(*channel as u32 * 255, alpha).min(255) as u8
+            };
+        }
+    }
+}
+
+/// Divides `n` by `d`, rounding to the nearest integer and ties to even.
+fn div_round_half_even(n: u32, d: u32) -> u32 {
+    let (quotient, remainder) = (n / d, n % d);
+    match (2 * remainder).cmp(&d) {
+        std::cmp::Ordering::Less => quotient,
+        std::cmp::Ordering::Equal => quotient + (quotient & 1),
+        std::cmp::Ordering::Greater => quotient + 1,
+    }
 }
 
 impl piet::Image for Image {
@@ -200,15 +1129,56 @@ impl piet::Image for Image {
     }
 }
 
+/// How [`PathFinderRenderContext::draw_image_tiled`] repeats an image along an
+/// axis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileMode {
+    /// Repeats the image as it is.
+    Repeat,
+    /// Flips every other copy of the image, so that neighboring copies meet
+    /// at matching edges.
+    Mirror,
+}
+
+/// An image used as a brush, optionally tiled.
+#[derive(Clone, PartialEq)]
+pub struct ImageBrush {
+    pub image: Image,
+    /// Maps the image, whose top-left corner is at the origin, into user space.
+    pub transform: Affine,
+    /// Whether the image tiles horizontally, rather than being clamped to its edges.
+    pub repeat_x: bool,
+    /// Whether the image tiles vertically, rather than being clamped to its edges.
+    pub repeat_y: bool,
+}
+
+impl ImageBrush {
+    /// Tiles `image` in both directions, starting at the origin.
+    pub fn new(image: Image) -> Self {
+        ImageBrush {
+            image,
+            transform: Affine::IDENTITY,
+            repeat_x: true,
+            repeat_y: true,
+        }
+    }
+
+    fn to_pattern(&self) -> Pattern {
+        let mut pattern = Pattern::from_image(self.image.pattern_image.clone());
+        pattern.apply_transform(transform2f_from_affine(self.transform));
+        pattern.set_repeat_x(self.repeat_x);
+        pattern.set_repeat_y(self.repeat_y);
+        pattern
+    }
+}
+
 impl pathfinder_canvas::CanvasImageSource for Image {
     fn to_pattern(
         self,
         dest_context: &mut pathfinder_canvas::CanvasRenderingContext2D,
         transform: pathfinder_canvas::Transform2F,
     ) -> Pattern {
-        let mut p = Pattern::from_image(pathfinder_content::pattern::Image::from_image_buffer(
-            self.inner,
-        ));
+        let mut p = Pattern::from_image(self.pattern_image);
         p.apply_transform(transform);
         p
     }
@@ -221,7 +1191,9 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
     type Image = Image;
 
     fn status(&mut self) -> Result<(), Error> {
-        Ok(())
+        // text with a missing family is still drawn, in the fallback font, so
+        // that's left to `Text::take_missing_font` instead.
+        std::mem::replace(&mut self.error, Ok(()))
     }
 
     fn solid_brush(&mut self, color: Color) -> Self::Brush {
@@ -229,29 +1201,55 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
     }
 
     fn gradient(&mut self, gradient: impl Into<FixedGradient>) -> Result<Self::Brush, Error> {
-        let gradient = gradient.into();
-        Ok(Brush::Gradient)
-        // todo!()
+        self.gradient_with_spread(gradient, GradientSpread::Pad)
     }
 
     fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {
-        let region = region.into();
-        if let Some(rect) = region {
-            self.canvas
-                .set_fill_style(FillStyle::Color(ColorU::from_u32(color.as_rgba_u32())));
-            self.canvas.fill_rect(pathfinder_geometry::rect::RectF::new(
-                vec2f_from_point(rect.origin()),
-                vec2f_from_size(rect.size()),
-            ));
-        } else {
-            self.canvas.clear();
+        if self.finished {
+            return;
         }
+        let canvas_rect = pathfinder_geometry::rect::RectF::new(
+            Vector2F::zero(),
+            self.canvas.canvas().size().to_f32(),
+        );
+        // piet clears in device space, whatever the transform.
+        let device_rect = match region.into() {
+            Some(rect) => rectf_from_rect(rect),
+            None => {
+                // The canvas only clears to transparent, so after clearing, all
+                // of it is filled with the color.
+                self.canvas.clear();
+                canvas_rect
+            }
+        };
+        // piet's clear ignores the clip too. The canvas can't drop a clip, but
+        // a new one replaces it, so the whole canvas is clipped to instead
+        // until the state is restored.
+        self.canvas.save();
+        let mut everything = pathfinder_canvas::Path2D::new();
+        everything.rect(canvas_rect);
+        self.canvas.reset_transform();
+        self.canvas.clip_path(everything, FillRule::Winding);
+        self.canvas.set_global_alpha(1.0);
+        self.canvas
+            .set_global_composite_operation(CompositeOperation::SourceOver);
+        self.set_canvas_fill_style(FillStyle::Color(ColorU::from_u32(color.as_rgba_u32())));
+        // Filling composites over what was there, so a translucent color
+        // would blend with it instead of replacing it.
+        self.canvas.clear_rect(device_rect);
+        self.canvas.fill_rect(device_rect);
+        self.canvas.restore();
     }
 
     fn stroke(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>, width: f64) {
-        self.set_fill_style(&shape, brush);
+        // A stroke this thin covers nothing, and isn't valid for pathfinder.
+        if self.finished || width <= 0.0 || has_no_elements(&shape) {
+            return;
+        }
+        self.set_stroke_style(&shape, brush, width);
         self.canvas.set_line_width(width as f32);
-        self.canvas.stroke_path(path2d_from_shape(shape))
+        let path = self.path_from_shape(shape);
+        self.canvas.stroke_path(path)
     }
 
     fn stroke_styled(
@@ -261,32 +1259,72 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
         width: f64,
         style: &StrokeStyle,
     ) {
+        // A stroke this thin covers nothing, and isn't valid for pathfinder.
+        if self.finished || width <= 0.0 || has_no_elements(&shape) {
+            return;
+        }
+        self.set_stroke_style(&shape, brush, width);
         self.canvas.set_line_width(width as f32);
-        self.canvas.stroke_path(path2d_from_shape(shape))
-        // todo!()
+        // The canvas dashes the path before stroking it, so each dash gets caps.
+        self.canvas.set_line_cap(line_cap_from_piet(style.line_cap));
+        // Pathfinder bevels miters longer than the limit itself.
+        let (line_join, miter_limit) = line_join_from_piet(style.line_join);
+        self.canvas.set_line_join(line_join);
+        self.canvas.set_miter_limit(miter_limit);
+        let dash = line_dash_from_pattern(&style.dash_pattern);
+        self.canvas
+            .set_line_dash(lengthen_capped_dashes(dash, style.line_cap));
+        self.canvas.set_line_dash_offset(style.dash_offset as f32);
+        let path = self.path_from_shape(shape);
+        self.canvas.stroke_path(path);
+        // The style lives on the canvas, so reset it to keep later strokes solid,
+        // butt capped and miter joined.
+        self.canvas.set_line_cap(pathfinder_canvas::LineCap::Butt);
+        self.canvas
+            .set_line_join(pathfinder_canvas::LineJoin::Miter);
+        self.canvas
+            .set_miter_limit(piet::LineJoin::DEFAULT_MITER_LIMIT as f32);
+        self.canvas.set_line_dash(Vec::new());
+        self.canvas.set_line_dash_offset(0.0);
     }
 
     fn fill(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>) {
-        self.set_fill_style(&shape, brush);
-        self.canvas.fill_path(
-            path2d_from_shape(shape),
-            pathfinder_canvas::FillRule::Winding,
-        );
+        if self.finished {
+            return;
+        }
+        let bbox = shape.bounding_box();
+        if bbox.area() == 0.0 {
+            return;
+        }
+        let brush = brush.make_brush(self, || bbox);
+        self.set_fill_brush(&brush, bbox);
+        // Solid rects don't need a `Path2D` built from the shape.
+        if let (Brush::Solid(_), Some(rect)) = (&*brush, shape.as_rect()) {
+            if is_finite_rect(rect) {
+                self.canvas.fill_rect(rectf_from_rect(rect));
+            } else {
+                self.set_error(Error::InvalidInput);
+            }
+            return;
+        }
+        let path = self.path_from_shape(shape);
+        self.canvas.fill_path(path, FillRule::Winding);
     }
 
     fn fill_even_odd(&mut self, shape: impl Shape, brush: &impl IntoBrush<Self>) {
+        if self.finished || shape.bounding_box().area() == 0.0 {
+            return;
+        }
         self.set_fill_style(&shape, brush);
-        self.canvas.fill_path(
-            path2d_from_shape(shape),
-            pathfinder_canvas::FillRule::EvenOdd,
-        );
+        let path = self.path_from_shape(shape);
+        self.canvas.fill_path(path, FillRule::EvenOdd);
     }
 
     fn clip(&mut self, shape: impl Shape) {
-        self.canvas.clip_path(
-            path2d_from_shape(shape),
-            pathfinder_canvas::FillRule::Winding,
-        )
+        if self.finished {
+            return;
+        }
+        self.clip_with_rule(shape, FillRule::Winding)
     }
 
     fn text(&mut self) -> &mut Self::Text {
@@ -296,36 +1334,62 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
     }
 
     fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<Point>) {
-        // todo!()
-        self.canvas
-            .fill_text(layout.text(), vec2f_from_point(pos.into()));
+        if self.finished {
+            return;
+        }
+        // piet positions the layout by its top-left corner, while the canvas
+        // draws text relative to its baseline.
+        let pos = pos.into().to_vec2();
+        self.set_canvas_fill_style(FillStyle::Color(ColorU::from_u32(
+            layout.color().as_rgba_u32(),
+        )));
+        for (glyphs, origin) in layout.glyph_runs() {
+            // glyph outlines are placed at fractional positions, so the origin
+            // mustn't be rounded to whole pixels.
+            let transform = Transform2F::from_translation(vec2f_from_point(origin + pos));
+            self.canvas.fill_layout(glyphs, transform);
+        }
+        self.draw_color_glyphs(layout, pos, |_| Vec2::ZERO);
     }
 
     fn save(&mut self) -> Result<(), Error> {
         self.canvas.save();
+        self.saved_clips
+            .push((self.clip_bounds, self.clip_region.clone()));
         Ok(())
     }
 
     fn restore(&mut self) -> Result<(), Error> {
+        // a save from outside the innermost layer belongs to another canvas.
+        if let Some(layer) = self.layers.last() {
+            if self.has_balanced_saves(layer) {
+                return Err(Error::StackUnbalance);
+            }
+        }
+        let (bounds, region) = self.saved_clips.pop().ok_or(Error::StackUnbalance)?;
+        self.clip_bounds = bounds;
+        self.clip_region = region;
         self.canvas.restore();
+        // The restored state may have another fill style.
+        self.fill_brush = None;
         Ok(())
     }
 
     fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.status()?;
+        if !self.saved_clips.is_empty() || !self.layers.is_empty() {
+            return Err(Error::StackUnbalance);
+        }
         Ok(())
     }
 
     fn transform(&mut self, transform: Affine) {
-        let transform = transform.as_coeffs();
-        let ptransform = pathfinder_canvas::Transform2F::row_major(
-            transform[0] as f32,
-            transform[2] as f32,
-            transform[1] as f32,
-            transform[3] as f32,
-            transform[4] as f32,
-            transform[5] as f32,
-        );
-        self.canvas.set_transform(&ptransform)
+        self.canvas
+            .set_transform(&transform2f_from_affine(transform))
     }
 
     fn make_image(
@@ -336,20 +1400,8 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
         format: ImageFormat,
     ) -> Result<Self::Image, Error> {
         match format {
-            ImageFormat::RgbaSeparate => Ok(Image {
-                inner: image::RgbaImage::from_raw(
-                    width
-                        .try_into()
-                        .ok()
-                        .ok_or_else(|| piet::Error::NotSupported)?,
-                    height
-                        .try_into()
-                        .ok()
-                        .ok_or_else(|| piet::Error::NotSupported)?,
-                    buf.to_owned(),
-                )
-                .ok_or_else(|| piet::Error::InvalidInput)?,
-            }),
+            ImageFormat::RgbaSeparate => Image::from_rgba(width, height, buf.to_owned()),
+            ImageFormat::RgbaPremul => Image::from_premultiplied(width, height, buf),
             _ => Err(piet::Error::NotSupported),
         }
     }
@@ -360,9 +1412,12 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
         dst_rect: impl Into<Rect>,
         interp: InterpolationMode,
     ) {
+        if self.finished {
+            return;
+        }
         self.set_interpolation(interp);
         self.canvas
-            .draw_image((*image).clone(), rectf_from_rect(dst_rect.into()));
+            .draw_image(image.clone(), rectf_from_rect(dst_rect.into()));
     }
 
     fn draw_image_area(
@@ -372,11 +1427,21 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
         dst_rect: impl Into<Rect>,
         interp: InterpolationMode,
     ) {
+        if self.finished {
+            return;
+        }
+        // Parts of `src_rect` outside the image are cut off, along with the
+        // matching parts of `dst_rect`.
+        let (src_rect, dst_rect) =
+            match clamp_image_area(src_rect.into(), dst_rect.into(), piet::Image::size(image)) {
+                Some(rects) => rects,
+                None => return self.set_error(Error::InvalidInput),
+            };
         self.set_interpolation(interp);
         self.canvas.draw_subimage(
-            (*image).clone(),
-            rectf_from_rect(src_rect.into()),
-            rectf_from_rect(dst_rect.into()),
+            image.clone(),
+            rectf_from_rect(src_rect),
+            rectf_from_rect(dst_rect),
         );
     }
 
@@ -385,65 +1450,581 @@ impl<'a> RenderContext for PathFinderRenderContext<'a> {
     }
 
     fn blurred_rect(&mut self, rect: Rect, blur_radius: f64, brush: &impl IntoBrush<Self>) {
-        let size = piet::util::size_for_blurred_rect(rect, blur_radius);
-        let width = size.width as usize;
-        let height = size.height as usize;
-        let mut data = vec![0u8; width * height];
-        let rect_exp = piet::util::compute_blurred_rect(rect, blur_radius, width, &mut data);
-        let maybe_buffer = image::RgbaImage::from_raw(width as u32, height as u32, data);
-        if let Some(buffer) = maybe_buffer {
-            let image = Self::Image { inner: buffer };
-            self.canvas
-                .draw_image(image, vec2f_from_point(rect_exp.origin()));
+        if self.finished {
+            return;
+        }
+        if blur_radius.is_nan() || blur_radius < 0.0 {
+            self.set_error(Error::InvalidInput);
+            return;
+        }
+        if blur_radius == 0.0 {
+            self.fill(rect, brush);
+            return;
+        }
+        let brush = brush.make_brush(self, || rect);
+        let color = match *brush {
+            Brush::Solid(color) => ColorU::from_u32(color),
+            // The blur is drawn as an image, which can only carry one color.
+            _ => {
+                self.set_error(Error::NotSupported);
+                return;
+            }
+        };
+
+        match blurred_rect_image(rect, blur_radius, color, self.device_pixel_ratio) {
+            Some((buffer, dst_rect)) => self
+                .canvas
+                .draw_image(Image::new(buffer), rectf_from_rect(dst_rect)),
+            None => self.set_error(Error::InvalidInput),
+        }
+    }
+
+    fn current_transform(&self) -> Affine {
+        let t = self.canvas.transform();
+        Affine::new([
+            t.matrix.m11().into(),
+            t.matrix.m21().into(),
+            t.matrix.m12().into(),
+            t.matrix.m22().into(),
+            t.vector.x().into(),
+            t.vector.y().into(),
+        ])
+    }
+}
+
+/// Rasterizes `rect` blurred by `blur_radius`, in `color`, into an image with
+/// `ratio` pixels per unit, returning it with the rect it should be drawn into.
+///
+/// Returns `None` if the image would be too large.
+fn blurred_rect_image(
+    rect: Rect,
+    blur_radius: f64,
+    color: ColorU,
+    ratio: f64,
+) -> Option<(image::RgbaImage, Rect)> {
+    let device_rect = Affine::scale(ratio).transform_rect_bbox(rect);
+    let device_radius = blur_radius * ratio;
+    let size = piet::util::size_for_blurred_rect(device_rect, device_radius);
+    let width = size.width as usize;
+    let height = size.height as usize;
+    let mut mask = vec![0u8; width.checked_mul(height)?];
+    let rect_exp = piet::util::compute_blurred_rect(device_rect, device_radius, width, &mut mask);
+    // The mask only holds coverage, so color it in with the brush.
+    let mut data = Vec::with_capacity(mask.len() * 4);
+    for &coverage in &mask {
+        let alpha = (coverage as u32 * color.a as u32 + 127) / 255;
+        data.extend_from_slice(&[color.r, color.g, color.b, alpha as u8]);
+    }
+    let buffer = image::RgbaImage::from_raw(width as u32, height as u32, data)?;
+    let dst_rect = Affine::scale(1.0 / ratio).transform_rect_bbox(rect_exp.with_size(size));
+    Some((buffer, dst_rect))
+}
+
+/// Rasterizes the shadow of `layout` in `color`, blurred by `blur_radius`, at
+/// `scale` pixels per unit, returning it with the rect it should be drawn
+/// into, relative to where the layout is drawn.
+fn text_shadow_image(
+    layout: &PathfinderTextLayout,
+    color: Color,
+    blur_radius: f64,
+    scale: f64,
+) -> Result<(image::RgbaImage, Rect), Error> {
+    let glyphs = layout.to_image(scale)?;
+    let (glyphs_width, glyphs_height) = glyphs.raw().dimensions();
+    // as for blurred rects, the blur reaches two and a half radii out.
+    let radius = blur_radius * scale;
+    let padding = (2.5 * radius).ceil() as u32;
+    let width = glyphs_width + 2 * padding;
+    let height = glyphs_height + 2 * padding;
+    let mut mask = vec![0u8; width as usize * height as usize];
+    for (x, y, pixel) in glyphs.raw().enumerate_pixels() {
+        mask[((y + padding) * width + x + padding) as usize] = pixel[3];
+    }
+    let mask = blur_mask(&mask, width as usize, radius);
+
+    let (r, g, b, a) = color.as_rgba8();
+    let mut data = Vec::with_capacity(mask.len() * 4);
+    for &coverage in &mask {
+        let alpha = (coverage as u32 * a as u32 + 127) / 255;
+        data.extend_from_slice(&[r, g, b, alpha as u8]);
+    }
+    let buffer = image::RgbaImage::from_raw(width, height, data).ok_or(Error::InvalidInput)?;
+    let bounds = layout.image_bounds();
+    let padding = padding as f64 / scale;
+    let rect = Rect::from_origin_size(
+        bounds.origin() - Vec2::new(padding, padding),
+        Size::new(width as f64, height as f64) / scale,
+    );
+    Ok((buffer, rect))
+}
+
+/// Blurs the rows of coverage in `mask`, each `width` long, with a gaussian
+/// whose standard deviation is `radius`, cut off at two and a half radii.
+///
+/// Coverage past the edges counts as zero.
+fn blur_mask(mask: &[u8], width: usize, radius: f64) -> Vec<u8> {
+    if radius <= 0.0 || width == 0 {
+        return mask.to_owned();
+    }
+    let height = mask.len() / width;
+    let reach = (2.5 * radius).ceil() as isize;
+    let kernel: Vec<f64> = (-reach..=reach)
+        .map(|i| (-((i * i) as f64) / (2.0 * radius * radius)).exp())
+        .collect();
+    let total: f64 = kernel.iter().sum();
+    let blur = |values: &[f64], len: usize, at: &dyn Fn(usize) -> usize, i: isize| {
+        let mut sum = 0.0;
+        for (k, weight) in kernel.iter().enumerate() {
+            let j = i + k as isize - reach;
+            if j >= 0 && (j as usize) < len {
+                sum += values[at(j as usize)] * weight;
+            }
+        }
+        sum / total
+    };
+    let source: Vec<f64> = mask.iter().map(|&coverage| coverage as f64).collect();
+    let mut rows = vec![0.0; source.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[y * width + x] = blur(&source, width, &|j| y * width + j, x as isize);
+        }
+    }
+    let mut blurred = vec![0; source.len()];
+    for x in 0..width {
+        for y in 0..height {
+            let value = blur(&rows, height, &|j| j * width + x, y as isize);
+            blurred[y * width + x] = value.round().min(255.0) as u8;
+        }
+    }
+    blurred
+}
+
+/// How [`PathFinderRenderContext::fill_blended`] combines a fill with what is
+/// already drawn beneath it.
+///
+/// These are the blend modes of the [Compositing and Blending] spec.
+///
+/// [Compositing and Blending]: https://www.w3.org/TR/compositing-1/#blending
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode {
+    fn composite_operation(self) -> CompositeOperation {
+        match self {
+            BlendMode::Normal => CompositeOperation::SourceOver,
+            BlendMode::Multiply => CompositeOperation::Multiply,
+            BlendMode::Screen => CompositeOperation::Screen,
+            BlendMode::Overlay => CompositeOperation::Overlay,
+            BlendMode::Darken => CompositeOperation::Darken,
+            BlendMode::Lighten => CompositeOperation::Lighten,
+            BlendMode::ColorDodge => CompositeOperation::ColorDodge,
+            BlendMode::ColorBurn => CompositeOperation::ColorBurn,
+            BlendMode::HardLight => CompositeOperation::HardLight,
+            BlendMode::SoftLight => CompositeOperation::SoftLight,
+            BlendMode::Difference => CompositeOperation::Difference,
+            BlendMode::Exclusion => CompositeOperation::Exclusion,
+            BlendMode::Hue => CompositeOperation::Hue,
+            BlendMode::Saturation => CompositeOperation::Saturation,
+            BlendMode::Color => CompositeOperation::Color,
+            BlendMode::Luminosity => CompositeOperation::Luminosity,
+        }
+    }
+}
+
+/// A cache of converted paths, keyed by the elements of the source shape.
+///
+/// See [`PathFinderRenderContext::with_path_cache`].
+#[derive(Default)]
+pub struct PathCache {
+    paths: HashMap<u64, Vec<(Vec<PathEl>, pathfinder_canvas::Path2D)>>,
+}
+
+impl PathCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the number of cached paths.
+    pub fn len(&self) -> usize {
+        self.paths.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every cached path.
+    pub fn clear(&mut self) {
+        self.paths.clear();
+    }
+
+    /// Returns the path for `elements`, and whether they were all finite.
+    ///
+    /// Paths with non-finite elements aren't cached, so that the error is
+    /// reported every time they are drawn.
+    fn get_or_insert(&mut self, elements: &[PathEl]) -> (pathfinder_canvas::Path2D, bool) {
+        let fingerprint = path_fingerprint(elements);
+        let cached = self.paths.get(&fingerprint).and_then(|bucket| {
+            bucket
+                .iter()
+                .find(|(key, _)| key.as_slice() == elements)
+                .map(|(_, path)| path.clone())
+        });
+        if let Some(path) = cached {
+            return (path, true);
+        }
+        let mut path = pathfinder_canvas::Path2D::new();
+        let finite = append_elements(&mut path, elements.iter().copied());
+        // a bucket is only made for a path that is stored in it.
+        if finite {
+            self.paths
+                .entry(fingerprint)
+                .or_default()
+                .push((elements.to_owned(), path.clone()));
+        }
+        (path, finite)
+    }
+}
+
+fn path_fingerprint(elements: &[PathEl]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut hash_point = |point: Point| {
+        point.x.to_bits().hash(&mut hasher);
+        point.y.to_bits().hash(&mut hasher);
+    };
+    for element in elements {
+        std::mem::discriminant(element).hash(&mut hasher);
+        match *element {
+            PathEl::MoveTo(p) => hash_point(p),
+            PathEl::LineTo(p) => hash_point(p),
+            PathEl::QuadTo(p1, p2) => {
+                hash_point(p1);
+                hash_point(p2);
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                hash_point(p1);
+                hash_point(p2);
+                hash_point(p3);
+            }
+            PathEl::ClosePath => {}
+        }
+    }
+    hasher.finish()
+}
+
+impl<'a> PathFinderRenderContext<'a> {
+    /// Whether every save made since `layer` started has been restored.
+    fn has_balanced_saves(&self, layer: &Layer) -> bool {
+        self.saved_clips.len() == layer.save_depth
+    }
+
+    /// Records `error` for `status` to return, unless an earlier one is pending.
+    fn set_error(&mut self, error: Error) {
+        if self.error.is_ok() {
+            self.error = Err(error);
+        }
+    }
+
+    /// Intersects the clip region with `shape`, filled with `fill_rule`.
+    fn clip_with_rule(&mut self, shape: impl Shape, fill_rule: FillRule) {
+        self.intersect_clip_bounds(shape.bounding_box());
+        let region = ClipRegion::new(&shape, self.current_transform(), self.tolerance, fill_rule);
+        match self.clip_region.take() {
+            // The first clip can keep its curves.
+            None => {
+                let path = self.path_from_shape(shape);
+                self.canvas.clip_path(path, fill_rule);
+                self.clip_region = Some(region);
+            }
+            // The canvas would replace the previous clip, so it's given the
+            // composed region, which is already in device space.
+            Some(previous) => {
+                let region = previous.intersect(&region);
+                let transform = self.canvas.transform();
+                self.canvas.reset_transform();
+                self.canvas.clip_path(region.to_path(), region.fill_rule());
+                self.canvas.set_transform(&transform);
+                self.clip_region = Some(region);
+            }
+        }
+    }
+
+    fn intersect_clip_bounds(&mut self, rect: Rect) {
+        let rect = self.current_transform().transform_rect_bbox(rect);
+        self.clip_bounds = Some(match self.clip_bounds {
+            Some(bounds) => bounds.intersect(rect),
+            None => rect,
+        });
+    }
+
+    /// Converts `shape` into a path, leaving out elements with coordinates
+    /// that aren't finite and recording [`Error::InvalidInput`] if there were any.
+    fn path_from_shape(&mut self, shape: impl Shape) -> pathfinder_canvas::Path2D {
+        let (path, finite) = match (self.path_cache.as_mut(), shape.as_path_slice()) {
+            (Some(cache), Some(elements)) => cache.get_or_insert(elements),
+            _ => {
+                let mut path = pathfinder_canvas::Path2D::new();
+                let finite = append_shape(&mut path, shape, self.tolerance);
+                (path, finite)
+            }
+        };
+        if !finite {
+            self.set_error(Error::InvalidInput);
+        }
+        path
+    }
+
+    /// Draws the glyphs of `layout` that come from color fonts, each moved by
+    /// what `offset` returns for its baseline origin.
+    ///
+    /// Color glyphs can't be filled with a single color, so they are
+    /// rasterized by font-kit and drawn as images.
+    fn draw_color_glyphs(
+        &mut self,
+        layout: &PathfinderTextLayout,
+        pos: Vec2,
+        mut offset: impl FnMut(Point) -> Vec2,
+    ) {
+        let scale = self.device_scale();
+        for (glyph, origin) in layout.color_glyphs() {
+            let origin = origin + pos;
+            let shift = offset(origin);
+            if let Some((image, rect)) =
+                text::rasterize_color_glyph(glyph, layout.font_size(), scale)
+            {
+                self.canvas
+                    .draw_image(image, rectf_from_rect(rect + origin.to_vec2() + shift));
+            }
         }
     }
 
-    fn current_transform(&self) -> Affine {
-        let t = self.canvas.transform();
-        Affine::new([
-            t.matrix.m11().into(),
-            t.matrix.m21().into(),
-            t.matrix.m12().into(),
-            t.matrix.m22().into(),
-            t.vector.x().into(),
-            t.vector.y().into(),
-        ])
+    /// The number of device pixels per unit of the current coordinate space,
+    /// on average.
+    fn device_scale(&self) -> f64 {
+        self.current_transform().determinant().abs().sqrt() * self.device_pixel_ratio
     }
-}
 
-impl<'a> PathFinderRenderContext<'a> {
     fn set_interpolation(&mut self, interp: InterpolationMode) {
-        use InterpolationMode::*;
-        match interp {
-            NearestNeighbor => self.canvas.set_image_smoothing_enabled(false),
-            Bilinear => {
+        match image_smoothing_quality(interp, self.bilinear_quality) {
+            None => self.canvas.set_image_smoothing_enabled(false),
+            Some(quality) => {
                 self.canvas.set_image_smoothing_enabled(true);
-                // I'm assuming that the lowest quality is bi-linear.
-                self.canvas
-                    .set_image_smoothing_quality(ImageSmoothingQuality::Low);
+                self.canvas.set_image_smoothing_quality(quality);
             }
         }
     }
 }
 
-fn path2d_from_shape(shape: impl Shape) -> pathfinder_canvas::Path2D {
-    let mut path = pathfinder_canvas::Path2D::new();
-    if let Some(Line { p0, p1 }) = shape.as_line() {
+/// The smoothing quality used for a piet interpolation mode, or `None` if
+/// smoothing should be disabled.
+///
+/// `bilinear` is the quality chosen with
+/// [`PathFinderRenderContext::set_image_smoothing_quality`].
+fn image_smoothing_quality(
+    interp: InterpolationMode,
+    bilinear: ImageSmoothingQuality,
+) -> Option<ImageSmoothingQuality> {
+    match interp {
+        InterpolationMode::NearestNeighbor => None,
+        InterpolationMode::Bilinear => Some(bilinear),
+    }
+}
+
+/// Appends `shape` to `path`, returning whether all of its coordinates were
+/// finite; elements with coordinates that aren't are left out.
+fn append_shape(path: &mut pathfinder_canvas::Path2D, shape: impl Shape, tolerance: f64) -> bool {
+    // Shapes with non-finite coordinates skip the native primitives and are
+    // filtered element by element below.
+    let line = shape
+        .as_line()
+        .filter(|line| is_finite_point(line.p0) && is_finite_point(line.p1));
+    let rect = shape.as_rect().filter(|rect| is_finite_rect(*rect));
+    let rounded_rect = shape.as_rounded_rect().filter(|rounded_rect| {
+        let radii = rounded_rect.radii();
+        is_finite_rect(rounded_rect.rect())
+            && [
+                radii.top_left,
+                radii.top_right,
+                radii.bottom_right,
+                radii.bottom_left,
+            ]
+            .iter()
+            .all(|radius| (*radius as f32).is_finite())
+    });
+    let circle = shape
+        .as_circle()
+        .filter(|circle| is_finite_point(circle.center) && (circle.radius as f32).is_finite());
+    if let Some(Line { p0, p1 }) = line {
         path.move_to(vec2f_from_point(p0));
         path.line_to(vec2f_from_point(p1));
-    } else if let Some(rect) = shape.as_rect() {
+    } else if let Some(rect) = rect {
         path.rect(rectf_from_rect(rect));
+    } else if let Some(rounded_rect) = rounded_rect {
+        rounded_rect_to_path(path, rounded_rect);
+    } else if let Some(Circle { center, radius }) = circle {
+        // `Shape` has no accessor for ellipses, so only circles can use the
+        // native arc here; ellipses are flattened below like any other shape.
+        path.arc(
+            vec2f_from_point(center),
+            radius as f32,
+            0.0,
+            2.0 * std::f32::consts::PI,
+            ArcDirection::CW,
+        );
+        path.close_path();
     } else if let Some(els) = shape.as_path_slice() {
-        for element in els {
-            apply_el(&mut path, *element);
-        }
+        return append_elements(path, els.iter().copied());
     } else {
-        let bez_path = shape.path_elements(TOLERANCE);
-        for element in bez_path {
-            apply_el(&mut path, element);
+        return append_elements(path, shape.path_elements(tolerance));
+    }
+    true
+}
+
+/// Appends `ellipse` as a native ellipse, returning `false`, and appending
+/// nothing, if its center, radii or rotation aren't finite.
+fn append_ellipse(path: &mut pathfinder_canvas::Path2D, ellipse: Ellipse) -> bool {
+    let radii = ellipse.radii();
+    let rotation = ellipse.rotation() as f32;
+    if !(is_finite_point(ellipse.center()) && is_finite_point(radii.to_point()))
+        || !rotation.is_finite()
+    {
+        return false;
+    }
+    path.ellipse(
+        vec2f_from_point(ellipse.center()),
+        vec2f_from_point(radii.to_point()),
+        rotation,
+        0.0,
+        2.0 * std::f32::consts::PI,
+    );
+    path.close_path();
+    true
+}
+
+/// Traces a rounded rect as straight edges joined by quarter arcs.
+///
+/// Radii larger than half the shorter side are clamped, and corners with a
+/// zero radius are drawn as sharp corners.
+fn rounded_rect_to_path(path: &mut pathfinder_canvas::Path2D, rounded_rect: RoundedRect) {
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    let rect = rounded_rect.rect().abs();
+    let radii = rounded_rect.radii();
+    let max_radius = rect.width().min(rect.height()) / 2.0;
+    let clamp = |radius: f64| radius.max(0.0).min(max_radius);
+    let (tl, tr, br, bl) = (
+        clamp(radii.top_left),
+        clamp(radii.top_right),
+        clamp(radii.bottom_right),
+        clamp(radii.bottom_left),
+    );
+    if tl == 0.0 && tr == 0.0 && br == 0.0 && bl == 0.0 {
+        path.rect(rectf_from_rect(rect));
+        return;
+    }
+
+    let corner = |path: &mut pathfinder_canvas::Path2D, center: Point, radius: f64, start: f32| {
+        if radius > 0.0 {
+            path.arc(
+                vec2f_from_point(center),
+                radius as f32,
+                start,
+                start + FRAC_PI_2,
+                ArcDirection::CW,
+            );
+        }
+    };
+
+    path.move_to(vec2f_from_point(Point::new(rect.x0 + tl, rect.y0)));
+    path.line_to(vec2f_from_point(Point::new(rect.x1 - tr, rect.y0)));
+    corner(path, Point::new(rect.x1 - tr, rect.y0 + tr), tr, -FRAC_PI_2);
+    path.line_to(vec2f_from_point(Point::new(rect.x1, rect.y1 - br)));
+    corner(path, Point::new(rect.x1 - br, rect.y1 - br), br, 0.0);
+    path.line_to(vec2f_from_point(Point::new(rect.x0 + bl, rect.y1)));
+    corner(path, Point::new(rect.x0 + bl, rect.y1 - bl), bl, FRAC_PI_2);
+    path.line_to(vec2f_from_point(Point::new(rect.x0, rect.y0 + tl)));
+    corner(path, Point::new(rect.x0 + tl, rect.y0 + tl), tl, PI);
+    path.close_path();
+}
+
+/// Appends kurbo path elements to `path`, returning whether all of them were
+/// finite.
+///
+/// A kurbo subpath may carry on after a `ClosePath` without a `MoveTo`, from
+/// where the closed subpath started, while the canvas would add the segments
+/// to the closed contour; so a new contour is started there first.
+///
+/// Elements with a coordinate that isn't finite once converted to `f32` would
+/// corrupt the scene, so they are left out.
+fn append_elements(
+    path: &mut pathfinder_canvas::Path2D,
+    elements: impl IntoIterator<Item = PathEl>,
+) -> bool {
+    let mut subpath_start = None;
+    let mut closed = false;
+    let mut finite = true;
+    for element in elements {
+        if !is_finite_element(element) {
+            finite = false;
+            continue;
+        }
+        match element {
+            PathEl::MoveTo(point) => subpath_start = Some(point),
+            _ if closed => {
+                if let Some(start) = subpath_start {
+                    path.move_to(vec2f_from_point(start));
+                }
+            }
+            _ => {}
         }
+        closed = element == PathEl::ClosePath;
+        apply_el(path, element);
     }
-    path
+    finite
+}
+
+/// Whether `shape` has no path elements at all, such as an empty `BezPath`.
+fn has_no_elements(shape: &impl Shape) -> bool {
+    match shape.as_path_slice() {
+        Some(elements) => elements.is_empty(),
+        None => shape.path_elements(DEFAULT_TOLERANCE).next().is_none(),
+    }
+}
+
+fn is_finite_element(element: PathEl) -> bool {
+    match element {
+        PathEl::MoveTo(p) | PathEl::LineTo(p) => is_finite_point(p),
+        PathEl::QuadTo(p0, p1) => is_finite_point(p0) && is_finite_point(p1),
+        PathEl::CurveTo(p0, p1, p2) => {
+            is_finite_point(p0) && is_finite_point(p1) && is_finite_point(p2)
+        }
+        PathEl::ClosePath => true,
+    }
+}
+
+fn is_finite_point(point: Point) -> bool {
+    (point.x as f32).is_finite() && (point.y as f32).is_finite()
+}
+
+fn is_finite_rect(rect: Rect) -> bool {
+    is_finite_point(rect.origin()) && is_finite_point(Point::new(rect.x1, rect.y1))
 }
 
 fn apply_el(path: &mut pathfinder_canvas::Path2D, element: PathEl) {
@@ -469,6 +2050,122 @@ fn apply_el(path: &mut pathfinder_canvas::Path2D, element: PathEl) {
     }
 }
 
+/// Sorts gradient stops by their position, clamped to `0.0..=1.0`, keeping
+/// the order of stops at the same position so that they still make a hard edge.
+///
+/// Returns [`Error::InvalidInput`] if a position is NaN.
+fn sorted_stops(mut stops: Vec<piet::GradientStop>) -> Result<Vec<piet::GradientStop>, Error> {
+    if stops.iter().any(|stop| stop.pos.is_nan()) {
+        return Err(Error::InvalidInput);
+    }
+    for stop in &mut stops {
+        stop.pos = stop.pos.max(0.0).min(1.0);
+    }
+    stops.sort_by(|a, b| a.pos.partial_cmp(&b.pos).unwrap());
+    Ok(stops)
+}
+
+/// How many stops [`linear_light_stops`] places between each pair of stops.
+const LINEAR_LIGHT_STEPS: usize = 16;
+
+/// Turns sorted stops blended in sRGB into ones that follow the same colors
+/// blended in linear light, by adding stops in between.
+fn linear_light_stops(stops: &[piet::GradientStop]) -> Vec<piet::GradientStop> {
+    let mut expanded = Vec::with_capacity(stops.len() * LINEAR_LIGHT_STEPS);
+    for pair in stops.windows(2) {
+        let (start, end) = (&pair[0], &pair[1]);
+        let (r0, g0, b0, a0) = start.color.as_rgba();
+        let (r1, g1, b1, a1) = end.color.as_rgba();
+        let (r0, g0, b0) = (srgb_to_linear(r0), srgb_to_linear(g0), srgb_to_linear(b0));
+        let (r1, g1, b1) = (srgb_to_linear(r1), srgb_to_linear(g1), srgb_to_linear(b1));
+        for step in 0..LINEAR_LIGHT_STEPS {
+            let t = step as f64 / LINEAR_LIGHT_STEPS as f64;
+            let mix = |a: f64, b: f64| a + (b - a) * t;
+            expanded.push(piet::GradientStop {
+                pos: start.pos + (end.pos - start.pos) * t as f32,
+                color: Color::rgba(
+                    linear_to_srgb(mix(r0, r1)),
+                    linear_to_srgb(mix(g0, g1)),
+                    linear_to_srgb(mix(b0, b1)),
+                    mix(a0, a1),
+                ),
+            });
+        }
+    }
+    expanded.extend(stops.last().cloned());
+    expanded
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts a piet dash pattern into the dash array expected by the canvas.
+///
+/// An empty pattern draws a solid line. As in CSS, an odd number of lengths is
+/// repeated once so that dashes and gaps keep alternating, and a pattern with
+/// a negative or NaN length, or no length at all, draws a solid line too.
+fn line_dash_from_pattern(pattern: &[f64]) -> Vec<f32> {
+    let invalid = |length: &f64| length.is_nan() || *length < 0.0;
+    if pattern.iter().any(invalid) || pattern.iter().sum::<f64>() == 0.0 {
+        return Vec::new();
+    }
+    let mut dash: Vec<f32> = pattern.iter().map(|length| *length as f32).collect();
+    if dash.len() % 2 == 1 {
+        let repeated = dash.clone();
+        dash.extend(repeated);
+    }
+    dash
+}
+
+/// Lengthens the dashes of `dash` that have no length a little, at the expense
+/// of the gaps after them.
+///
+/// The canvas drops such dashes, but with round or square caps they should
+/// still be drawn as their caps, such as a row of dots.
+fn lengthen_capped_dashes(mut dash: Vec<f32>, cap: piet::LineCap) -> Vec<f32> {
+    const MIN_CAPPED_DASH: f32 = 0.01;
+    if cap != piet::LineCap::Butt {
+        for pair in dash.chunks_mut(2) {
+            if pair[0] <= 0.0 {
+                pair[0] = MIN_CAPPED_DASH;
+                pair[1] = (pair[1] - MIN_CAPPED_DASH).max(0.0);
+            }
+        }
+    }
+    dash
+}
+
+/// The canvas join for a piet one, along with the miter limit to use.
+fn line_join_from_piet(join: piet::LineJoin) -> (pathfinder_canvas::LineJoin, f32) {
+    let default_limit = piet::LineJoin::DEFAULT_MITER_LIMIT as f32;
+    match join {
+        piet::LineJoin::Miter { limit } => (pathfinder_canvas::LineJoin::Miter, limit as f32),
+        piet::LineJoin::Round => (pathfinder_canvas::LineJoin::Round, default_limit),
+        piet::LineJoin::Bevel => (pathfinder_canvas::LineJoin::Bevel, default_limit),
+    }
+}
+
+fn line_cap_from_piet(cap: piet::LineCap) -> pathfinder_canvas::LineCap {
+    match cap {
+        piet::LineCap::Butt => pathfinder_canvas::LineCap::Butt,
+        piet::LineCap::Round => pathfinder_canvas::LineCap::Round,
+        piet::LineCap::Square => pathfinder_canvas::LineCap::Square,
+    }
+}
+
 fn vec2f_from_point(point: Point) -> Vector2F {
     pathfinder_geometry::vector::vec2f(point.x as f32, point.y as f32)
 }
@@ -477,8 +2174,34 @@ fn vec2f_from_size(size: Size) -> Vector2F {
     pathfinder_geometry::vector::vec2f(size.width as f32, size.height as f32)
 }
 
-fn vec2i_from_size(size: Size) -> Vector2I {
-    pathfinder_geometry::vector::vec2i(size.width as i32, size.height as i32)
+/// Clips `src` to an image of `size`, shrinking `dst` to match, or returns
+/// `None` if no part of `src` is inside the image.
+fn clamp_image_area(src: Rect, dst: Rect, size: Size) -> Option<(Rect, Rect)> {
+    let clamped = src.intersect(size.to_rect());
+    if !(clamped.width() > 0.0 && clamped.height() > 0.0) {
+        return None;
+    }
+    let scale_x = dst.width() / src.width();
+    let scale_y = dst.height() / src.height();
+    let dst = Rect::new(
+        dst.x0 + (clamped.x0 - src.x0) * scale_x,
+        dst.y0 + (clamped.y0 - src.y0) * scale_y,
+        dst.x1 - (src.x1 - clamped.x1) * scale_x,
+        dst.y1 - (src.y1 - clamped.y1) * scale_y,
+    );
+    Some((clamped, dst))
+}
+
+fn transform2f_from_affine(affine: Affine) -> Transform2F {
+    let coeffs = affine.as_coeffs();
+    Transform2F::row_major(
+        coeffs[0] as f32,
+        coeffs[2] as f32,
+        coeffs[1] as f32,
+        coeffs[3] as f32,
+        coeffs[4] as f32,
+        coeffs[5] as f32,
+    )
 }
 
 fn rectf_from_rect(rect: Rect) -> pathfinder_geometry::rect::RectF {
@@ -490,6 +2213,11 @@ fn rectf_from_rect(rect: Rect) -> pathfinder_geometry::rect::RectF {
 pub struct FontSource {
     in_memory_source: std::sync::Mutex<font_kit::sources::mem::MemSource>,
     multi_source: font_kit::sources::multi::MultiSource,
+    /// Incremented whenever in-memory fonts are added or removed.
+    generation: AtomicUsize,
+    /// Whether `in_memory_source` has any fonts, so that selection can skip
+    /// locking it when it doesn't.
+    has_in_memory_fonts: AtomicBool,
 }
 
 impl FontSource {
@@ -497,6 +2225,130 @@ impl FontSource {
         FontSource {
             multi_source: font_kit::sources::multi::MultiSource::from_sources(sources),
             in_memory_source: Mutex::new(font_kit::sources::mem::MemSource::empty()),
+            generation: AtomicUsize::new(0),
+            has_in_memory_fonts: AtomicBool::new(false),
+        }
+    }
+
+    /// Creates a font source for the fonts installed on the system.
+    ///
+    /// Like every `FontSource`, it also holds the fonts loaded with
+    /// [`Text::load_font`], in memory, so no separate memory source is needed.
+    ///
+    /// [`Text::load_font`]: piet::Text::load_font
+    pub fn system() -> Self {
+        FontSource::new(vec![Box::new(font_kit::source::SystemSource::new())])
+    }
+
+    /// Creates a font source for the system fonts followed by an empty
+    /// `MemSource`, the sources the examples used to set up by hand.
+    ///
+    /// This selects the same fonts as [`system`]; the extra source is only
+    /// kept for code that expects it.
+    ///
+    /// [`system`]: FontSource::system
+    pub fn with_system_and_memory() -> Self {
+        FontSource::new(vec![
+            Box::new(font_kit::source::SystemSource::new()),
+            Box::new(font_kit::sources::mem::MemSource::empty()),
+        ])
+    }
+
+    /// Locks the in-memory fonts, or returns `None` if there aren't any.
+    fn in_memory_source(&self) -> Option<MutexGuard<font_kit::sources::mem::MemSource>> {
+        if self.has_in_memory_fonts.load(Ordering::Acquire) {
+            Some(self.in_memory_source.lock().unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// Adds a font to the in-memory fonts, which take precedence over the
+    /// other sources.
+    pub(crate) fn add_in_memory_font(
+        &self,
+        handle: Handle,
+    ) -> Result<font_kit::font::Font, FontLoadingError> {
+        let font = self.in_memory_source.lock().unwrap().add_font(handle)?;
+        self.has_in_memory_fonts.store(true, Ordering::Release);
+        self.bump_generation();
+        Ok(font)
+    }
+
+    /// Returns a counter that changes whenever the set of in-memory fonts does,
+    /// for invalidating anything resolved from them.
+    pub(crate) fn generation(&self) -> usize {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn bump_generation(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Returns the names of all available font families, without duplicates.
+    ///
+    /// Families loaded with [`Text::load_font`] come first, followed by those
+    /// from the sources passed to [`FontSource::new`].
+    ///
+    /// [`Text::load_font`]: piet::Text::load_font
+    pub fn family_names(&self) -> Result<Vec<String>, SelectionError> {
+        let mut names = match self.in_memory_source() {
+            Some(source) => source.all_families()?,
+            None => Vec::new(),
+        };
+        names.extend(self.multi_source.all_families()?);
+        let mut seen = HashSet::new();
+        names.retain(|name| seen.insert(name.clone()));
+        Ok(names)
+    }
+
+    /// Unloads the fonts of `family` that were added with [`Text::load_font`].
+    ///
+    /// Returns `false` if no such font was loaded. Layouts that were already
+    /// built keep their own reference to the font and still draw with it; only
+    /// layouts built afterwards are affected.
+    ///
+    /// [`Text::load_font`]: piet::Text::load_font
+    pub fn remove_font(&self, family: &FontFamily) -> Result<bool, Error> {
+        let mut in_memory_source = self.in_memory_source.lock().unwrap();
+        let mut kept = Vec::new();
+        let mut removed = false;
+        for handle in in_memory_source
+            .all_fonts()
+            .map_err(|err| Error::BackendError(Box::new(err)))?
+        {
+            let font = handle
+                .load()
+                .map_err(|err| Error::BackendError(Box::new(err)))?;
+            if font.family_name() == family.name() {
+                removed = true;
+            } else {
+                kept.push(handle);
+            }
+        }
+        if removed {
+            let now_empty = kept.is_empty();
+            *in_memory_source = font_kit::sources::mem::MemSource::from_fonts(kept.into_iter())
+                .map_err(|err| Error::BackendError(Box::new(err)))?;
+            self.has_in_memory_fonts
+                .store(!now_empty, Ordering::Release);
+            self.bump_generation();
+        }
+        Ok(removed)
+    }
+
+    /// Unloads every font that was added with [`Text::load_font`], leaving
+    /// only the fonts of the sources the `FontSource` was created with.
+    ///
+    /// As with [`remove_font`], layouts that were already built keep drawing
+    /// with their fonts.
+    ///
+    /// [`Text::load_font`]: piet::Text::load_font
+    /// [`remove_font`]: FontSource::remove_font
+    pub fn clear_fonts(&self) {
+        *self.in_memory_source.lock().unwrap() = font_kit::sources::mem::MemSource::empty();
+        if self.has_in_memory_fonts.swap(false, Ordering::AcqRel) {
+            self.bump_generation();
         }
     }
 }
@@ -504,34 +2356,24 @@ impl FontSource {
 impl font_kit::source::Source for FontSource {
     fn all_fonts(&self) -> Result<Vec<Handle>, SelectionError> {
         let mut handles = self.multi_source.all_fonts()?;
-        handles.extend(
-            self.in_memory_source
-                .lock()
-                .unwrap()
-                .all_fonts()?
-                .into_iter(),
-        );
+        if let Some(source) = self.in_memory_source() {
+            handles.extend(source.all_fonts()?);
+        }
         Ok(handles)
     }
 
     fn all_families(&self) -> Result<Vec<String>, SelectionError> {
         let mut handles = self.multi_source.all_families()?;
-        handles.extend(
-            self.in_memory_source
-                .lock()
-                .unwrap()
-                .all_families()?
-                .into_iter(),
-        );
+        if let Some(source) = self.in_memory_source() {
+            handles.extend(source.all_families()?);
+        }
         Ok(handles)
     }
 
     fn select_family_by_name(&self, family_name: &str) -> Result<FamilyHandle, SelectionError> {
-        if let Ok(handle) = self
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .select_family_by_name(family_name)
+        if let Some(handle) = self
+            .in_memory_source()
+            .and_then(|source| source.select_family_by_name(family_name).ok())
         {
             Ok(handle)
         } else {
@@ -540,11 +2382,9 @@ impl font_kit::source::Source for FontSource {
     }
 
     fn select_by_postscript_name(&self, postscript_name: &str) -> Result<Handle, SelectionError> {
-        if let Ok(handle) = self
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .select_by_postscript_name(postscript_name)
+        if let Some(handle) = self
+            .in_memory_source()
+            .and_then(|source| source.select_by_postscript_name(postscript_name).ok())
         {
             Ok(handle)
         } else {
@@ -556,11 +2396,9 @@ impl font_kit::source::Source for FontSource {
         &self,
         family_name: &FamilyName,
     ) -> Result<FamilyHandle, SelectionError> {
-        if let Ok(handle) = self
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .select_family_by_generic_name(family_name)
+        if let Some(handle) = self
+            .in_memory_source()
+            .and_then(|source| source.select_family_by_generic_name(family_name).ok())
         {
             Ok(handle)
         } else {
@@ -573,11 +2411,9 @@ impl font_kit::source::Source for FontSource {
         family_names: &[FamilyName],
         properties: &Properties,
     ) -> Result<Handle, SelectionError> {
-        if let Ok(handle) = self
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .select_best_match(family_names, properties)
+        if let Some(handle) = self
+            .in_memory_source()
+            .and_then(|source| source.select_best_match(family_names, properties).ok())
         {
             Ok(handle)
         } else {
@@ -590,11 +2426,9 @@ impl font_kit::source::Source for FontSource {
         &self,
         family: &FamilyHandle,
     ) -> Result<Vec<Properties>, SelectionError> {
-        if let Ok(properties) = self
-            .in_memory_source
-            .lock()
-            .unwrap()
-            .select_descriptions_in_family(family)
+        if let Some(properties) = self
+            .in_memory_source()
+            .and_then(|source| source.select_descriptions_in_family(family).ok())
         {
             Ok(properties)
         } else {
@@ -613,12 +2447,828 @@ impl font_kit::source::Source for FontSource {
 
 impl<'a> PathFinderRenderContext<'a> {
     fn set_fill_style(&mut self, shape: &impl Shape, brush: &impl IntoBrush<Self>) {
-        let brush = brush.make_brush(self, || shape.bounding_box());
+        let bbox = shape.bounding_box();
+        let brush = brush.make_brush(self, || bbox);
+        self.set_fill_brush(&brush, bbox);
+    }
+
+    /// Sets the canvas's fill style for painting `brush` within `bbox`, unless
+    /// it is already set from an equal brush.
+    ///
+    /// Sweep gradients are rasterized for `bbox`, so they are always set.
+    fn set_fill_brush(&mut self, brush: &Brush, bbox: Rect) {
+        if let Brush::Sweep(_) = brush {
+            let style = self.fill_style_from_brush(brush, bbox);
+            return self.set_canvas_fill_style(style);
+        }
+        if self.fill_brush.as_ref() != Some(brush) {
+            let style = self.fill_style_from_brush(brush, bbox);
+            self.set_canvas_fill_style(style);
+            self.fill_brush = Some(brush.clone());
+        }
+    }
+
+    /// Sets the canvas's fill style to one that isn't from a brush.
+    fn set_canvas_fill_style(&mut self, style: FillStyle) {
+        self.canvas.set_fill_style(style);
+        self.fill_brush = None;
+    }
+
+    fn set_stroke_style(&mut self, shape: &impl Shape, brush: &impl IntoBrush<Self>, width: f64) {
+        let bbox = shape.bounding_box().inflate(width / 2.0, width / 2.0);
+        let brush = brush.make_brush(self, || bbox);
+        let style = self.fill_style_from_brush(&brush, bbox);
+        self.canvas.set_stroke_style(style);
+    }
+
+    /// Converts `brush` into a canvas style, for painting a shape within `bbox`.
+    ///
+    /// A sweep gradient that can't be drawn records its error and paints
+    /// nothing.
+    fn fill_style_from_brush(&mut self, brush: &Brush, bbox: Rect) -> FillStyle {
         match *brush {
-            Brush::Solid(color) => self
-                .canvas
-                .set_fill_style(FillStyle::Color(ColorU::from_u32(color))),
-            Brush::Gradient => {}
+            Brush::Solid(color) => FillStyle::Color(ColorU::from_u32(color)),
+            Brush::Gradient(ref gradient) => FillStyle::Gradient(gradient.clone()),
+            Brush::Sweep(ref sweep) => {
+                let scale = self.device_scale();
+                // only the part of the shape on the canvas is rasterized, so a
+                // huge shape doesn't make a huge image.
+                let transform = self.current_transform();
+                let rect = if transform.determinant() != 0.0 {
+                    let size = self.canvas.canvas().size().to_f32();
+                    let visible = Rect::new(0.0, 0.0, size.x().into(), size.y().into());
+                    bbox.intersect(transform.inverse().transform_rect_bbox(visible))
+                } else {
+                    bbox
+                };
+                match self.sweep_cache.pattern(sweep, rect, scale) {
+                    Ok(pattern) => FillStyle::Pattern(pattern),
+                    Err(err) => {
+                        self.set_error(err);
+                        FillStyle::Color(ColorU::transparent_black())
+                    }
+                }
+            }
+            Brush::Image(ref image) => FillStyle::Pattern(image.to_pattern()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piet::{Text as _, TextLayoutBuilder as _};
+
+    fn with_context(f: impl FnOnce(&mut PathFinderRenderContext)) {
+        let font_source = Arc::new(FontSource::system());
+        let font_context = CanvasFontContext::new(font_source.clone());
+        let mut canvas = pathfinder_canvas::Canvas::new(pathfinder_canvas::vec2f(100.0, 100.0))
+            .get_context_2d(font_context);
+        f(&mut PathFinderRenderContext::new(&mut canvas, font_source));
+    }
+
+    #[test]
+    fn system_font_source_finds_generic_families() {
+        let family = FontSource::system()
+            .select_family_by_generic_name(&FamilyName::SansSerif)
+            .unwrap();
+        assert!(!family.fonts().is_empty());
+        let family = FontSource::with_system_and_memory()
+            .select_family_by_generic_name(&FamilyName::SansSerif)
+            .unwrap();
+        assert!(!family.fonts().is_empty());
+    }
+
+    #[test]
+    fn finish_reports_unbalanced_saves() {
+        with_context(|rc| {
+            rc.save().unwrap();
+            assert!(matches!(rc.finish(), Err(Error::StackUnbalance)));
+        });
+        with_context(|rc| {
+            rc.push_opacity_layer(0.5);
+            assert!(matches!(rc.finish(), Err(Error::StackUnbalance)));
+        });
+        with_context(|rc| {
+            rc.save().unwrap();
+            rc.restore().unwrap();
+            assert!(rc.finish().is_ok());
+        });
+    }
+
+    #[test]
+    fn finish_returns_pending_errors() {
+        with_context(|rc| {
+            rc.blurred_rect(Rect::new(0.0, 0.0, 10.0, 10.0), -1.0, &Color::BLACK);
+            assert!(matches!(rc.finish(), Err(Error::InvalidInput)));
+        });
+    }
+
+    #[test]
+    fn missing_families_are_only_warnings() {
+        let font_source = Arc::new(FontSource::system());
+        let font_context = CanvasFontContext::new(font_source.clone());
+        let mut canvas = pathfinder_canvas::Canvas::new(pathfinder_canvas::vec2f(100.0, 100.0))
+            .get_context_2d(font_context);
+        let mut rc = PathFinderRenderContext::new(&mut canvas, font_source);
+        let layout = rc
+            .text()
+            .new_text_layout("fallback")
+            .font(
+                FontFamily::new_unchecked("Not A Real Font Family 5f3a"),
+                16.0,
+            )
+            .build()
+            .unwrap();
+        rc.draw_text(&layout, (0.0, 0.0));
+        assert!(rc.status().is_ok());
+        assert!(rc.text().take_missing_font());
+        assert!(!rc.text().take_missing_font());
+        assert!(rc.into_scene().unwrap().draw_path_count() > 0);
+    }
+
+    #[test]
+    fn layers_must_be_ended_the_way_they_began() {
+        with_context(|rc| {
+            rc.begin_layer(Size::new(10.0, 10.0));
+            assert!(matches!(rc.pop_layer(), Err(Error::StackUnbalance)));
+            assert_eq!(
+                rc.end_layer_as_image().unwrap().size(),
+                Size::new(10.0, 10.0)
+            );
+            rc.push_opacity_layer(0.5);
+            assert!(rc.end_layer_as_image().is_err());
+            rc.pop_layer().unwrap();
+            assert!(rc.end_layer_as_image().is_err());
+        });
+    }
+
+    #[test]
+    fn saves_can_only_be_restored_in_the_layer_they_were_made_in() {
+        with_context(|rc| {
+            rc.save().unwrap();
+            rc.push_opacity_layer(0.5);
+            assert!(matches!(rc.restore(), Err(Error::StackUnbalance)));
+            rc.save().unwrap();
+            assert!(matches!(rc.pop_layer(), Err(Error::StackUnbalance)));
+            rc.restore().unwrap();
+            rc.pop_layer().unwrap();
+            rc.restore().unwrap();
+            assert!(rc.finish().is_ok());
+        });
+        with_context(|rc| {
+            rc.begin_layer(Size::new(10.0, 10.0));
+            rc.save().unwrap();
+            assert!(rc.end_layer_as_image().is_err());
+            rc.restore().unwrap();
+            assert!(rc.end_layer_as_image().is_ok());
+        });
+    }
+
+    #[test]
+    fn layers_after_finish_are_ignored() {
+        with_context(|rc| {
+            rc.finish().unwrap();
+            rc.push_opacity_layer(0.5);
+            assert!(matches!(rc.pop_layer(), Err(Error::StackUnbalance)));
+            rc.begin_layer(Size::new(10.0, 10.0));
+            assert!(matches!(
+                rc.end_layer_as_image(),
+                Err(Error::StackUnbalance)
+            ));
+        });
+    }
+
+    #[test]
+    fn drawing_after_finish_does_nothing() {
+        with_context(|rc| {
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::BLACK);
+            rc.finish().unwrap();
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::BLACK);
+            rc.stroke(Line::new((0.0, 0.0), (10.0, 10.0)), &Color::BLACK, 1.0);
+            rc.stroke_hairline(Line::new((0.0, 0.0), (10.0, 10.0)), &Color::BLACK);
+            rc.fill_blended(
+                Rect::new(0.0, 0.0, 10.0, 10.0),
+                &Color::BLACK,
+                BlendMode::Multiply,
+            );
+            assert_eq!(rc.take_scene().unwrap().draw_path_count(), 1);
+        });
+    }
+
+    #[test]
+    fn blended_fills_restore_the_composite_operation() {
+        with_context(|rc| {
+            rc.canvas_mut()
+                .set_global_composite_operation(CompositeOperation::Lighter);
+            rc.fill_blended(
+                Rect::new(0.0, 0.0, 10.0, 10.0),
+                &Color::BLACK,
+                BlendMode::Multiply,
+            );
+            assert!(matches!(
+                rc.canvas.global_composite_operation(),
+                CompositeOperation::Lighter
+            ));
+        });
+    }
+
+    #[test]
+    fn empty_shapes_draw_nothing() {
+        with_context(|rc| {
+            let empty = piet::kurbo::BezPath::new();
+            rc.fill(&empty, &Color::BLACK);
+            rc.fill_even_odd(&empty, &Color::BLACK);
+            rc.stroke(&empty, &Color::BLACK, 1.0);
+            rc.stroke_hairline(&empty, &Color::BLACK);
+            rc.fill(Rect::new(10.0, 10.0, 10.0, 20.0), &Color::BLACK);
+            rc.fill_path_iter(empty.iter(), &Color::BLACK);
+            assert!(rc.status().is_ok());
+            assert_eq!(rc.take_scene().unwrap().draw_path_count(), 0);
+            // thin shapes still cover something.
+            rc.fill(Rect::new(10.0, 10.0, 10.1, 20.0), &Color::BLACK);
+            rc.stroke(Rect::new(10.0, 10.0, 10.0, 20.0), &Color::BLACK, 1.0);
+            assert_eq!(rc.take_scene().unwrap().draw_path_count(), 2);
+        });
+    }
+
+    #[test]
+    fn blurred_rects_are_rasterized_at_the_device_pixel_ratio() {
+        let rect = Rect::new(10.0, 10.0, 30.0, 20.0);
+        let color = ColorU::black();
+        let (normal, normal_rect) = blurred_rect_image(rect, 2.0, color, 1.0).unwrap();
+        let (hidpi, hidpi_rect) = blurred_rect_image(rect, 2.0, color, 2.0).unwrap();
+        assert_eq!(hidpi.width(), normal.width() * 2);
+        assert_eq!(hidpi.height(), normal.height() * 2);
+        // both cover the same part of the canvas.
+        assert!((hidpi_rect.width() - normal_rect.width()).abs() < 1e-9);
+        assert!((hidpi_rect.height() - normal_rect.height()).abs() < 1e-9);
+        assert!((hidpi_rect.origin() - normal_rect.origin()).hypot() < 1.0);
+    }
+
+    #[test]
+    fn taken_scene_holds_the_drawn_paths() {
+        with_context(|rc| {
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::BLACK);
+            rc.fill(Circle::new((50.0, 50.0), 10.0), &Color::WHITE);
+            rc.stroke(Line::new((0.0, 0.0), (100.0, 100.0)), &Color::BLACK, 1.0);
+            assert_eq!(rc.take_scene().unwrap().draw_path_count(), 3);
+            // The canvas starts over after the scene is taken.
+            rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::BLACK);
+            assert_eq!(rc.take_scene().unwrap().draw_path_count(), 1);
+        });
+    }
+
+    #[test]
+    fn into_scene_finishes_the_context() {
+        let font_source = Arc::new(FontSource::system());
+        let font_context = CanvasFontContext::new(font_source.clone());
+        let mut canvas = pathfinder_canvas::Canvas::new(pathfinder_canvas::vec2f(100.0, 100.0))
+            .get_context_2d(font_context);
+        let mut rc = PathFinderRenderContext::new(&mut canvas, font_source.clone());
+        rc.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &Color::BLACK);
+        assert_eq!(rc.into_scene().unwrap().draw_path_count(), 1);
+
+        let mut rc = PathFinderRenderContext::new(&mut canvas, font_source);
+        rc.save().unwrap();
+        assert!(matches!(rc.into_scene(), Err(Error::StackUnbalance)));
+    }
+
+    #[test]
+    fn scene_is_not_taken_inside_a_layer() {
+        with_context(|rc| {
+            rc.push_opacity_layer(0.5);
+            assert!(matches!(rc.take_scene(), Err(Error::StackUnbalance)));
+            rc.pop_layer().unwrap();
+            assert!(rc.take_scene().is_ok());
+        });
+    }
+
+    fn path2d_from_shape(shape: impl Shape, tolerance: f64) -> pathfinder_canvas::Path2D {
+        let mut path = pathfinder_canvas::Path2D::new();
+        append_shape(&mut path, shape, tolerance);
+        path
+    }
+
+    fn contour_count(path: pathfinder_canvas::Path2D) -> usize {
+        path.into_outline().contours().len()
+    }
+
+    #[test]
+    fn quadratic_curves_are_not_raised_to_cubics() {
+        let mut path = piet::kurbo::BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.quad_to((50.0, -40.0), (100.0, 0.0));
+        path.quad_to((50.0, 40.0), (0.0, 0.0));
+        let outline = path2d_from_shape(path, DEFAULT_TOLERANCE).into_outline();
+        // a start point, then a control point and an end point for each curve.
+        assert_eq!(outline.contours()[0].len(), 5);
+    }
+
+    #[test]
+    fn open_paths_stay_open() {
+        let mut path = piet::kurbo::BezPath::new();
+        path.move_to((10.0, 10.0));
+        path.line_to((10.0, 50.0));
+        path.line_to((50.0, 50.0));
+        let outline = path2d_from_shape(path.clone(), DEFAULT_TOLERANCE).into_outline();
+        assert!(!outline.contours()[0].is_closed());
+
+        path.close_path();
+        let outline = path2d_from_shape(path, DEFAULT_TOLERANCE).into_outline();
+        assert!(outline.contours()[0].is_closed());
+    }
+
+    #[test]
+    fn subpaths_after_close_path_start_new_contours() {
+        let mut ring = Rect::new(0.0, 0.0, 30.0, 30.0).to_path(0.1);
+        ring.extend(Rect::new(10.0, 10.0, 20.0, 20.0).path_elements(0.1));
+        assert_eq!(contour_count(path2d_from_shape(ring, DEFAULT_TOLERANCE)), 2);
+
+        // a subpath may also go on from where the closed one started.
+        let mut path = piet::kurbo::BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((10.0, 0.0));
+        path.line_to((10.0, 10.0));
+        path.close_path();
+        path.line_to((-10.0, 0.0));
+        path.line_to((-10.0, -10.0));
+        path.close_path();
+        let outline = path2d_from_shape(path, DEFAULT_TOLERANCE).into_outline();
+        let contours = outline.contours();
+        assert_eq!(contours.len(), 2);
+        assert_eq!(
+            contours[1].position_of(0),
+            pathfinder_canvas::vec2f(0.0, 0.0)
+        );
+        assert_eq!(contours[1].len(), 3);
+    }
+
+    fn point_count(path: pathfinder_canvas::Path2D) -> u32 {
+        path.into_outline()
+            .contours()
+            .iter()
+            .map(|contour| contour.len())
+            .sum()
+    }
+
+    #[test]
+    fn circles_and_ellipses_are_native_arcs() {
+        let circle = Circle::new((50.0, 50.0), 40.0);
+        let native = point_count(path2d_from_shape(circle, 1e-4));
+        // the native arc doesn't depend on the tolerance...
+        assert_eq!(point_count(path2d_from_shape(circle, 10.0)), native);
+        // ...and needs fewer curves than kurbo's fine approximation.
+        let mut flattened = pathfinder_canvas::Path2D::new();
+        append_elements(&mut flattened, circle.path_elements(1e-4));
+        assert!(native < point_count(flattened));
+
+        let ellipse = Ellipse::new((50.0, 50.0), (40.0, 20.0), 0.5);
+        let mut path = pathfinder_canvas::Path2D::new();
+        assert!(append_ellipse(&mut path, ellipse));
+        assert_eq!(point_count(path), native);
+        let mut path = pathfinder_canvas::Path2D::new();
+        assert!(!append_ellipse(
+            &mut path,
+            Ellipse::new((50.0, f64::NAN), (40.0, 20.0), 0.0)
+        ));
+        assert_eq!(point_count(path), 0);
+    }
+
+    #[test]
+    fn rounded_rects_are_clamped_arcs() {
+        let rect = Rect::new(10.0, 10.0, 110.0, 70.0);
+        let sharp = path2d_from_shape(RoundedRect::from_rect(rect, 0.0), DEFAULT_TOLERANCE);
+        let plain = path2d_from_shape(rect, DEFAULT_TOLERANCE);
+        assert_eq!(point_count(sharp), point_count(plain));
+
+        // radii past half the shorter side are clamped, so the path stays
+        // inside the rect.
+        let huge = RoundedRect::from_rect(rect, 1000.0);
+        let bounds = path2d_from_shape(huge, DEFAULT_TOLERANCE)
+            .into_outline()
+            .bounds();
+        assert!((bounds.min_x() - 10.0).abs() < 0.01 && (bounds.max_x() - 110.0).abs() < 0.01);
+        assert!((bounds.min_y() - 10.0).abs() < 0.01 && (bounds.max_y() - 70.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn coarse_tolerance_produces_fewer_points() {
+        let ellipse = piet::kurbo::Ellipse::new((500.0, 500.0), (400.0, 300.0), 0.0);
+        let fine = point_count(path2d_from_shape(ellipse, DEFAULT_TOLERANCE));
+        let coarse = point_count(path2d_from_shape(ellipse, 50.0));
+        assert!(coarse < fine, "{} >= {}", coarse, fine);
+    }
+
+    #[test]
+    fn builder_sets_the_tolerance() {
+        let font_source = Arc::new(FontSource::system());
+        let font_context = CanvasFontContext::new(font_source.clone());
+        let mut canvas = pathfinder_canvas::Canvas::new(pathfinder_canvas::vec2f(100.0, 100.0))
+            .get_context_2d(font_context);
+        let ellipse = piet::kurbo::Ellipse::new((50.0, 50.0), (40.0, 30.0), 0.0);
+        let fine = point_count(path2d_from_shape(ellipse, DEFAULT_TOLERANCE));
+
+        let mut rc = PathFinderRenderContext::builder(&mut canvas, font_source)
+            .tolerance(5.0)
+            .build();
+        assert_eq!(rc.tolerance(), 5.0);
+        let coarse = point_count(rc.path_from_shape(ellipse));
+        assert!(coarse < fine, "{} >= {}", coarse, fine);
+    }
+
+    #[test]
+    fn path_cache_reuses_identical_paths() {
+        let mut bez_path = piet::kurbo::BezPath::new();
+        bez_path.move_to((10.0, 10.0));
+        bez_path.curve_to((20.0, 0.0), (40.0, 30.0), (50.0, 10.0));
+        bez_path.close_path();
+
+        let mut cache = PathCache::new();
+        let (first, _) = cache.get_or_insert(bez_path.elements());
+        let (second, _) = cache.get_or_insert(bez_path.elements());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(point_count(first), point_count(second));
+        assert_eq!(
+            point_count(cache.get_or_insert(bez_path.elements()).0),
+            point_count(path2d_from_shape(bez_path.clone(), DEFAULT_TOLERANCE))
+        );
+    }
+
+    #[test]
+    fn non_finite_elements_are_left_out() {
+        let mut bez_path = piet::kurbo::BezPath::new();
+        bez_path.move_to((0.0, 0.0));
+        bez_path.line_to((f64::NAN, 10.0));
+        bez_path.line_to((10.0, 10.0));
+        bez_path.line_to((1e300, 10.0));
+        bez_path.close_path();
+        let mut path = pathfinder_canvas::Path2D::new();
+        assert!(!append_elements(&mut path, bez_path.iter()));
+        assert_eq!(point_count(path), 2);
+
+        let mut cache = PathCache::new();
+        assert!(!cache.get_or_insert(bez_path.elements()).1);
+        assert!(cache.is_empty());
+        assert!(cache.paths.is_empty());
+    }
+
+    #[test]
+    fn clip_bounds_intersect_nested_clips() {
+        with_context(|rc| {
+            assert_eq!(rc.current_clip_bounds(), None);
+            rc.clip(Rect::new(10.0, 10.0, 50.0, 50.0));
+            assert_eq!(
+                rc.current_clip_bounds(),
+                Some(Rect::new(10.0, 10.0, 50.0, 50.0))
+            );
+
+            rc.save().unwrap();
+            rc.clip(Rect::new(30.0, 0.0, 80.0, 40.0));
+            assert_eq!(
+                rc.current_clip_bounds(),
+                Some(Rect::new(30.0, 10.0, 50.0, 40.0))
+            );
+            rc.restore().unwrap();
+            assert_eq!(
+                rc.current_clip_bounds(),
+                Some(Rect::new(10.0, 10.0, 50.0, 50.0))
+            );
+        });
+    }
+
+    #[test]
+    fn clip_bounds_follow_the_transform() {
+        with_context(|rc| {
+            rc.clip(Rect::new(0.0, 0.0, 10.0, 10.0));
+            rc.transform(Affine::translate((5.0, 5.0)));
+            assert_eq!(
+                rc.current_clip_bounds(),
+                Some(Rect::new(-5.0, -5.0, 5.0, 5.0))
+            );
+        });
+    }
+
+    // `with_save` comes from `RenderContext`; these check it against our
+    // `save`/`restore`.
+    #[test]
+    fn with_save_restores_the_transform() {
+        with_context(|rc| {
+            rc.with_save(|rc| {
+                rc.transform(Affine::translate((5.0, 5.0)));
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(rc.current_transform(), Affine::IDENTITY);
+        });
+    }
+
+    #[test]
+    fn with_save_restores_after_an_error() {
+        with_context(|rc| {
+            let result = rc.with_save(|rc| {
+                rc.clip(Rect::new(0.0, 0.0, 10.0, 10.0));
+                Err(Error::InvalidInput)
+            });
+            assert!(matches!(result, Err(Error::InvalidInput)));
+            assert_eq!(rc.current_clip_bounds(), None);
+            assert!(matches!(rc.restore(), Err(Error::StackUnbalance)));
+        });
+    }
+
+    #[test]
+    fn image_bytes_round_trip() {
+        let pixels: Vec<u8> = (0..2 * 3 * 4).map(|i| i as u8 * 10).collect();
+        with_context(|rc| {
+            let image = rc
+                .make_image(2, 3, &pixels, ImageFormat::RgbaSeparate)
+                .unwrap();
+            assert_eq!(image.to_rgba_bytes(), pixels);
+            assert_eq!(image.raw().dimensions(), (2, 3));
+        });
+    }
+
+    #[test]
+    fn negative_blur_radius_is_reported() {
+        with_context(|rc| {
+            rc.blurred_rect(Rect::new(0.0, 0.0, 10.0, 10.0), -1.0, &Color::BLACK);
+            assert!(matches!(rc.status(), Err(Error::InvalidInput)));
+            assert!(rc.status().is_ok());
+        });
+    }
+
+    #[test]
+    fn premultiplying_round_trips() {
+        let pixel = [200, 100, 51, 128];
+        let premultiplied = Image::from_rgba(1, 1, pixel.to_vec())
+            .unwrap()
+            .premultiplied_bytes();
+        let round_tripped = Image::from_premultiplied(1, 1, &premultiplied)
+            .unwrap()
+            .to_rgba_bytes();
+        for (original, round_tripped) in pixel.iter().zip(&round_tripped) {
+            assert!((*original as i32 - *round_tripped as i32).abs() <= 1);
+        }
+        assert_eq!(round_tripped[3], 128);
+    }
+
+    #[test]
+    fn crop_copies_the_region() {
+        let data: Vec<u8> = (0..16u8).flat_map(|i| vec![i, i, i, 255]).collect();
+        let image = Image::from_rgba(4, 4, data).unwrap();
+        let quarter = image.crop(Rect::new(0.0, 0.0, 2.0, 2.0)).unwrap();
+        assert_eq!(quarter.raw().dimensions(), (2, 2));
+        let reds: Vec<u8> = quarter.raw().pixels().map(|pixel| pixel[0]).collect();
+        assert_eq!(reds, vec![0, 1, 4, 5]);
+
+        assert!(matches!(
+            image.crop(Rect::new(2.0, 2.0, 5.0, 4.0)),
+            Err(Error::InvalidInput)
+        ));
+        assert!(matches!(
+            image.crop(Rect::new(1.0, 1.0, 1.0, 3.0)),
+            Err(Error::InvalidInput)
+        ));
+    }
+
+    #[test]
+    fn tinting_multiplies_each_channel() {
+        let data = vec![255, 255, 255, 255, 255, 255, 255, 0, 128, 64, 32, 255];
+        let image = Image::from_rgba(3, 1, data).unwrap();
+        let tinted = image.tinted(Color::rgba8(0, 128, 255, 128));
+        assert_eq!(
+            tinted.to_rgba_bytes(),
+            vec![0, 128, 255, 128, 0, 128, 255, 0, 0, 32, 32, 128]
+        );
+    }
+
+    #[test]
+    fn blurring_a_mask_keeps_its_total_coverage() {
+        // a 5x5 square in the middle of a 31x31 mask.
+        let mut mask = vec![0u8; 31 * 31];
+        for y in 13..18 {
+            for x in 13..18 {
+                mask[y * 31 + x] = 255;
+            }
+        }
+        let blurred = blur_mask(&mask, 31, 2.0);
+        let total: u32 = blurred.iter().map(|&coverage| coverage as u32).sum();
+        assert!(
+            (total as f64 / (25.0 * 255.0) - 1.0).abs() < 0.02,
+            "total {}",
+            total
+        );
+        assert!(blurred[15 * 31 + 15] < 255);
+        assert!(blurred[15 * 31 + 19] > 0);
+        assert_eq!(blurred[15 * 31 + 19], blurred[15 * 31 + 11]);
+        assert_eq!(blurred[19 * 31 + 15], blurred[15 * 31 + 19]);
+    }
+
+    #[test]
+    fn divisions_round_ties_to_even() {
+        assert_eq!(div_round_half_even(5, 2), 2);
+        assert_eq!(div_round_half_even(7, 2), 4);
+        assert_eq!(div_round_half_even(8, 3), 3);
+        assert_eq!(div_round_half_even(7, 3), 2);
+    }
+
+    #[test]
+    fn image_area_inside_the_image_is_unchanged() {
+        let src = Rect::new(1.0, 1.0, 3.0, 3.0);
+        let dst = Rect::new(0.0, 0.0, 20.0, 20.0);
+        assert_eq!(
+            clamp_image_area(src, dst, Size::new(4.0, 4.0)),
+            Some((src, dst))
+        );
+    }
+
+    #[test]
+    fn image_area_is_clamped_to_the_image() {
+        let src = Rect::new(-2.0, 2.0, 6.0, 6.0);
+        let dst = Rect::new(0.0, 0.0, 80.0, 40.0);
+        assert_eq!(
+            clamp_image_area(src, dst, Size::new(4.0, 4.0)),
+            Some((
+                Rect::new(0.0, 2.0, 4.0, 4.0),
+                Rect::new(20.0, 0.0, 60.0, 20.0)
+            ))
+        );
+    }
+
+    #[test]
+    fn image_area_outside_the_image_is_reported() {
+        with_context(|rc| {
+            let image = rc
+                .make_image(2, 2, &[255; 16], ImageFormat::RgbaSeparate)
+                .unwrap();
+            rc.draw_image_area(
+                &image,
+                Rect::new(5.0, 5.0, 8.0, 8.0),
+                Rect::new(0.0, 0.0, 10.0, 10.0),
+                InterpolationMode::Bilinear,
+            );
+            assert!(matches!(rc.status(), Err(Error::InvalidInput)));
+        });
+    }
+
+    #[test]
+    fn repeated_brushes_set_the_fill_style_once() {
+        with_context(|rc| {
+            let brush = rc.solid_brush(Color::BLACK);
+            for i in 0..10 {
+                rc.fill(Rect::new(i as f64, 0.0, i as f64 + 1.0, 1.0), &brush);
+                assert!(rc.fill_brush == Some(brush.clone()));
+            }
+            rc.fill(Rect::new(0.0, 0.0, 1.0, 1.0), &Color::WHITE);
+            assert!(rc.fill_brush == Some(rc.solid_brush(Color::WHITE)));
+            let image = rc
+                .make_image(1, 1, &[0, 0, 0, 255], ImageFormat::RgbaSeparate)
+                .unwrap();
+            rc.draw_image_tiled(
+                &image,
+                Rect::new(0.0, 0.0, 1.0, 1.0),
+                Rect::new(0.0, 0.0, 4.0, 4.0),
+                TileMode::Repeat,
+                TileMode::Repeat,
+                InterpolationMode::NearestNeighbor,
+            );
+            assert!(rc.fill_brush.is_none());
+
+            rc.fill(Rect::new(0.0, 0.0, 1.0, 1.0), &brush);
+            rc.save().unwrap();
+            rc.restore().unwrap();
+            assert!(rc.fill_brush.is_none());
+            rc.fill(Rect::new(0.0, 0.0, 1.0, 1.0), &brush);
+            assert!(rc.fill_brush == Some(brush));
+        });
+    }
+
+    #[test]
+    fn solid_colors_keep_their_channel_order() {
+        let color = Color::rgba8(0x12, 0x34, 0x56, 0x78);
+        assert_eq!(
+            ColorU::from_u32(color.as_rgba_u32()),
+            ColorU::new(0x12, 0x34, 0x56, 0x78)
+        );
+    }
+
+    #[test]
+    fn decoded_images_are_converted_to_rgba() {
+        let gray = image::GrayImage::from_fn(3, 2, |x, y| image::Luma([(x * 50 + y * 100) as u8]));
+        let rgb = image::RgbImage::from_fn(3, 2, |x, _| image::Rgb([x as u8 * 100, 20, 30]));
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgb8(rgb)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        with_context(|rc| {
+            let image = rc.make_image_from_dynamic(&decoded).unwrap();
+            assert_eq!(piet::Image::size(&image), Size::new(3.0, 2.0));
+            assert_eq!(image.raw().get_pixel(2, 1).0, [200, 20, 30, 255]);
+
+            let image = rc
+                .make_image_from_dynamic(&image::DynamicImage::ImageLuma8(gray))
+                .unwrap();
+            assert_eq!(image.raw().get_pixel(1, 1).0, [150, 150, 150, 255]);
+        });
+    }
+
+    #[test]
+    fn gradient_stops_are_sorted_and_clamped() {
+        let stop = |pos, color| piet::GradientStop { pos, color };
+        let stops = sorted_stops(vec![
+            stop(1.5, Color::WHITE),
+            stop(0.5, Color::BLACK),
+            stop(-1.0, Color::WHITE),
+            stop(0.5, Color::WHITE),
+        ])
+        .unwrap();
+        let positions: Vec<f32> = stops.iter().map(|stop| stop.pos).collect();
+        assert_eq!(positions, vec![0.0, 0.5, 0.5, 1.0]);
+        // stops at the same position keep their order.
+        assert_eq!(stops[1].color, Color::BLACK);
+        assert_eq!(stops[2].color, Color::WHITE);
+
+        assert!(matches!(
+            sorted_stops(vec![stop(f32::NAN, Color::BLACK)]),
+            Err(Error::InvalidInput)
+        ));
+    }
+
+    #[test]
+    fn sweep_gradients_with_nan_stops_are_reported() {
+        let stop = |pos, color| piet::GradientStop { pos, color };
+        let sweep = SweepGradient::new(
+            (50.0, 50.0),
+            0.0,
+            vec![stop(0.0, Color::BLACK), stop(f32::NAN, Color::WHITE)],
+        );
+        with_context(|rc| {
+            rc.fill(Rect::new(0.0, 0.0, 100.0, 100.0), &Brush::Sweep(sweep));
+            assert!(matches!(rc.status(), Err(Error::InvalidInput)));
+        });
+    }
+
+    #[test]
+    fn sweep_gradients_over_huge_shapes_are_rasterized_on_the_canvas_only() {
+        let stop = |pos, color| piet::GradientStop { pos, color };
+        let sweep = SweepGradient::new(
+            (50.0, 50.0),
+            0.0,
+            vec![stop(0.0, Color::BLACK), stop(1.0, Color::WHITE)],
+        );
+        with_context(|rc| {
+            // a raster of the whole rect wouldn't fit in memory.
+            rc.fill(
+                Rect::new(-1e7, -1e7, 1e7, 1e7),
+                &Brush::Sweep(sweep.clone()),
+            );
+            rc.transform(Affine::scale(1e-6));
+            rc.fill(Rect::new(-1e12, -1e12, 1e12, 1e12), &Brush::Sweep(sweep));
+            assert!(rc.status().is_ok());
+        });
+    }
+
+    #[test]
+    fn empty_dash_pattern_is_solid() {
+        assert!(line_dash_from_pattern(&[]).is_empty());
+    }
+
+    #[test]
+    fn invalid_dash_patterns_are_solid() {
+        assert!(line_dash_from_pattern(&[4.0, -2.0]).is_empty());
+        assert!(line_dash_from_pattern(&[0.0, 0.0]).is_empty());
+        assert!(line_dash_from_pattern(&[0.0]).is_empty());
+        assert!(line_dash_from_pattern(&[4.0, f64::NAN]).is_empty());
+        for &cap in &[piet::LineCap::Butt, piet::LineCap::Round] {
+            let dash = line_dash_from_pattern(&[0.0, 0.0]);
+            assert!(lengthen_capped_dashes(dash, cap).is_empty());
         }
     }
+
+    #[test]
+    fn even_dash_pattern_is_unchanged() {
+        assert_eq!(line_dash_from_pattern(&[4.0, 2.0]), vec![4.0, 2.0]);
+    }
+
+    #[test]
+    fn odd_dash_pattern_is_repeated() {
+        assert_eq!(
+            line_dash_from_pattern(&[5.0, 2.0, 1.0]),
+            vec![5.0, 2.0, 1.0, 5.0, 2.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn empty_dashes_are_lengthened_only_with_caps() {
+        let dash = vec![0.0, 4.0, 3.0, 1.0];
+        assert_eq!(
+            lengthen_capped_dashes(dash.clone(), piet::LineCap::Butt),
+            dash
+        );
+        let lengthened = lengthen_capped_dashes(dash, piet::LineCap::Round);
+        assert!(lengthened[0] > 0.0);
+        assert!((lengthened[0] + lengthened[1] - 4.0).abs() < 1e-6);
+        assert_eq!(lengthened[2..], [3.0, 1.0]);
+    }
 }
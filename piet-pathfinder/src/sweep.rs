@@ -0,0 +1,139 @@
+//! Sweep (conic) gradients, which pathfinder can't paint natively.
+
+use std::f64::consts::PI;
+
+use pathfinder_canvas::Transform2F;
+use pathfinder_content::pattern::Pattern;
+use pathfinder_geometry::vector::vec2f;
+use piet::kurbo::{Point, Rect};
+use piet::{Color, Error, GradientStop, GradientStops};
+
+use crate::{sorted_stops, vec2f_from_point};
+
+/// About the most pixels a sweep gradient is rasterized into along either
+/// axis.
+const MAX_RASTER_SIZE: f64 = 8192.0;
+
+/// A gradient whose colors sweep around a center point, like a color wheel.
+///
+/// Angles are in radians and, since y points down, go clockwise from the
+/// positive x axis. A stop at `0.0` is painted at `start_angle` and one at
+/// `1.0` a full turn later.
+///
+/// Stops are sorted and clamped like those of linear gradients when drawn, and
+/// a NaN position is reported as [`Error::InvalidInput`].
+///
+/// Pathfinder has no conic gradients, so this is rasterized into an image
+/// covering the part of the painted shape on the canvas, at the resolution it
+/// is drawn at.
+#[derive(Clone, Debug)]
+pub struct SweepGradient {
+    pub center: Point,
+    pub start_angle: f64,
+    pub stops: Vec<GradientStop>,
+}
+
+impl PartialEq for SweepGradient {
+    fn eq(&self, other: &SweepGradient) -> bool {
+        // piet's gradient stops can't be compared themselves.
+        let stops_eq = self.stops.len() == other.stops.len()
+            && self
+                .stops
+                .iter()
+                .zip(&other.stops)
+                .all(|(a, b)| a.pos == b.pos && a.color == b.color);
+        self.center == other.center && self.start_angle == other.start_angle && stops_eq
+    }
+}
+
+impl SweepGradient {
+    pub fn new(center: impl Into<Point>, start_angle: f64, stops: impl GradientStops) -> Self {
+        SweepGradient {
+            center: center.into(),
+            start_angle,
+            stops: stops.to_vec(),
+        }
+    }
+
+    /// Rasterizes the part of the gradient inside `rect` into a pattern, with
+    /// `scale` pixels per unit, or fewer if that would make it more than
+    /// [`MAX_RASTER_SIZE`] pixels wide or high.
+    fn to_pattern(&self, rect: Rect, scale: f64) -> Result<Pattern, Error> {
+        let stops = sorted_stops(self.stops.clone())?;
+        // under a degenerate transform nothing is painted anyway.
+        let scale = if scale > 0.0 { scale } else { 1.0 };
+        let rect = rect.expand();
+        // a huge rect gets fewer pixels per unit instead of a huge raster, and
+        // its size never overflows the cast.
+        let scale_x = scale.min(MAX_RASTER_SIZE / rect.width());
+        let scale_y = scale.min(MAX_RASTER_SIZE / rect.height());
+        let width = (rect.width() * scale_x).ceil().max(1.0);
+        let height = (rect.height() * scale_y).ceil().max(1.0);
+        let buffer = image::RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+            let dx = rect.x0 + (x as f64 + 0.5) / scale_x - self.center.x;
+            let dy = rect.y0 + (y as f64 + 0.5) / scale_y - self.center.y;
+            let turns = ((dy.atan2(dx) - self.start_angle) / (2.0 * PI)).rem_euclid(1.0);
+            let (r, g, b, a) = color_at(&stops, turns as f32).as_rgba8();
+            image::Rgba([r, g, b, a])
+        });
+        let mut pattern = Pattern::from_image(
+            pathfinder_content::pattern::Image::from_image_buffer(buffer),
+        );
+        pattern.apply_transform(
+            Transform2F::from_translation(vec2f_from_point(rect.origin()))
+                * Transform2F::from_scale(vec2f((1.0 / scale_x) as f32, (1.0 / scale_y) as f32)),
+        );
+        Ok(pattern)
+    }
+}
+
+/// The pattern a sweep gradient was last rasterized into, so that drawing
+/// the same gradient over the same area again doesn't rasterize it again.
+#[derive(Default)]
+pub(crate) struct SweepCache {
+    last: Option<(SweepGradient, Rect, f64, Pattern)>,
+}
+
+impl SweepCache {
+    /// Returns the pattern of `sweep` inside `rect`, rasterized with `scale`
+    /// device pixels per unit.
+    pub(crate) fn pattern(
+        &mut self,
+        sweep: &SweepGradient,
+        rect: Rect,
+        scale: f64,
+    ) -> Result<Pattern, Error> {
+        if let Some((last, last_rect, last_scale, pattern)) = &self.last {
+            if last == sweep && *last_rect == rect && *last_scale == scale {
+                return Ok(pattern.clone());
+            }
+        }
+        let pattern = sweep.to_pattern(rect, scale)?;
+        self.last = Some((sweep.clone(), rect, scale, pattern.clone()));
+        Ok(pattern)
+    }
+}
+
+/// The color of `stops` at `pos`, which are assumed to be sorted.
+fn color_at(stops: &[GradientStop], pos: f32) -> Color {
+    let next = match stops.iter().position(|stop| stop.pos > pos) {
+        Some(0) => return stops[0].color.clone(),
+        Some(next) => next,
+        None => {
+            return stops
+                .last()
+                .map(|stop| stop.color.clone())
+                .unwrap_or(Color::TRANSPARENT)
+        }
+    };
+    let (before, after) = (&stops[next - 1], &stops[next]);
+    let t = ((pos - before.pos) / (after.pos - before.pos)) as f64;
+    let (r0, g0, b0, a0) = before.color.as_rgba();
+    let (r1, g1, b1, a1) = after.color.as_rgba();
+    Color::rgba(
+        r0 + (r1 - r0) * t,
+        g0 + (g1 - g0) * t,
+        b0 + (b1 - b0) * t,
+        a0 + (a1 - a0) * t,
+    )
+}
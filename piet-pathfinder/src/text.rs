@@ -0,0 +1,2538 @@
+//! Text functionality for the Piet pathfinder backend
+
+mod lines;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{Range, RangeBounds};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use font_kit::canvas::{Format, RasterizationOptions};
+use font_kit::error::FontLoadingError;
+use font_kit::family_name::FamilyName;
+use font_kit::file_type::FileType;
+use font_kit::hinting::HintingOptions;
+use font_kit::properties::{Properties, Style, Weight};
+use font_kit::source::Source;
+use pathfinder_geometry::rect::RectI;
+use pathfinder_geometry::transform2d::Transform2F;
+use pathfinder_geometry::vector::{vec2f, Vector2F};
+use skribo::{FontCollection, TextStyle};
+use unicode_bidi::{BidiInfo, Level};
+use unicode_segmentation::UnicodeSegmentation;
+use xi_unicode::LineBreakIterator;
+
+use piet::kurbo::{Point, Rect, Size, Vec2};
+use piet::{
+    util, Color, Error, FontFamily, FontFamilyInner, FontStyle, FontWeight, HitTestPoint,
+    HitTestPosition, LineMetric, TextAlignment, TextAttribute, TextLayout, TextStorage,
+};
+
+use crate::FontSource;
+
+#[derive(Clone)]
+pub struct Text {
+    font_source: Arc<FontSource>,
+    fonts: Rc<RefCell<FontCache>>,
+    /// The family and size that new layouts start with.
+    default_font: (FontFamily, f64),
+}
+
+/// The fonts resolved by a [`Text`] and the layouts it builds, so that each
+/// family and style is only looked up once.
+#[derive(Default)]
+struct FontCache {
+    /// The [`FontSource::generation`] the fonts were resolved in.
+    generation: usize,
+    fonts: HashMap<(FontFamily, FontWeight, FontStyle), ResolvedFont>,
+    /// The fallback fonts that were loaded, whether or not they covered the
+    /// characters they were loaded for, or `None` for the families that
+    /// couldn't be loaded at all.
+    fallback_fonts: HashMap<(FontFamily, FontWeight, FontStyle), Option<font_kit::font::Font>>,
+    /// The fallback family chosen for each character that a requested font
+    /// lacked, or `None` if none of them has it.
+    fallback_families: HashMap<(char, FontWeight, FontStyle), Option<FontFamily>>,
+    /// The families tried for missing characters, in order, once needed.
+    fallback_chain: Option<Vec<FontFamily>>,
+    /// Set when a family couldn't be found and sans-serif was used in its
+    /// place, until the next [`Text::take_missing_font`].
+    missing_font: bool,
+}
+
+impl FontCache {
+    /// Resolves the font for `defaults`, for text that will be shaped with it.
+    fn resolve(
+        &mut self,
+        font_source: &FontSource,
+        defaults: &util::LayoutDefaults,
+        text: &str,
+    ) -> Result<ResolvedFont, Error> {
+        // loading or removing fonts may change what a family resolves to.
+        if self.generation != font_source.generation() {
+            self.fonts.clear();
+            self.fallback_fonts.clear();
+            self.fallback_families.clear();
+            self.fallback_chain = None;
+            self.generation = font_source.generation();
+        }
+        let key = (defaults.font.clone(), defaults.weight, defaults.style);
+        let mut font = match self.fonts.get(&key) {
+            Some(font) => font.clone(),
+            None => {
+                let family_name = family_name(&defaults.font);
+                let font = match ResolvedFont::new(font_source, family_name, defaults) {
+                    // a missing family shouldn't leave the text without a
+                    // font, so it falls back to the one that's always there.
+                    Err(Error::MissingFont) if !defaults.font.is_generic() => {
+                        self.missing_font = true;
+                        ResolvedFont::new(font_source, FamilyName::SansSerif, defaults)?
+                    }
+                    font => font?,
+                };
+                self.fonts.insert(key, font.clone());
+                font
+            }
+        };
+        font.size = defaults.font_size;
+        let fallbacks = self.fallbacks(font_source, &font, defaults, text);
+        Ok(font.with_fallbacks(fallbacks))
+    }
+
+    /// Finds fonts that cover the characters of `text` missing from `font`,
+    /// trying each family of the [`fallback_chain`] in turn.
+    ///
+    /// The family chosen for each character is remembered, so fonts are only
+    /// searched for characters that haven't been seen before, and each
+    /// family is only looked up and loaded once.
+    ///
+    /// [`fallback_chain`]: FontCache::fallback_chain
+    fn fallbacks(
+        &mut self,
+        font_source: &FontSource,
+        font: &ResolvedFont,
+        defaults: &util::LayoutDefaults,
+        text: &str,
+    ) -> Vec<font_kit::font::Font> {
+        let (weight, style) = (defaults.weight, defaults.style);
+        let mut families: Vec<FontFamily> = Vec::new();
+        let mut missing: Vec<char> = Vec::new();
+        for c in text
+            .chars()
+            .filter(|&c| !c.is_control() && font.font.glyph_for_char(c).is_none())
+        {
+            match self.fallback_families.get(&(c, weight, style)) {
+                Some(Some(family)) if !families.contains(family) => families.push(family.clone()),
+                Some(_) => {}
+                None if !missing.contains(&c) => missing.push(c),
+                None => {}
+            }
+        }
+
+        if missing.is_empty() {
+            return self.loaded_fallbacks(families, weight, style);
+        }
+        for family in self.fallback_chain(font_source) {
+            if missing.is_empty() {
+                break;
+            }
+            let key = (family.clone(), weight, style);
+            let fallback = self
+                .fallback_fonts
+                .entry(key)
+                .or_insert_with(|| {
+                    font_source
+                        .select_best_match(&[family_name(&family)], &font.properties)
+                        .ok()
+                        .and_then(|handle| handle.load().ok())
+                })
+                .clone();
+            let fallback = match fallback {
+                Some(fallback) => fallback,
+                None => continue,
+            };
+            let count = missing.len();
+            for &c in missing
+                .iter()
+                .filter(|&&c| fallback.glyph_for_char(c).is_some())
+            {
+                self.fallback_families
+                    .insert((c, weight, style), Some(family.clone()));
+            }
+            missing.retain(|&c| fallback.glyph_for_char(c).is_none());
+            if missing.len() < count && !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        for c in missing {
+            self.fallback_families.insert((c, weight, style), None);
+        }
+        self.loaded_fallbacks(families, weight, style)
+    }
+
+    /// The already loaded fallback fonts of `families`.
+    fn loaded_fallbacks(
+        &self,
+        families: Vec<FontFamily>,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Vec<font_kit::font::Font> {
+        families
+            .into_iter()
+            .filter_map(|family| {
+                self.fallback_fonts
+                    .get(&(family, weight, style))
+                    .cloned()
+                    .flatten()
+            })
+            .collect()
+    }
+
+    /// The families tried for characters that the requested font lacks.
+    ///
+    /// These are the [`FALLBACK_FAMILIES`], which are known to cover a lot,
+    /// then the generic families, and then every other family of the
+    /// `FontSource`, so that any character some font has is found.
+    fn fallback_chain(&mut self, font_source: &FontSource) -> Vec<FontFamily> {
+        self.fallback_chain
+            .get_or_insert_with(|| {
+                let mut chain: Vec<FontFamily> = FALLBACK_FAMILIES
+                    .iter()
+                    .map(|family| FontFamily::new_unchecked(*family))
+                    .chain(vec![
+                        FontFamily::SANS_SERIF,
+                        FontFamily::SERIF,
+                        FontFamily::MONOSPACE,
+                    ])
+                    .collect();
+                for name in font_source.family_names().unwrap_or_default() {
+                    let family = FontFamily::new_unchecked(name);
+                    if !chain.contains(&family) {
+                        chain.push(family);
+                    }
+                }
+                chain
+            })
+            .clone()
+    }
+}
+
+pub struct TextLayoutBuilder {
+    font_source: Arc<FontSource>,
+    fonts: Rc<RefCell<FontCache>>,
+    text: Rc<dyn TextStorage>,
+    width: f64,
+    alignment: TextAlignment,
+    options: ShapingOptions,
+    wrap: WrapMode,
+    first_line_indent: f64,
+    line_height: f64,
+    max_lines: Option<usize>,
+    baseline_shifts: Vec<(Range<usize>, f64)>,
+    defaults: util::LayoutDefaults,
+}
+
+#[derive(Clone)]
+pub struct PathfinderTextLayout {
+    font: ResolvedFont,
+    text: Rc<dyn TextStorage>,
+    color: Color,
+
+    width: f64,
+    alignment: TextAlignment,
+    options: ShapingOptions,
+    wrap: WrapMode,
+    first_line_indent: f64,
+    line_height: f64,
+    max_lines: Option<usize>,
+    /// Ranges of text raised by a number of ems, with later ones taking
+    /// precedence.
+    baseline_shifts: Vec<(Range<usize>, f64)>,
+
+    // Calculated on build
+    lines: Vec<Line>,
+    size: Size,
+    trailing_ws_width: f64,
+    /// The union of the glyphs' raster bounds, where they are drawn.
+    image_bounds: Rect,
+}
+
+/// The vertical metrics of a font at a given size, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FontMetrics {
+    /// The distance from the baseline to the top of the tallest glyphs.
+    pub ascent: f64,
+    /// The distance from the baseline to the bottom of the lowest glyphs, as
+    /// a positive number.
+    pub descent: f64,
+    /// The extra space the font asks for between lines.
+    pub line_gap: f64,
+    /// The height of capital letters above the baseline.
+    pub cap_height: f64,
+    /// The height of lowercase letters such as "x" above the baseline.
+    pub x_height: f64,
+}
+
+/// A place where a line may be broken, found by
+/// [`Text::line_break_opportunities`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineBreakOpportunity {
+    /// The byte offset the next line would start at.
+    pub offset: usize,
+    /// The advance width of the text since the previous opportunity,
+    /// including any trailing whitespace.
+    pub width: f64,
+    /// Whether the line must be broken here, as it must at a newline and at
+    /// the end of the text.
+    pub is_hard: bool,
+}
+
+/// Where a layout's lines may be broken when wrapping them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WrapMode {
+    /// Lines are only broken between words, so a word wider than the max
+    /// width overflows its line. This is the default.
+    Word,
+    /// Like `Word`, but a word wider than the max width is broken between
+    /// grapheme clusters, across as many lines as it needs.
+    Character,
+}
+
+/// A run of text with a single direction, placed within its line.
+struct VisualRun {
+    range: Range<usize>,
+    rtl: bool,
+    x: f64,
+    width: f64,
+    /// The offset of each grapheme boundary in the run from its logical start.
+    carets: Vec<(usize, f64)>,
+}
+
+/// A single visual line of a layout.
+#[derive(Clone)]
+struct Line {
+    metric: LineMetric,
+    /// The width of the line, excluding trailing whitespace.
+    width: f64,
+    /// The offset of the start of the line from the left edge of the layout.
+    x_offset: f64,
+    /// The text offset and x position of each cursor stop in the line.
+    carets: Vec<(usize, f64)>,
+    /// The shaped glyphs of the line's visible text.
+    glyphs: Rc<skribo::Layout>,
+    /// The glyphs from `glyphs` that come from color fonts, and so are drawn
+    /// as images instead of being filled.
+    color_glyphs: Rc<Vec<skribo::Glyph>>,
+}
+
+/// Options that affect how text is shaped and measured.
+#[derive(Clone, Copy, Debug)]
+struct ShapingOptions {
+    /// Extra space added after each glyph.
+    tracking: f64,
+    /// The distance between tab stops, if not the default.
+    tab_width: Option<f64>,
+    /// Whether the shaper may join characters into ligatures.
+    ligatures: bool,
+    /// Whether glyphs are positioned with the font's kerning.
+    kerning: bool,
+}
+
+impl Default for ShapingOptions {
+    fn default() -> Self {
+        ShapingOptions {
+            tracking: 0.0,
+            tab_width: None,
+            ligatures: true,
+            kerning: true,
+        }
+    }
+}
+
+/// A font that has been resolved through the [`FontSource`], at a given size.
+#[derive(Clone)]
+struct ResolvedFont {
+    collection: Arc<FontCollection>,
+    font: font_kit::font::Font,
+    properties: Properties,
+    size: f64,
+    /// The PostScript names of the fonts in `collection` that have color glyphs.
+    color_fonts: Vec<String>,
+    /// The fonts after `font` in `collection`, in order.
+    fallbacks: Vec<font_kit::font::Font>,
+}
+
+impl Text {
+    pub(crate) fn new(font_source: Arc<FontSource>) -> Self {
+        Text {
+            font_source,
+            fonts: Default::default(),
+            default_font: (FontFamily::default(), util::DEFAULT_FONT_SIZE),
+        }
+    }
+
+    /// Sets the font family and size of the layouts built after this, where
+    /// they don't set their own.
+    ///
+    /// A `size` of zero or less uses the default size instead, as for
+    /// [`TextAttribute::FontSize`].
+    pub fn set_default_font(&mut self, family: FontFamily, size: f64) {
+        self.default_font = (family, size);
+    }
+
+    /// Returns whether a family called `name` is installed or loaded, without
+    /// resolving any of its fonts.
+    pub fn has_family(&self, name: &str) -> bool {
+        match generic_family(name) {
+            Some((_, generic)) => self.font_source.select_family_by_generic_name(&generic),
+            None => self.font_source.select_family_by_name(name),
+        }
+        .is_ok()
+    }
+
+    /// Returns whether a layout, or a measurement, asked for a family that
+    /// couldn't be found, since this was last called.
+    ///
+    /// Such text is laid out with the system's sans-serif font instead, so
+    /// this is only a warning, which isn't reported by `status` or `finish`.
+    /// Each missing family is only reported once, until fonts are loaded or
+    /// removed.
+    pub fn take_missing_font(&self) -> bool {
+        std::mem::replace(&mut self.fonts.borrow_mut().missing_font, false)
+    }
+
+    /// Unloads every font loaded with [`load_font`] and the other loading
+    /// methods, such as between documents. Installed fonts stay available.
+    ///
+    /// See [`FontSource::clear_fonts`].
+    ///
+    /// [`load_font`]: piet::Text::load_font
+    pub fn clear_loaded_fonts(&mut self) {
+        self.font_source.clear_fonts();
+    }
+
+    /// Loads the face at `font_index` of a font collection (such as a `.ttc`
+    /// file), like [`load_font`] does for the first one.
+    ///
+    /// Returns [`Error::MissingFont`] if the collection has no such face.
+    ///
+    /// [`load_font`]: piet::Text::load_font
+    pub fn load_font_at(&mut self, data: &[u8], font_index: u32) -> Result<FontFamily, Error> {
+        let data = Arc::new(data.to_owned());
+        if font_index >= face_count(&data)? {
+            return Err(Error::MissingFont);
+        }
+        self.add_font(data, font_index)
+    }
+
+    /// Loads every face of a font collection, returning their families in the
+    /// order they appear. A file with a single font yields a single family.
+    pub fn load_font_collection(&mut self, data: &[u8]) -> Result<Vec<FontFamily>, Error> {
+        let data = Arc::new(data.to_owned());
+        (0..face_count(&data)?)
+            .map(|font_index| self.add_font(data.clone(), font_index))
+            .collect()
+    }
+
+    /// Measures the advance width of `text` as a single line, styled with
+    /// `attributes`.
+    ///
+    /// This shapes the text once, without wrapping it or building a layout, so
+    /// it's cheaper than `new_text_layout` when only the width is needed. Unlike
+    /// a layout's size, the width includes any trailing whitespace.
+    pub fn measure_width(
+        &mut self,
+        text: &str,
+        attributes: &util::LayoutDefaults,
+    ) -> Result<f64, Error> {
+        let font = self
+            .fonts
+            .borrow_mut()
+            .resolve(&self.font_source, attributes, text)?;
+        Ok(font.text_width(text, &ShapingOptions::default()))
+    }
+
+    /// Measures the height of `text` wrapped to `width`, styled with
+    /// `attributes`.
+    ///
+    /// This breaks the text into lines like a layout with that max width
+    /// would, and adds up their heights, but doesn't shape the lines to
+    /// position their glyphs, so it's cheaper than building a layout to
+    /// find out how much space to give it.
+    pub fn measure_height(
+        &mut self,
+        text: &str,
+        width: f64,
+        attributes: &util::LayoutDefaults,
+    ) -> Result<f64, Error> {
+        let font = self
+            .fonts
+            .borrow_mut()
+            .resolve(&self.font_source, attributes, text)?;
+        let options = ShapingOptions::default();
+        let width_of = |s: &str| font.text_width(s, &options);
+        let lines = lines::break_lines(text, width, 0.0, WrapMode::Word, width_of);
+        let FontMetrics {
+            ascent,
+            descent,
+            line_gap,
+            ..
+        } = font.metrics();
+        Ok(lines.len() as f64 * (ascent + descent + line_gap))
+    }
+
+    /// Finds the places where `text` may be broken into lines, following the
+    /// Unicode line breaking algorithm (UAX #14), and measures the text
+    /// between them as if styled with `attributes`.
+    ///
+    /// This is for callers that do their own line breaking and then lay out
+    /// each line on its own. Each piece is measured like [`measure_width`],
+    /// so their widths add up to that of the whole text, except for any
+    /// kerning across a break.
+    ///
+    /// [`measure_width`]: Text::measure_width
+    pub fn line_break_opportunities(
+        &mut self,
+        text: &str,
+        attributes: &util::LayoutDefaults,
+    ) -> Result<Vec<LineBreakOpportunity>, Error> {
+        let font = self
+            .fonts
+            .borrow_mut()
+            .resolve(&self.font_source, attributes, text)?;
+        let options = ShapingOptions::default();
+        let mut start = 0;
+        let opportunities = LineBreakIterator::new(text)
+            .map(|(offset, is_hard)| {
+                let width = font.text_width(&text[start..offset], &options);
+                start = offset;
+                LineBreakOpportunity {
+                    offset,
+                    width,
+                    is_hard,
+                }
+            })
+            .collect();
+        Ok(opportunities)
+    }
+
+    fn add_font(&mut self, data: Arc<Vec<u8>>, font_index: u32) -> Result<FontFamily, Error> {
+        let font_handle = font_kit::handle::Handle::from_memory(data, font_index);
+        let font = self
+            .font_source
+            .add_in_memory_font(font_handle)
+            .map_err(font_loading_error)?;
+        Ok(FontFamily::new_unchecked(font.family_name()))
+    }
+}
+
+/// The piet and font-kit families for a CSS generic family name, such as
+/// `"monospace"`, which isn't the name of any installed family.
+fn generic_family(name: &str) -> Option<(FontFamily, FamilyName)> {
+    match name.to_ascii_lowercase().as_str() {
+        "serif" => Some((FontFamily::SERIF, FamilyName::Serif)),
+        "sans-serif" => Some((FontFamily::SANS_SERIF, FamilyName::SansSerif)),
+        "monospace" => Some((FontFamily::MONOSPACE, FamilyName::Monospace)),
+        // font-kit has no system UI family, so it resolves to sans-serif.
+        "system-ui" => Some((FontFamily::SYSTEM_UI, FamilyName::SansSerif)),
+        _ => None,
+    }
+}
+
+/// The font-kit family to select for a piet family.
+fn family_name(family: &FontFamily) -> FamilyName {
+    match family.inner() {
+        FontFamilyInner::Serif => FamilyName::Serif,
+        FontFamilyInner::Monospace => FamilyName::Monospace,
+        FontFamilyInner::Named(name) => FamilyName::Title(name.to_string()),
+        _ => FamilyName::SansSerif,
+    }
+}
+
+/// The number of faces in font data, which is more than one for collections.
+fn face_count(data: &Arc<Vec<u8>>) -> Result<u32, Error> {
+    match font_kit::font::Font::analyze_bytes(data.clone()).map_err(font_loading_error)? {
+        FileType::Single => Ok(1),
+        FileType::Collection(count) => Ok(count),
+    }
+}
+
+/// Converts a font-kit error, keeping it as the source of a backend error so
+/// that callers can tell a malformed font from one in an unknown format.
+fn font_loading_error(err: FontLoadingError) -> Error {
+    match err {
+        FontLoadingError::NoSuchFontInCollection => Error::MissingFont,
+        _ => Error::BackendError(Box::new(err)),
+    }
+}
+
+impl piet::Text for Text {
+    type TextLayoutBuilder = TextLayoutBuilder;
+    type TextLayout = PathfinderTextLayout;
+
+    fn font_family(&mut self, family_name: &str) -> Option<FontFamily> {
+        if let Some((family, generic)) = generic_family(family_name) {
+            let resolved = self.font_source.select_family_by_generic_name(&generic);
+            return resolved.ok().map(|_family| family);
+        }
+        let family = self.font_source.select_family_by_name(family_name);
+        family
+            .ok()
+            .map(|_family| FontFamily::new_unchecked(family_name))
+    }
+
+    fn load_font(&mut self, data: &[u8]) -> Result<FontFamily, Error> {
+        self.load_font_at(data, 0)
+    }
+
+    fn new_text_layout(&mut self, text: impl TextStorage) -> Self::TextLayoutBuilder {
+        let (family, size) = self.default_font.clone();
+        let mut defaults = util::LayoutDefaults::default();
+        defaults.set(family);
+        defaults.set(TextAttribute::FontSize(size));
+        TextLayoutBuilder {
+            font_source: self.font_source.clone(),
+            fonts: self.fonts.clone(),
+            text: Rc::new(text),
+            width: f64::INFINITY,
+            alignment: TextAlignment::Start,
+            options: Default::default(),
+            wrap: WrapMode::Word,
+            first_line_indent: 0.0,
+            line_height: 1.0,
+            max_lines: None,
+            baseline_shifts: Vec::new(),
+            defaults,
+        }
+    }
+}
+
+impl TextLayoutBuilder {
+    /// Adds `tracking` of extra space after each glyph, as in CSS `letter-spacing`.
+    ///
+    /// This is an extension specific to the pathfinder backend. The extra space
+    /// is included when measuring, hit testing and drawing the layout.
+    pub fn tracking(mut self, tracking: f64) -> Self {
+        self.options.tracking = tracking;
+        self
+    }
+
+    /// Sets the distance between the tab stops that tabs advance to.
+    ///
+    /// This is an extension specific to the pathfinder backend. Tab stops are
+    /// measured from the start of each line, and default to the width of eight
+    /// spaces.
+    pub fn tab_width(mut self, tab_width: f64) -> Self {
+        self.options.tab_width = Some(tab_width);
+        self
+    }
+
+    /// Sets whether characters such as "fi" may be joined into ligatures,
+    /// which they are by default.
+    ///
+    /// This is an extension specific to the pathfinder backend. Without
+    /// ligatures, each grapheme cluster is shaped on its own, so kerning
+    /// between clusters is lost too.
+    pub fn ligatures(mut self, ligatures: bool) -> Self {
+        self.options.ligatures = ligatures;
+        self
+    }
+
+    /// Sets whether glyphs are positioned with the font's kerning, which they
+    /// are by default.
+    ///
+    /// This is an extension specific to the pathfinder backend. Without
+    /// kerning, each glyph is placed by its own advance, as is often wanted
+    /// for tabular data.
+    pub fn kerning(mut self, kerning: bool) -> Self {
+        self.options.kerning = kerning;
+        self
+    }
+
+    /// Sets how lines are wrapped at the max width; see [`WrapMode`].
+    ///
+    /// This is an extension specific to the pathfinder backend.
+    pub fn wrap_mode(mut self, wrap: WrapMode) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Indents the first line of the layout by `indent`, which also makes it
+    /// that much narrower when wrapping.
+    ///
+    /// This is an extension specific to the pathfinder backend. The indent is
+    /// on the right of right-to-left text. Lines after a hard break aren't
+    /// indented.
+    pub fn first_line_indent(mut self, indent: f64) -> Self {
+        self.first_line_indent = indent;
+        self
+    }
+
+    /// Scales the height of each line by `line_height`, so that `2.0` gives
+    /// double spacing.
+    ///
+    /// This is an extension specific to the pathfinder backend. As in CSS, the
+    /// extra space is split evenly above and below the text of each line. The
+    /// default is `1.0`, the font's own line height.
+    pub fn line_height(mut self, line_height: f64) -> Self {
+        self.line_height = line_height;
+        self
+    }
+
+    /// Raises the text in `range` by `shift` ems above the baseline, or lowers
+    /// it for a negative `shift`, as for superscripts and subscripts.
+    ///
+    /// This is an extension specific to the pathfinder backend. The line's
+    /// metrics are unchanged, so a large shift may draw outside of it. Where
+    /// ranges overlap, the one set last applies.
+    pub fn baseline_shift(mut self, range: impl RangeBounds<usize>, shift: f64) -> Self {
+        let range = util::resolve_range(range, self.text.len());
+        self.baseline_shifts.push((range, shift));
+        self
+    }
+
+    /// Limits the layout to its first `max_lines` lines, ending the last one
+    /// with an ellipsis that fits within the max width.
+    ///
+    /// This is an extension specific to the pathfinder backend. When lines are
+    /// dropped, the layout's text is the truncated text, including the
+    /// ellipsis, so positions past the truncation refer to it rather than to
+    /// the original text. At least one line is always kept.
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = Some(max_lines);
+        self
+    }
+}
+
+impl piet::TextLayoutBuilder for TextLayoutBuilder {
+    type Out = PathfinderTextLayout;
+
+    fn max_width(mut self, width: f64) -> Self {
+        self.width = width;
+        self
+    }
+
+    fn alignment(mut self, alignment: TextAlignment) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    fn default_attribute(mut self, attribute: impl Into<TextAttribute>) -> Self {
+        self.defaults.set(attribute);
+        self
+    }
+
+    fn range_attribute(
+        self,
+        _range: impl RangeBounds<usize>,
+        _attribute: impl Into<TextAttribute>,
+    ) -> Self {
+        self
+    }
+
+    fn build(self) -> Result<Self::Out, Error> {
+        let font =
+            self.fonts
+                .borrow_mut()
+                .resolve(&self.font_source, &self.defaults, &self.text)?;
+        let mut layout = PathfinderTextLayout {
+            font,
+            text: self.text,
+            color: self.defaults.fg_color,
+            width: self.width,
+            alignment: self.alignment,
+            options: self.options,
+            wrap: self.wrap,
+            first_line_indent: self.first_line_indent,
+            line_height: self.line_height,
+            max_lines: self.max_lines,
+            baseline_shifts: self.baseline_shifts,
+            lines: Vec::new(),
+            size: Size::ZERO,
+            trailing_ws_width: 0.0,
+            image_bounds: Rect::ZERO,
+        };
+        layout.update_lines();
+        Ok(layout)
+    }
+}
+
+impl TextLayout for PathfinderTextLayout {
+    fn size(&self) -> Size {
+        self.size
+    }
+
+    fn trailing_whitespace_width(&self) -> f64 {
+        self.trailing_ws_width
+    }
+
+    fn image_bounds(&self) -> Rect {
+        self.image_bounds
+    }
+
+    fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn line_text(&self, line_number: usize) -> Option<&str> {
+        self.lines
+            .get(line_number)
+            .map(|line| &self.text[line.metric.range()])
+    }
+
+    fn line_metric(&self, line_number: usize) -> Option<LineMetric> {
+        self.lines.get(line_number).map(|line| line.metric.clone())
+    }
+
+    fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn hit_test_point(&self, point: Point) -> HitTestPoint {
+        let line_number = self
+            .lines
+            .iter()
+            .position(|line| point.y < line.metric.y_offset + line.metric.height)
+            .unwrap_or(self.lines.len() - 1);
+        let line = &self.lines[line_number];
+        let is_last_line = line_number + 1 == self.lines.len();
+
+        let x = point.x - line.x_offset;
+        let idx = line
+            .carets
+            .iter()
+            // the end of a wrapped line is also the start of the next one,
+            // where `hit_test_text_position` places it, so it's left out to
+            // keep the caret on the line that was hit.
+            .filter(|(idx, _)| is_last_line || *idx < line.metric.end_offset)
+            .min_by(|(_, a), (_, b)| (a - x).abs().partial_cmp(&(b - x).abs()).unwrap())
+            .map(|(idx, _)| *idx)
+            .unwrap_or(line.metric.start_offset);
+        // points above the first line or below the last are clamped to them,
+        // but aren't inside the text.
+        let last = &self.lines[self.lines.len() - 1].metric;
+        let is_inside_y = point.y >= 0.0 && point.y < last.y_offset + last.height;
+        let is_inside = is_inside_y && x >= 0.0 && x <= line.width;
+        HitTestPoint::new(idx, is_inside)
+    }
+
+    fn hit_test_text_position(&self, idx: usize) -> HitTestPosition {
+        let idx = idx.min(self.text.len());
+        let line_number = self.line_number_for_position(idx);
+        let line = &self.lines[line_number];
+        let x = line
+            .carets
+            .iter()
+            .rev()
+            .find(|(caret_idx, _)| *caret_idx <= idx)
+            .map(|(_, x)| *x)
+            .unwrap_or(0.0);
+        let y = line.metric.y_offset + line.metric.baseline
+            - baseline_shift_at(&self.baseline_shifts, idx) * self.font.size;
+        HitTestPosition::new(Point::new(line.x_offset + x, y), line_number)
+    }
+}
+
+impl PathfinderTextLayout {
+    pub(crate) fn color(&self) -> Color {
+        self.color
+    }
+
+    /// Returns the shaped glyphs of each line, along with the position of its
+    /// baseline origin relative to the top-left of the layout.
+    pub(crate) fn glyph_runs(&self) -> impl Iterator<Item = (&skribo::Layout, Point)> {
+        self.lines.iter().map(|line| {
+            let metric = &line.metric;
+            let origin = Point::new(line.x_offset, metric.y_offset + metric.baseline);
+            (&*line.glyphs, origin)
+        })
+    }
+
+    /// Returns the glyphs that come from color fonts, which `glyph_runs`
+    /// leaves out, along with the position of each one's baseline origin.
+    pub(crate) fn color_glyphs(&self) -> impl Iterator<Item = (&skribo::Glyph, Point)> {
+        self.lines.iter().flat_map(|line| {
+            let metric = &line.metric;
+            line.color_glyphs.iter().map(move |glyph| {
+                let origin = Point::new(
+                    line.x_offset + glyph.offset.x() as f64,
+                    metric.y_offset + metric.baseline + glyph.offset.y() as f64,
+                );
+                (glyph, origin)
+            })
+        })
+    }
+
+    pub(crate) fn font_size(&self) -> f64 {
+        self.font.size
+    }
+
+    /// Returns the baseline origin of every glyph, relative to the top-left
+    /// of the layout.
+    ///
+    /// Glyphs come line by line in visual order, except that those from color
+    /// fonts follow all the others. This is the order in which
+    /// [`draw_text_with`] numbers them.
+    ///
+    /// [`draw_text_with`]: crate::PathFinderRenderContext::draw_text_with
+    pub fn glyph_origins(&self) -> Vec<Point> {
+        let filled = self.glyph_runs().flat_map(|(glyphs, origin)| {
+            glyphs.glyphs.iter().map(move |glyph| {
+                origin + Vec2::new(glyph.offset.x() as f64, glyph.offset.y() as f64)
+            })
+        });
+        filled
+            .chain(self.color_glyphs().map(|(_, origin)| origin))
+            .collect()
+    }
+
+    /// Rasterizes the layout into an image covering its [`image_bounds`], with
+    /// `scale` pixels per unit, on a transparent background.
+    ///
+    /// The image can be drawn with `draw_image` into the image bounds, offset
+    /// by where the layout would be drawn, to avoid shaping and filling
+    /// static text every frame. It is rasterized by font-kit, so unlike
+    /// drawing the layout it doesn't need a renderer.
+    ///
+    /// [`image_bounds`]: TextLayout::image_bounds
+    pub fn to_image(&self, scale: f64) -> Result<crate::Image, Error> {
+        let bounds = self.image_bounds();
+        let width = ((bounds.width() * scale).ceil() as usize).max(1);
+        let height = ((bounds.height() * scale).ceil() as usize).max(1);
+        let mut pixels = vec![0; width * height * 4];
+        let point_size = (self.font.size * scale) as f32;
+        let device_origin = |origin: Point| {
+            let origin = (origin - bounds.origin()) * scale;
+            vec2f(origin.x as f32, origin.y as f32)
+        };
+
+        let (r, g, b, a) = self.color.as_rgba8();
+        let fill_color = |coverage: &[u8]| {
+            let alpha = (coverage[0] as u32 * a as u32 + 127) / 255;
+            let premultiply = |c: u8| ((c as u32 * alpha + 127) / 255) as u8;
+            [premultiply(r), premultiply(g), premultiply(b), alpha as u8]
+        };
+        for (glyphs, origin) in self.glyph_runs() {
+            for glyph in &glyphs.glyphs {
+                let origin = device_origin(origin) + glyph.offset * scale as f32;
+                if let Some(raster) = rasterize_glyph(glyph, point_size, origin, Format::A8) {
+                    composite_glyph(&mut pixels, width, raster, fill_color);
+                }
+            }
+        }
+        for (glyph, origin) in self.color_glyphs() {
+            let origin = device_origin(origin);
+            if let Some(raster) = rasterize_glyph(glyph, point_size, origin, Format::Rgba32) {
+                composite_glyph(&mut pixels, width, raster, |color| {
+                    [color[0], color[1], color[2], color[3]]
+                });
+            }
+        }
+        crate::Image::from_premultiplied(width, height, &pixels)
+    }
+
+    /// Returns the rects covering the text in `range`, such as for drawing a
+    /// selection, with one for each line the range is on.
+    ///
+    /// Each rect spans the full height of its line, and the range is clamped
+    /// to the text. Within right-to-left or mixed direction lines, the rect
+    /// covers every part of the line the range touches.
+    pub fn rects_for_range(&self, range: Range<usize>) -> Vec<Rect> {
+        let start = range.start.min(self.text.len());
+        let end = range.end.min(self.text.len());
+        if start >= end {
+            return Vec::new();
+        }
+        self.lines
+            .iter()
+            .filter_map(|line| {
+                let metric = &line.metric;
+                let line_start = start.max(metric.start_offset);
+                let line_end = end.min(metric.end_offset);
+                if line_start >= line_end {
+                    return None;
+                }
+                let (x0, x1) = line
+                    .carets
+                    .iter()
+                    .filter(|(idx, _)| (line_start..=line_end).contains(idx))
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |(x0, x1), (_, x)| {
+                        (x0.min(*x), x1.max(*x))
+                    });
+                if x0 > x1 {
+                    return None;
+                }
+                Some(Rect::new(
+                    line.x_offset + x0,
+                    metric.y_offset,
+                    line.x_offset + x1,
+                    metric.y_offset + metric.height,
+                ))
+            })
+            .collect()
+    }
+
+    /// Returns the metrics of the layout's font, at the layout's font size.
+    ///
+    /// This is the font the layout's style resolved to; characters it doesn't
+    /// cover may be drawn with fallback fonts whose metrics differ.
+    pub fn font_metrics(&self) -> FontMetrics {
+        self.font.metrics()
+    }
+
+    /// Returns the index of the line containing this text position, or the
+    /// last line if the position is out of bounds.
+    fn line_number_for_position(&self, position: usize) -> usize {
+        match self
+            .lines
+            .binary_search_by_key(&position, |line| line.metric.start_offset)
+        {
+            Ok(idx) => idx,
+            Err(idx) => idx.saturating_sub(1),
+        }
+    }
+
+    fn update_lines(&mut self) {
+        let FontMetrics {
+            ascent,
+            descent,
+            line_gap,
+            ..
+        } = self.font.metrics();
+        let natural_height = ascent + descent + line_gap;
+        let height = natural_height * self.line_height;
+        let baseline = ascent + (height - natural_height) / 2.0;
+        let font = &self.font;
+        let options = &self.options;
+        let width_of = |s: &str| font.text_width(s, options);
+
+        let indent = self.first_line_indent;
+        let mut ranges = lines::break_lines(&self.text, self.width, indent, self.wrap, width_of);
+        if let Some(max_lines) = self.max_lines {
+            let last_width = if max_lines <= 1 {
+                self.width - indent
+            } else {
+                self.width
+            };
+            if let Some(clamped) =
+                lines::clamp_lines(&self.text, &mut ranges, max_lines, last_width, width_of)
+            {
+                self.text = Rc::new(clamped);
+            }
+        }
+        let text = &self.text;
+        let shifts = &self.baseline_shifts;
+        let rtl = util::first_strong_rtl(text);
+
+        let mut y_offset = 0.0;
+        let mut lines: Vec<Line> = ranges
+            .into_iter()
+            .map(|range| {
+                let metric = LineMetric {
+                    start_offset: range.start,
+                    end_offset: range.end,
+                    trailing_whitespace: lines::trailing_whitespace(&text[range]),
+                    baseline,
+                    height,
+                    y_offset,
+                };
+                y_offset += height;
+                Line::new(text, metric, font, options, shifts, rtl)
+            })
+            .collect();
+
+        let line_indent = |i: usize| if i == 0 { indent } else { 0.0 };
+        let (width, ws_width) = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let ws_width = line.carets.last().map(|c| c.1).unwrap_or(0.0);
+                (line.width + line_indent(i), ws_width + line_indent(i))
+            })
+            .fold((0.0, 0.0), |a: (f64, f64), b| (a.0.max(b.0), a.1.max(b.1)));
+
+        // Lines are aligned within the max width if there is one, and otherwise
+        // within the widest line.
+        let align_width = if self.width.is_finite() {
+            self.width
+        } else {
+            width
+        };
+        for (i, line) in lines.iter_mut().enumerate() {
+            let free_space = (align_width - line_indent(i) - line.width).max(0.0);
+            // the start of right-to-left text is on the right.
+            line.x_offset = match (self.alignment, rtl) {
+                (TextAlignment::Start, false) | (TextAlignment::End, true) => 0.0,
+                (TextAlignment::Start, true) | (TextAlignment::End, false) => free_space,
+                (TextAlignment::Justified, _) => 0.0,
+                (TextAlignment::Center, _) => free_space / 2.0,
+            };
+            if !rtl {
+                line.x_offset += line_indent(i);
+            }
+        }
+
+        self.size = Size::new(width, y_offset);
+        self.trailing_ws_width = ws_width;
+        self.lines = lines;
+        self.image_bounds = self.glyph_bounds();
+    }
+
+    /// Computes the union of the glyphs' raster bounds at their origins, which
+    /// take alignment and baseline shifts into account.
+    fn glyph_bounds(&self) -> Rect {
+        let point_size = self.font.size as f32;
+        let filled = self.glyph_runs().flat_map(|(glyphs, origin)| {
+            glyphs.glyphs.iter().map(move |glyph| {
+                let offset = Vec2::new(glyph.offset.x() as f64, glyph.offset.y() as f64);
+                (glyph, origin + offset)
+            })
+        });
+        filled
+            .chain(self.color_glyphs())
+            .filter_map(|(glyph, origin)| {
+                let origin = vec2f(origin.x as f32, origin.y as f32);
+                let bounds = glyph_raster_bounds(glyph, point_size, origin)?;
+                Some(Rect::new(
+                    bounds.min_x() as f64,
+                    bounds.min_y() as f64,
+                    bounds.max_x() as f64,
+                    bounds.max_y() as f64,
+                ))
+            })
+            .fold(None, |union: Option<Rect>, rect| {
+                Some(union.map_or(rect, |union| union.union(rect)))
+            })
+            .unwrap_or(Rect::ZERO)
+    }
+}
+
+impl Line {
+    /// Lays out a line of `text`, whose paragraph runs right to left if `rtl`.
+    fn new(
+        text: &str,
+        metric: LineMetric,
+        font: &ResolvedFont,
+        options: &ShapingOptions,
+        shifts: &[(Range<usize>, f64)],
+        rtl: bool,
+    ) -> Self {
+        let start = metric.start_offset;
+        let visible_end = metric.end_offset - metric.trailing_whitespace;
+        // a line break belongs to the start of the next line, not to this one.
+        let caret_end = start
+            + text[metric.range()]
+                .trim_end_matches(|c| c == '\n' || c == '\r')
+                .len();
+
+        let mut glyphs = skribo::Layout {
+            size: font.size as f32,
+            glyphs: Vec::new(),
+        };
+        let mut runs = Vec::new();
+        let mut width = 0.0;
+        for (range, run_rtl) in visual_runs(&text[start..visible_end], rtl) {
+            let range = start + range.start..start + range.end;
+            let mut pieces = shifted_pieces(range, shifts);
+            if run_rtl {
+                pieces.reverse();
+            }
+            for (range, shift) in pieces {
+                let (run, run_width, carets) =
+                    font.shape_with_carets(&text[range.clone()], width, options);
+                let y = -(shift * font.size) as f32;
+                for mut glyph in run.glyphs {
+                    let mut x = glyph.offset.x();
+                    // skribo lays every run out left to right, in logical order.
+                    if run_rtl {
+                        x = run_width as f32 - x - glyph_advance(&glyph, run.size);
+                    }
+                    glyph.offset = vec2f(x + width as f32, glyph.offset.y() + y);
+                    glyphs.glyphs.push(glyph);
+                }
+                let carets = carets
+                    .into_iter()
+                    .map(|(idx, x)| (range.start + idx, x))
+                    .collect();
+                runs.push(VisualRun {
+                    range,
+                    rtl: run_rtl,
+                    x: width,
+                    width: run_width,
+                    carets,
+                });
+                width += run_width;
+            }
+        }
+
+        // trailing whitespace hangs past the end of the line.
+        let trailing_x = if rtl { 0.0 } else { width };
+        let (_, _, trailing) =
+            font.shape_with_carets(&text[visible_end..caret_end], trailing_x, options);
+        let trailing: Vec<_> = trailing
+            .into_iter()
+            .map(|(idx, x)| (visible_end + idx, x))
+            .collect();
+
+        // carets never split a grapheme cluster, such as an accented letter or
+        // a flag.
+        let carets = text[start..caret_end]
+            .grapheme_indices(true)
+            .map(|(idx, _)| start + idx)
+            .chain(Some(caret_end))
+            .map(|idx| {
+                let x = match runs.iter().find(|run| run.range.contains(&idx)) {
+                    Some(run) if run.rtl => run.x + run.width - offset_at(&run.carets, idx),
+                    Some(run) => run.x + offset_at(&run.carets, idx),
+                    None if rtl => -offset_at(&trailing, idx),
+                    None => width + offset_at(&trailing, idx),
+                };
+                (idx, x)
+            })
+            .collect();
+
+        let (color_glyphs, filled_glyphs): (Vec<_>, Vec<_>) = glyphs
+            .glyphs
+            .into_iter()
+            .partition(|glyph| font.is_color_font(&glyph.font.font));
+        glyphs.glyphs = filled_glyphs;
+
+        Line {
+            glyphs: Rc::new(glyphs),
+            color_glyphs: Rc::new(color_glyphs),
+            metric,
+            width,
+            x_offset: 0.0,
+            carets,
+        }
+    }
+}
+
+/// Places `clusters`, given with their widths, one after the other from `start`
+/// to `end`, each taking a share of the space by its width.
+fn spread_clusters(
+    offsets: &mut Vec<(usize, f64)>,
+    clusters: &[(usize, f64)],
+    start: f64,
+    end: f64,
+) {
+    let total: f64 = clusters.iter().map(|(_, width)| width).sum();
+    let mut offset = start;
+    for &(idx, width) in clusters {
+        offsets.push((idx, offset));
+        offset += if total > 0.0 {
+            (end - start) * width / total
+        } else {
+            (end - start) / clusters.len() as f64
+        };
+    }
+}
+
+/// Looks up the offset of the caret at `idx` among `carets`, which are in
+/// logical order, using the closest boundary before it if a piece of the line
+/// starts inside a grapheme cluster.
+fn offset_at(carets: &[(usize, f64)], idx: usize) -> f64 {
+    carets
+        .iter()
+        .take_while(|(caret_idx, _)| *caret_idx <= idx)
+        .last()
+        .map(|(_, x)| *x)
+        .unwrap_or(0.0)
+}
+
+/// Splits `range` into pieces with a single baseline shift, in logical order,
+/// along with the shift of each one.
+fn shifted_pieces(range: Range<usize>, shifts: &[(Range<usize>, f64)]) -> Vec<(Range<usize>, f64)> {
+    let mut boundaries: Vec<usize> = shifts
+        .iter()
+        .flat_map(|(shifted, _)| vec![shifted.start, shifted.end])
+        .filter(|idx| range.contains(idx))
+        .chain(vec![range.start, range.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    boundaries
+        .windows(2)
+        .map(|piece| (piece[0]..piece[1], baseline_shift_at(shifts, piece[0])))
+        .collect()
+}
+
+/// The baseline shift, in ems, of the text at `idx`.
+fn baseline_shift_at(shifts: &[(Range<usize>, f64)], idx: usize) -> f64 {
+    shifts
+        .iter()
+        .rev()
+        .find(|(range, _)| range.contains(&idx))
+        .map_or(0.0, |(_, shift)| *shift)
+}
+
+/// Splits `text` into runs of a single direction, in visual order, along with
+/// whether each one runs right to left.
+fn visual_runs(text: &str, rtl: bool) -> Vec<(Range<usize>, bool)> {
+    let level = if rtl { Level::rtl() } else { Level::ltr() };
+    let bidi = BidiInfo::new(text, Some(level));
+    let mut runs = Vec::new();
+    for paragraph in &bidi.paragraphs {
+        let (levels, level_runs) = bidi.visual_runs(paragraph, paragraph.range.clone());
+        runs.extend(level_runs.into_iter().map(|run| {
+            let rtl = levels[run.start].is_rtl();
+            (run, rtl)
+        }));
+    }
+    runs
+}
+
+impl ResolvedFont {
+    /// Resolves the font of `family_name` with the style of `defaults`,
+    /// without any fallbacks.
+    fn new(
+        font_source: &FontSource,
+        family_name: FamilyName,
+        defaults: &util::LayoutDefaults,
+    ) -> Result<Self, Error> {
+        let mut properties = Properties::new();
+        properties.weight = Weight(defaults.weight.to_raw() as f32);
+        if defaults.style == FontStyle::Italic {
+            properties.style = Style::Italic;
+        }
+
+        let font = font_source
+            .select_best_match(&[family_name], &properties)
+            .map_err(|_| Error::MissingFont)?
+            .load()
+            .map_err(|_| Error::FontLoadingFailed)?;
+        let mut collection = FontCollection::new();
+        collection.add_family(skribo::FontFamily::new_from_font(font.clone()));
+        let color_fonts = if has_color_glyphs(&font) {
+            font.postscript_name().into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        Ok(ResolvedFont {
+            collection: Arc::new(collection),
+            font,
+            properties,
+            size: defaults.font_size,
+            color_fonts,
+            fallbacks: Vec::new(),
+        })
+    }
+
+    /// Adds `fallbacks` for the characters that this font doesn't cover.
+    fn with_fallbacks(mut self, fallbacks: Vec<font_kit::font::Font>) -> Self {
+        if fallbacks.is_empty() {
+            return self;
+        }
+        // skribo picks the first font in the collection that covers each
+        // character, so fallbacks go after the requested font.
+        let mut collection = FontCollection::new();
+        collection.add_family(skribo::FontFamily::new_from_font(self.font.clone()));
+        for fallback in &fallbacks {
+            if has_color_glyphs(fallback) {
+                self.color_fonts.extend(fallback.postscript_name());
+            }
+            collection.add_family(skribo::FontFamily::new_from_font(fallback.clone()));
+        }
+        self.collection = Arc::new(collection);
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    /// Returns `c`'s own glyph and its advance, from the first font of the
+    /// collection that covers it, as skribo picks it, or `None` if none does.
+    fn nominal_glyph(&self, c: char) -> Option<(u32, f64)> {
+        std::iter::once(&self.font)
+            .chain(&self.fallbacks)
+            .find_map(|font| {
+                let glyph_id = font.glyph_for_char(c)?;
+                let advance = font.advance(glyph_id).ok()?.x() as f64;
+                Some((
+                    glyph_id,
+                    advance * self.size / font.metrics().units_per_em as f64,
+                ))
+            })
+    }
+
+    fn is_color_font(&self, font: &font_kit::font::Font) -> bool {
+        !self.color_fonts.is_empty()
+            && font
+                .postscript_name()
+                .map_or(false, |name| self.color_fonts.contains(&name))
+    }
+
+    /// Returns the metrics of this font, in pixels.
+    fn metrics(&self) -> FontMetrics {
+        let metrics = self.font.metrics();
+        let scale = self.size / metrics.units_per_em as f64;
+        FontMetrics {
+            ascent: metrics.ascent as f64 * scale,
+            descent: -metrics.descent as f64 * scale,
+            line_gap: metrics.line_gap as f64 * scale,
+            cap_height: metrics.cap_height as f64 * scale,
+            x_height: metrics.x_height as f64 * scale,
+        }
+    }
+
+    /// Shapes `text`, which starts `x` from the start of its line, returning
+    /// the glyphs along with their advance width.
+    ///
+    /// Tabs aren't shaped, but advance to the next tab stop, which is why the
+    /// position in the line matters.
+    fn shape_at(&self, text: &str, x: f64, options: &ShapingOptions) -> (skribo::Layout, f64) {
+        let mut layout = skribo::Layout {
+            size: self.size as f32,
+            glyphs: Vec::new(),
+        };
+        let mut advance = 0.0;
+        for (i, segment) in text.split('\t').enumerate() {
+            if i > 0 {
+                advance = next_tab_stop(x + advance, self.tab_width(options)) - x;
+            }
+            let segment = self.shape_segment(segment, options);
+            if segment.glyphs.is_empty() {
+                continue;
+            }
+            let segment_advance = layout_advance(&segment) + options.tracking;
+            layout
+                .glyphs
+                .extend(segment.glyphs.into_iter().map(|mut glyph| {
+                    glyph.offset = glyph.offset + vec2f(advance as f32, 0.0);
+                    glyph
+                }));
+            // the glyph offsets already include the tracking of all but the last glyph.
+            advance += segment_advance;
+        }
+        (layout, advance)
+    }
+
+    /// Shapes `text` like [`shape_at`], also returning the offset of each
+    /// grapheme boundary in it, ending with its advance.
+    ///
+    /// The text is shaped once, and each cluster's caret is placed at the first
+    /// of its glyphs.
+    ///
+    /// [`shape_at`]: ResolvedFont::shape_at
+    fn shape_with_carets(
+        &self,
+        text: &str,
+        x: f64,
+        options: &ShapingOptions,
+    ) -> (skribo::Layout, f64, Vec<(usize, f64)>) {
+        let mut layout = skribo::Layout {
+            size: self.size as f32,
+            glyphs: Vec::new(),
+        };
+        let mut carets = Vec::new();
+        let mut advance = 0.0;
+        let mut segment_start = 0;
+        for (i, segment) in text.split('\t').enumerate() {
+            if i > 0 {
+                // the tab is a cluster of its own, without glyphs.
+                carets.push((segment_start - 1, advance));
+                advance = next_tab_stop(x + advance, self.tab_width(options)) - x;
+            }
+            let shaped = self.shape_segment(segment, options);
+            let segment_advance = if shaped.glyphs.is_empty() {
+                0.0
+            } else {
+                layout_advance(&shaped) + options.tracking
+            };
+            carets.extend(
+                self.cluster_offsets(segment, &shaped, segment_advance)
+                    .into_iter()
+                    .map(|(idx, offset)| (segment_start + idx, advance + offset)),
+            );
+            layout
+                .glyphs
+                .extend(shaped.glyphs.into_iter().map(|mut glyph| {
+                    glyph.offset = glyph.offset + vec2f(advance as f32, 0.0);
+                    glyph
+                }));
+            advance += segment_advance;
+            segment_start += segment.len() + 1;
+        }
+        carets.push((text.len(), advance));
+        (layout, advance, carets)
+    }
+
+    /// Finds where each grapheme cluster of `text` starts within `layout`,
+    /// which is `text` shaped on its own, with the given advance.
+    ///
+    /// skribo doesn't say which cluster a glyph came from, so each character
+    /// the fonts cover is taken to become its own glyph, and a cluster is
+    /// found where the glyph for its first character is. Clusters that can't
+    /// be found that way, such as those joined into a ligature, share the
+    /// space up to the next one that is by their characters' own advances.
+    fn cluster_offsets(
+        &self,
+        text: &str,
+        layout: &skribo::Layout,
+        advance: f64,
+    ) -> Vec<(usize, f64)> {
+        let glyph_offset = |glyph: usize| {
+            layout
+                .glyphs
+                .get(glyph)
+                .map_or(advance, |glyph| glyph.offset.x() as f64)
+        };
+        let mut offsets = Vec::new();
+        // the clusters since the last one found, with their nominal widths.
+        let mut pending: Vec<(usize, f64)> = Vec::new();
+        let mut pending_glyphs = 0;
+        // glyphs before `first` belong to clusters that were found, and the
+        // next cluster would start at `expected` if no glyphs were joined.
+        let (mut first, mut expected) = (0, 0);
+        for (idx, cluster) in text.grapheme_indices(true) {
+            let glyphs: Vec<(u32, f64)> = cluster
+                .chars()
+                .filter_map(|c| self.nominal_glyph(c))
+                .collect();
+            let found = match glyphs.first() {
+                Some(&(glyph_id, _)) => (first..=expected + pending_glyphs).find(|&glyph| {
+                    layout
+                        .glyphs
+                        .get(glyph)
+                        .map_or(false, |glyph| glyph.glyph_id == glyph_id)
+                }),
+                None if pending.is_empty() => Some(expected.min(layout.glyphs.len())),
+                None => None,
+            };
+            match found {
+                Some(found) => {
+                    let start = glyph_offset(expected.min(found));
+                    spread_clusters(&mut offsets, &pending, start, glyph_offset(found));
+                    pending.clear();
+                    pending_glyphs = 0;
+                    offsets.push((idx, glyph_offset(found)));
+                    first = found + glyphs.len().min(1);
+                    expected = found + glyphs.len();
+                }
+                None => {
+                    pending.push((idx, glyphs.iter().map(|(_, width)| width).sum()));
+                    pending_glyphs += glyphs.len();
+                }
+            }
+        }
+        let start = glyph_offset(expected);
+        spread_clusters(&mut offsets, &pending, start, advance);
+        offsets
+    }
+
+    /// Shapes `text`, which has no tabs, with this font.
+    fn shape_segment(&self, text: &str, options: &ShapingOptions) -> skribo::Layout {
+        let mut layout = if options.ligatures {
+            self.shape_run(text)
+        } else {
+            // skribo has no way to turn features off, but it can't form a
+            // ligature across clusters that are shaped on their own.
+            let mut layout = skribo::Layout {
+                size: self.size as f32,
+                glyphs: Vec::new(),
+            };
+            let mut x = 0.0;
+            for cluster in text.graphemes(true) {
+                let run = self.shape_run(cluster);
+                let advance = layout_advance(&run) as f32;
+                layout
+                    .glyphs
+                    .extend(run.glyphs.into_iter().map(|mut glyph| {
+                        glyph.offset = glyph.offset + vec2f(x, 0.0);
+                        glyph
+                    }));
+                x += advance;
+            }
+            layout
+        };
+        if !options.kerning {
+            // placing each glyph by its own advance drops the shaper's
+            // adjustments between pairs.
+            let mut x = 0.0;
+            for glyph in &mut layout.glyphs {
+                glyph.offset = vec2f(x, glyph.offset.y());
+                x += glyph_advance(glyph, layout.size);
+            }
+        }
+        if options.tracking != 0.0 {
+            for (i, glyph) in layout.glyphs.iter_mut().enumerate() {
+                glyph.offset = glyph.offset + vec2f((i as f64 * options.tracking) as f32, 0.0);
+            }
+        }
+        layout
+    }
+
+    /// Shapes `text` with skribo's default features.
+    fn shape_run(&self, text: &str) -> skribo::Layout {
+        skribo::layout(
+            &TextStyle {
+                size: self.size as f32,
+            },
+            &self.collection,
+            text,
+        )
+    }
+
+    /// Returns the advance width of `text` when shaped with this font, at the
+    /// start of a line.
+    fn text_width(&self, text: &str, options: &ShapingOptions) -> f64 {
+        self.text_width_at(text, 0.0, options)
+    }
+
+    /// Returns the advance width of `text`, starting `x` into its line.
+    fn text_width_at(&self, text: &str, x: f64, options: &ShapingOptions) -> f64 {
+        self.shape_at(text, x, options).1
+    }
+
+    /// The distance between tab stops, which defaults to eight spaces.
+    fn tab_width(&self, options: &ShapingOptions) -> f64 {
+        options.tab_width.unwrap_or_else(|| {
+            let space = self
+                .font
+                .glyph_for_char(' ')
+                .and_then(|glyph_id| self.font.advance(glyph_id).ok())
+                .map_or(0.0, |advance| advance.x() as f64);
+            8.0 * space * self.size / self.font.metrics().units_per_em as f64
+        })
+    }
+}
+
+/// The first tab stop after `x`, for stops every `tab_width`.
+fn next_tab_stop(x: f64, tab_width: f64) -> f64 {
+    if tab_width > 0.0 {
+        ((x / tab_width).floor() + 1.0) * tab_width
+    } else {
+        x
+    }
+}
+
+/// Families tried first, in order, for characters that the requested font
+/// lacks.
+const FALLBACK_FAMILIES: &[&str] = &[
+    "Noto Sans",
+    "Noto Sans CJK SC",
+    "Noto Color Emoji",
+    "Apple Color Emoji",
+    "Segoe UI Emoji",
+    "Segoe UI Symbol",
+    "PingFang SC",
+    "Microsoft YaHei",
+    "Arial Unicode MS",
+    "DejaVu Sans",
+];
+
+/// Whether `font` has color glyphs, as color emoji fonts do.
+fn has_color_glyphs(font: &font_kit::font::Font) -> bool {
+    [b"COLR", b"CBDT", b"sbix"]
+        .iter()
+        .any(|tag| font.load_font_table(u32::from_be_bytes(**tag)).is_some())
+}
+
+/// Rasterizes a glyph from a color font into an image, returning it with the
+/// rect to draw it in, relative to the glyph's baseline origin.
+///
+/// `scale` is the scale of the device pixels the image is drawn to, so that it
+/// isn't blurred when drawn.
+pub(crate) fn rasterize_color_glyph(
+    glyph: &skribo::Glyph,
+    size: f64,
+    scale: f64,
+) -> Option<(crate::Image, Rect)> {
+    let point_size = (size * scale) as f32;
+    let (canvas, bounds) = rasterize_glyph(glyph, point_size, Vector2F::zero(), Format::Rgba32)?;
+    let (width, height) = (bounds.width() as usize, bounds.height() as usize);
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in canvas.pixels.chunks(canvas.stride) {
+        pixels.extend_from_slice(&row[..width * 4]);
+    }
+    let image = crate::Image::from_premultiplied(width, height, &pixels).ok()?;
+    let origin = Point::new(bounds.origin_x() as f64, bounds.origin_y() as f64);
+    let rect = Rect::from_origin_size(origin, (width as f64, height as f64))
+        .scale_from_origin(1.0 / scale);
+    Some((image, rect))
+}
+
+/// Rasterizes a glyph of `point_size` device pixels with its baseline origin
+/// at `origin`, returning the canvas along with the device pixels it covers.
+fn rasterize_glyph(
+    glyph: &skribo::Glyph,
+    point_size: f32,
+    origin: Vector2F,
+    format: Format,
+) -> Option<(font_kit::canvas::Canvas, RectI)> {
+    let font = &glyph.font.font;
+    let transform = Transform2F::from_translation(origin);
+    let bounds = glyph_raster_bounds(glyph, point_size, origin)?;
+    let mut canvas = font_kit::canvas::Canvas::new(bounds.size(), format);
+    font.rasterize_glyph(
+        &mut canvas,
+        glyph.glyph_id,
+        point_size,
+        Transform2F::from_translation(-bounds.origin().to_f32()) * transform,
+        HintingOptions::None,
+        RasterizationOptions::GrayscaleAa,
+    )
+    .ok()?;
+    Some((canvas, bounds))
+}
+
+/// Returns the device pixels covered by a glyph of `point_size` device pixels
+/// with its baseline origin at `origin`, or `None` if it covers nothing.
+fn glyph_raster_bounds(glyph: &skribo::Glyph, point_size: f32, origin: Vector2F) -> Option<RectI> {
+    let bounds = glyph
+        .font
+        .font
+        .raster_bounds(
+            glyph.glyph_id,
+            point_size,
+            Transform2F::from_translation(origin),
+            HintingOptions::None,
+            RasterizationOptions::GrayscaleAa,
+        )
+        .ok()?;
+    if bounds.width() <= 0 || bounds.height() <= 0 {
+        return None;
+    }
+    Some(bounds)
+}
+
+/// Composites a rasterized glyph over premultiplied RGBA `pixels`, rows of
+/// `width` pixels, mapping each of the glyph's pixels to a premultiplied color
+/// with `color_of`. Parts of the glyph outside of `pixels` are left out.
+fn composite_glyph(
+    pixels: &mut [u8],
+    width: usize,
+    (canvas, rect): (font_kit::canvas::Canvas, RectI),
+    color_of: impl Fn(&[u8]) -> [u8; 4],
+) {
+    let height = pixels.len() / 4 / width;
+    let bytes_per_pixel = canvas.format.bytes_per_pixel() as usize;
+    for y in 0..rect.height() {
+        for x in 0..rect.width() {
+            let (dst_x, dst_y) = (rect.origin_x() + x, rect.origin_y() + y);
+            if dst_x < 0 || dst_y < 0 || dst_x as usize >= width || dst_y as usize >= height {
+                continue;
+            }
+            let src = y as usize * canvas.stride + x as usize * bytes_per_pixel;
+            let color = color_of(&canvas.pixels[src..src + bytes_per_pixel]);
+            let dst = (dst_y as usize * width + dst_x as usize) * 4;
+            let inverse = 255 - color[3] as u32;
+            for (dst, src) in pixels[dst..dst + 4].iter_mut().zip(&color) {
+                *dst = (*src as u32 + (*dst as u32 * inverse + 127) / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Computes the advance of a shaped layout: the offset of its last glyph, plus
+/// that glyph's own advance.
+fn layout_advance(layout: &skribo::Layout) -> f64 {
+    match layout.glyphs.last() {
+        Some(glyph) => (glyph.offset.x() + glyph_advance(glyph, layout.size)) as f64,
+        None => 0.0,
+    }
+}
+
+/// The advance of a single glyph, at the given font size.
+fn glyph_advance(glyph: &skribo::Glyph, size: f32) -> f32 {
+    let font = &glyph.font.font;
+    let scale = size / font.metrics().units_per_em as f32;
+    let advance = font
+        .advance(glyph.glyph_id)
+        .map(|advance| advance.x())
+        .unwrap_or(0.0);
+    advance * scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use font_kit::error::SelectionError;
+    use font_kit::family_handle::FamilyHandle;
+    use font_kit::handle::Handle;
+    use piet::{Text as _, TextLayoutBuilder as _};
+    use std::any::Any;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn system_text() -> Text {
+        Text::new(Arc::new(FontSource::system()))
+    }
+
+    /// The data of some installed font, for testing `load_font`.
+    fn system_font_data() -> Vec<u8> {
+        let font = font_kit::source::SystemSource::new()
+            .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+            .unwrap()
+            .load()
+            .unwrap();
+        font.copy_font_data().unwrap().to_vec()
+    }
+
+    #[test]
+    fn loaded_family_is_listed_first() {
+        let mut text = system_text();
+        let family = text.load_font(&system_font_data()).unwrap();
+        let names = text.font_source.family_names().unwrap();
+        assert_eq!(names.first().map(String::as_str), Some(family.name()));
+        assert_eq!(
+            names.iter().filter(|name| *name == family.name()).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn loaded_family_is_available() {
+        let mut text = system_text();
+        let family = text.load_font(&system_font_data()).unwrap();
+        assert!(text.has_family(family.name()));
+        assert!(!text.has_family("Not A Real Font Family 5f3a"));
+    }
+
+    #[test]
+    fn removed_family_is_no_longer_listed() {
+        let mut text = system_text();
+        let family = text.load_font(&system_font_data()).unwrap();
+        assert!(text.font_source.remove_font(&family).unwrap());
+        assert!(!text.font_source.remove_font(&family).unwrap());
+
+        let in_memory_families = text
+            .font_source
+            .in_memory_source
+            .lock()
+            .unwrap()
+            .all_families()
+            .unwrap();
+        assert!(!in_memory_families.iter().any(|name| name == family.name()));
+    }
+
+    #[test]
+    fn cleared_fonts_are_no_longer_loaded() {
+        let mut text = system_text();
+        let family = text.load_font(&system_font_data()).unwrap();
+        text.clear_loaded_fonts();
+        let in_memory_families = text
+            .font_source
+            .in_memory_source
+            .lock()
+            .unwrap()
+            .all_families()
+            .unwrap();
+        assert!(in_memory_families.is_empty());
+        // the data came from an installed font, which is still there.
+        assert!(text.has_family(family.name()));
+        assert!(text.new_text_layout("hello").build().is_ok());
+    }
+
+    #[test]
+    fn missing_family_falls_back_to_sans_serif() {
+        let mut text = system_text();
+        assert!(!text.take_missing_font());
+        let layout = text
+            .new_text_layout("fallback")
+            .font(
+                FontFamily::new_unchecked("Not A Real Font Family 5f3a"),
+                16.0,
+            )
+            .build()
+            .unwrap();
+        assert!(layout.size().width > 0.0);
+        assert!(layout.font_metrics().ascent > 0.0);
+        assert!(text.take_missing_font());
+        assert!(!text.take_missing_font());
+    }
+
+    #[test]
+    fn out_of_range_face_is_missing() {
+        let mut text = system_text();
+        let data = system_font_data();
+        let count = face_count(&Arc::new(data.clone())).unwrap();
+        assert!(matches!(
+            text.load_font_at(&data, count),
+            Err(Error::MissingFont)
+        ));
+    }
+
+    #[test]
+    fn truncated_font_is_a_parse_error() {
+        let mut text = system_text();
+        let data = system_font_data();
+        match text.load_font(&data[..data.len().min(64)]) {
+            Err(Error::BackendError(err)) => assert!(matches!(
+                err.downcast_ref::<FontLoadingError>(),
+                Some(FontLoadingError::Parse)
+            )),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an installed font collection"]
+    fn collection_faces_are_selectable() {
+        // There is no collection checked into the repo, so borrow an installed one.
+        let path = font_kit::source::SystemSource::new()
+            .all_fonts()
+            .unwrap()
+            .into_iter()
+            .find_map(|handle| match handle {
+                font_kit::handle::Handle::Path { path, font_index } if font_index > 0 => Some(path),
+                _ => None,
+            })
+            .expect("no font collection is installed");
+        let data = std::fs::read(path).unwrap();
+
+        let mut text = system_text();
+        let families = text.load_font_collection(&data).unwrap();
+        assert!(families.len() >= 2);
+        let second = text.load_font_at(&data, 1).unwrap();
+        assert_eq!(second, families[1]);
+        for family in &families {
+            assert!(text.font_family(family.name()).is_some());
+        }
+    }
+
+    #[test]
+    fn mixed_direction_text_is_reordered() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("abc \u{5d0}\u{5d1}\u{5d2}")
+            .build()
+            .unwrap();
+        let x = |idx| layout.hit_test_text_position(idx).point.x;
+        // the latin run reads left to right...
+        assert!(x(0) < x(1) && x(1) < x(2));
+        // ...and is followed by the hebrew one, which reads right to left.
+        assert!(x(4) > x(6) && x(6) > x(8));
+        assert!(x(8) > x(3));
+    }
+
+    #[test]
+    fn rtl_text_starts_on_the_right() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("\u{5d0}\u{5d1}\u{5d2}")
+            .max_width(200.0)
+            .build()
+            .unwrap();
+        assert!((layout.hit_test_text_position(0).point.x - 200.0).abs() < 0.01);
+        assert!(layout.hit_test_text_position(2).point.x < 200.0);
+    }
+
+    #[test]
+    #[ignore = "needs an installed font that covers CJK"]
+    fn missing_glyphs_fall_back_to_other_fonts() {
+        let mut text = system_text();
+        let layout = text.new_text_layout("a\u{4e2d}").build().unwrap();
+        let glyphs = &layout.lines[0].glyphs.glyphs;
+        assert_eq!(glyphs.len(), 2);
+        assert_ne!(glyphs[1].glyph_id, 0, "the CJK glyph is .notdef");
+        let cjk_width = layout.size().width - layout.hit_test_text_position(1).point.x;
+        assert!(cjk_width > 0.0);
+    }
+
+    #[test]
+    fn generic_family_names_resolve() {
+        let mut text = system_text();
+        let monospace = text.font_family("monospace").unwrap();
+        assert_eq!(monospace, FontFamily::MONOSPACE);
+        assert_eq!(text.font_family("Sans-Serif"), Some(FontFamily::SANS_SERIF));
+        assert!(text.has_family("serif"));
+
+        let mut attributes = util::LayoutDefaults::default();
+        attributes.set(monospace);
+        let narrow = text.measure_width("iiii", &attributes).unwrap();
+        let wide = text.measure_width("WWWW", &attributes).unwrap();
+        assert!(narrow > 0.0);
+        assert!((narrow - wide).abs() < 0.01, "{} != {}", narrow, wide);
+    }
+
+    #[test]
+    fn measured_width_matches_layout_width() {
+        let mut text = system_text();
+        let mut attributes = util::LayoutDefaults::default();
+        attributes.set(TextAttribute::FontSize(20.0));
+        for string in &["hello world", "W", "measure me"] {
+            let measured = text.measure_width(string, &attributes).unwrap();
+            let layout = text
+                .new_text_layout(*string)
+                .default_attribute(TextAttribute::FontSize(20.0))
+                .build()
+                .unwrap();
+            assert!((measured - layout.size().width).abs() < 0.01, "{}", string);
+        }
+    }
+
+    #[test]
+    fn measured_height_matches_layout_height() {
+        let mut text = system_text();
+        let mut attributes = util::LayoutDefaults::default();
+        attributes.set(TextAttribute::FontSize(16.0));
+        let paragraph = "A paragraph that is long enough to wrap onto a few lines \
+                         when it is given a narrow width.";
+        let narrow = text.measure_height(paragraph, 100.0, &attributes).unwrap();
+        let wide = text.measure_height(paragraph, 300.0, &attributes).unwrap();
+        let unwrapped = text
+            .measure_height(paragraph, f64::INFINITY, &attributes)
+            .unwrap();
+        assert!(
+            narrow > wide && wide > unwrapped,
+            "{} {} {}",
+            narrow,
+            wide,
+            unwrapped
+        );
+
+        let layout = text
+            .new_text_layout(paragraph)
+            .default_attribute(TextAttribute::FontSize(16.0))
+            .max_width(100.0)
+            .build()
+            .unwrap();
+        assert!((layout.size().height - narrow).abs() < 0.01);
+    }
+
+    #[test]
+    fn line_break_opportunities_follow_spaces_and_hyphens() {
+        let mut text = system_text();
+        let mut attributes = util::LayoutDefaults::default();
+        attributes.set(TextAttribute::FontSize(20.0));
+        let string = "A well-known fact.\nNew line";
+        let opportunities = text.line_break_opportunities(string, &attributes).unwrap();
+        let offsets: Vec<(usize, bool)> = opportunities
+            .iter()
+            .map(|opportunity| (opportunity.offset, opportunity.is_hard))
+            .collect();
+        assert_eq!(
+            offsets,
+            vec![
+                (2, false),
+                (7, false),
+                (13, false),
+                (19, true),
+                (23, false),
+                (27, true)
+            ]
+        );
+        let known = text.measure_width("known ", &attributes).unwrap();
+        assert!((opportunities[2].width - known).abs() < 0.01);
+        assert!(opportunities
+            .iter()
+            .all(|opportunity| opportunity.width > 0.0));
+    }
+
+    /// A system font source that counts family lookups.
+    struct CountingSource {
+        inner: font_kit::source::SystemSource,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl Source for CountingSource {
+        fn all_fonts(&self) -> Result<Vec<Handle>, SelectionError> {
+            self.inner.all_fonts()
+        }
+
+        fn all_families(&self) -> Result<Vec<String>, SelectionError> {
+            self.inner.all_families()
+        }
+
+        fn select_family_by_name(&self, family_name: &str) -> Result<FamilyHandle, SelectionError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.select_family_by_name(family_name)
+        }
+
+        fn select_family_by_generic_name(
+            &self,
+            family_name: &FamilyName,
+        ) -> Result<FamilyHandle, SelectionError> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.inner.select_family_by_generic_name(family_name)
+        }
+
+        fn select_by_postscript_name(
+            &self,
+            postscript_name: &str,
+        ) -> Result<Handle, SelectionError> {
+            self.inner.select_by_postscript_name(postscript_name)
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_mut_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn fonts_are_resolved_once_per_style() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let source = CountingSource {
+            inner: font_kit::source::SystemSource::new(),
+            lookups: lookups.clone(),
+        };
+        let mut text = Text::new(Arc::new(FontSource::new(vec![Box::new(source)])));
+
+        text.new_text_layout("first").build().unwrap();
+        let first_lookups = lookups.load(Ordering::SeqCst);
+        assert!(first_lookups > 0);
+        for _ in 0..10 {
+            text.new_text_layout("again").build().unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), first_lookups);
+
+        // characters the font lacks only look up fallbacks the first time,
+        // whether or not one covers them.
+        text.new_text_layout("a\u{4e2d}").build().unwrap();
+        let fallback_lookups = lookups.load(Ordering::SeqCst);
+        for _ in 0..10 {
+            text.new_text_layout("b\u{4e2d}").build().unwrap();
+            text.measure_width("\u{4e2d}", &util::LayoutDefaults::default())
+                .unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), fallback_lookups);
+
+        // families that didn't cover one character aren't looked up again for
+        // the next; no font covers these noncharacters.
+        text.new_text_layout("\u{fdd0}").build().unwrap();
+        let uncovered_lookups = lookups.load(Ordering::SeqCst);
+        text.new_text_layout("\u{fdd1}").build().unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), uncovered_lookups);
+
+        // a newly loaded font may change what the family resolves to.
+        text.load_font(&system_font_data()).unwrap();
+        assert_ne!(
+            text.fonts.borrow().generation,
+            text.font_source.generation()
+        );
+        text.new_text_layout("after loading").build().unwrap();
+        assert_eq!(
+            text.fonts.borrow().generation,
+            text.font_source.generation()
+        );
+    }
+
+    fn is_in_memory(handle: &FamilyHandle) -> bool {
+        handle
+            .fonts()
+            .iter()
+            .all(|font| matches!(font, Handle::Memory { .. }))
+    }
+
+    #[test]
+    fn selection_prefers_loaded_fonts() {
+        let mut text = system_text();
+        let source = text.font_source.clone();
+        assert!(!source.has_in_memory_fonts.load(Ordering::SeqCst));
+
+        let family = text.load_font(&system_font_data()).unwrap();
+        assert!(source.has_in_memory_fonts.load(Ordering::SeqCst));
+        let handle = source.select_family_by_name(family.name()).unwrap();
+        assert!(is_in_memory(&handle));
+
+        source.remove_font(&family).unwrap();
+        assert!(!source.has_in_memory_fonts.load(Ordering::SeqCst));
+        let handle = source.select_family_by_name(family.name()).unwrap();
+        assert!(!is_in_memory(&handle));
+    }
+
+    #[test]
+    fn clamped_layout_ends_in_an_ellipsis() {
+        let mut text = system_text();
+        let paragraph = "This paragraph is long enough that it has to wrap onto \
+                         several lines at this width, but only two are shown.";
+        let layout = text
+            .new_text_layout(paragraph)
+            .max_width(100.0)
+            .max_lines(2)
+            .build()
+            .unwrap();
+        assert_eq!(layout.line_count(), 2);
+        assert!(layout.line_text(1).unwrap().ends_with(lines::ELLIPSIS));
+        assert!(layout.size().width <= 100.0);
+    }
+
+    #[test]
+    fn character_wrap_breaks_long_words() {
+        let mut text = system_text();
+        let string = "a supercalifragilisticexpialidocious word";
+        let mut layout = |wrap| {
+            text.new_text_layout(string)
+                .max_width(60.0)
+                .wrap_mode(wrap)
+                .build()
+                .unwrap()
+        };
+        let word = layout(WrapMode::Word);
+        let character = layout(WrapMode::Character);
+        assert_eq!(word.line_count(), 3);
+        assert!(character.line_count() > word.line_count());
+        assert!(character.size().width <= 60.0);
+        assert!(word.size().width > 60.0);
+    }
+
+    #[test]
+    fn tabs_align_columns() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("a\tcolumn\nabc\tcolumn")
+            .build()
+            .unwrap();
+        let first = layout.hit_test_text_position(2).point.x;
+        let second = layout.hit_test_text_position(13).point.x;
+        assert!(first > 0.0);
+        assert!((first - second).abs() < 0.01, "{} != {}", first, second);
+        assert!(layout.size().width >= first);
+
+        let tabbed = text
+            .new_text_layout("a\tb")
+            .tab_width(100.0)
+            .build()
+            .unwrap();
+        assert!((tabbed.hit_test_text_position(2).point.x - 100.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn first_line_is_indented() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("first line\nsecond line")
+            .first_line_indent(20.0)
+            .build()
+            .unwrap();
+        let first = layout.hit_test_text_position(0).point.x;
+        let second = layout.hit_test_text_position(11).point.x;
+        assert!((first - second - 20.0).abs() < 0.01);
+        assert_eq!(layout.hit_test_point(Point::new(first + 0.1, 5.0)).idx, 0);
+        assert!(layout.glyph_runs().next().unwrap().1.x > second);
+    }
+
+    #[test]
+    fn line_height_scales_line_spacing() {
+        let mut text = system_text();
+        let spacing = |text: &mut Text, line_height| {
+            let layout = text
+                .new_text_layout("first line second line")
+                .max_width(80.0)
+                .line_height(line_height)
+                .build()
+                .unwrap();
+            assert_eq!(layout.line_count(), 2);
+            let first = layout.line_metric(0).unwrap();
+            let second = layout.line_metric(1).unwrap();
+            let baselines: Vec<f64> = layout.glyph_runs().map(|(_, origin)| origin.y).collect();
+            assert!((baselines[1] - baselines[0] - first.height).abs() < 0.01);
+            (second.y_offset + second.baseline) - (first.y_offset + first.baseline)
+        };
+        let single = spacing(&mut text, 1.0);
+        let double = spacing(&mut text, 2.0);
+        assert!(
+            (double - 2.0 * single).abs() < 0.01,
+            "{} {}",
+            single,
+            double
+        );
+    }
+
+    #[test]
+    fn font_metrics_match_the_line_height() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("Metrics")
+            .default_attribute(TextAttribute::FontSize(24.0))
+            .build()
+            .unwrap();
+        let metrics = layout.font_metrics();
+        let line = layout.line_metric(0).unwrap();
+        let height = metrics.ascent + metrics.descent + metrics.line_gap;
+        assert!((height - line.height).abs() < 0.01);
+        assert!((metrics.ascent - line.baseline).abs() < 0.01);
+        assert!(metrics.x_height < metrics.cap_height);
+        assert!(metrics.cap_height <= metrics.ascent);
+    }
+
+    #[test]
+    fn trailing_newline_starts_an_empty_line() {
+        let mut text = system_text();
+        let layout = text.new_text_layout("a\n").build().unwrap();
+        assert_eq!(layout.line_count(), 2);
+        assert_eq!(layout.line_text(1), Some(""));
+        let metric = layout.line_metric(1).unwrap();
+        assert!(metric.height > 0.0);
+        assert!((layout.size().height - 2.0 * metric.height).abs() < 0.01);
+
+        let position = layout.hit_test_text_position(2);
+        assert_eq!(position.line, 1);
+        assert_eq!(position.point.x, 0.0);
+        assert!((position.point.y - metric.y_offset - metric.baseline).abs() < 0.01);
+    }
+
+    #[test]
+    fn hits_past_a_wrapped_line_stay_on_it() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("first line second line")
+            .max_width(80.0)
+            .build()
+            .unwrap();
+        assert_eq!(layout.line_count(), 2);
+        let first = layout.line_metric(0).unwrap();
+        let hit = layout.hit_test_point(Point::new(1000.0, first.baseline));
+        assert!(hit.idx < first.end_offset);
+        assert_eq!(layout.hit_test_text_position(hit.idx).line, 0);
+        assert!(!hit.is_inside);
+
+        // the last line has nothing after it, so its end can be hit.
+        let second = layout.line_metric(1).unwrap();
+        let hit = layout.hit_test_point(Point::new(1000.0, second.y_offset + second.baseline));
+        assert_eq!(hit.idx, second.end_offset);
+    }
+
+    #[test]
+    fn hits_above_or_below_the_text_are_outside() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("first line second line")
+            .max_width(80.0)
+            .build()
+            .unwrap();
+        assert_eq!(layout.line_count(), 2);
+        let width = layout.size().width;
+
+        let hit = layout.hit_test_point(Point::new(width, 1000.0));
+        assert!(!hit.is_inside);
+        assert_eq!(hit.idx, "first line second line".len());
+
+        let hit = layout.hit_test_point(Point::new(0.0, -1000.0));
+        assert!(!hit.is_inside);
+        assert_eq!(hit.idx, 0);
+
+        let first = layout.line_metric(0).unwrap();
+        assert!(
+            layout
+                .hit_test_point(Point::new(1.0, first.baseline))
+                .is_inside
+        );
+    }
+
+    #[test]
+    fn range_rects_cover_each_line() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("first line second line")
+            .max_width(80.0)
+            .build()
+            .unwrap();
+        let rects = layout.rects_for_range(6..17);
+        assert_eq!(rects.len(), 2);
+        for (rect, line) in rects.iter().zip(0..) {
+            let metric = layout.line_metric(line).unwrap();
+            assert_eq!(rect.y0, metric.y_offset);
+            assert_eq!(rect.y1, metric.y_offset + metric.height);
+        }
+        let x = |idx| layout.hit_test_text_position(idx).point.x;
+        assert!((rects[0].x0 - x(6)).abs() < 0.01);
+        assert_eq!(rects[1].x0, 0.0);
+        assert!((rects[1].x1 - x(17)).abs() < 0.01);
+
+        assert!(layout.rects_for_range(5..5).is_empty());
+        assert_eq!(layout.rects_for_range(18..usize::MAX).len(), 1);
+    }
+
+    #[test]
+    fn hits_land_on_grapheme_boundaries() {
+        let mut text = system_text();
+        // a flag made of two regional indicators, and an "e" with a combining
+        // acute accent.
+        let string = "a\u{1f1ef}\u{1f1f5}xe\u{301}y";
+        let layout = text.new_text_layout(string).build().unwrap();
+        let boundaries = [0, 1, 9, 10, 13, 14];
+        let mut x = 0.0;
+        while x < layout.size().width {
+            let hit = layout.hit_test_point(Point::new(x, 5.0));
+            assert!(boundaries.contains(&hit.idx), "{} at {}", hit.idx, x);
+            x += 0.5;
+        }
+        // positions inside a cluster are placed at its start.
+        let position = |idx| layout.hit_test_text_position(idx).point.x;
+        assert_eq!(position(5), position(1));
+        assert_eq!(position(11), position(10));
+    }
+
+    #[test]
+    fn carets_are_placed_after_the_text_before_them() {
+        let mut text = system_text();
+        let string = "AVA\tTo We\u{301}y  ";
+        let layout = text.new_text_layout(string).build().unwrap();
+        for (idx, _) in string.grapheme_indices(true).skip(1) {
+            let prefix = text
+                .new_text_layout(string[..idx].to_string())
+                .build()
+                .unwrap();
+            let expected = prefix.hit_test_text_position(idx).point.x;
+            let x = layout.hit_test_text_position(idx).point.x;
+            assert!((x - expected).abs() < 0.01, "{} at {}", x, idx);
+        }
+    }
+
+    #[test]
+    fn carets_inside_ligatures_are_in_order() {
+        let mut text = system_text();
+        let string = "office affine";
+        let layout = text.new_text_layout(string).build().unwrap();
+        let x = |idx| layout.hit_test_text_position(idx).point.x;
+        for idx in 1..=string.len() {
+            assert!(x(idx) > x(idx - 1), "{} at {}", x(idx), idx);
+        }
+        assert!((x(string.len()) - layout.size().width).abs() < 0.01);
+    }
+
+    #[test]
+    fn positions_of_invalid_indices_are_clamped() {
+        let mut text = system_text();
+        let string = "h\u{e9}llo";
+        let layout = text.new_text_layout(string).build().unwrap();
+        let end = layout.hit_test_text_position(string.len());
+        assert!((end.point.x - layout.size().width).abs() < 0.01);
+        let past_end = layout.hit_test_text_position(usize::MAX);
+        assert_eq!((past_end.point, past_end.line), (end.point, end.line));
+        // the middle of the two byte "é" is placed at its start.
+        let inside = layout.hit_test_text_position(2).point;
+        assert_eq!(inside, layout.hit_test_text_position(1).point);
+
+        let empty = text.new_text_layout("").build().unwrap();
+        assert_eq!(empty.hit_test_text_position(usize::MAX).point.x, 0.0);
+    }
+
+    #[test]
+    fn ligatures_can_be_turned_off() {
+        let mut text = system_text();
+        let ligated = text.new_text_layout("fi").build().unwrap();
+        let separate = text.new_text_layout("fi").ligatures(false).build().unwrap();
+        assert_eq!(separate.lines[0].glyphs.glyphs.len(), 2);
+        // not every font has an "fi" ligature, and some make it as wide as
+        // the letters it joins.
+        if ligated.lines[0].glyphs.glyphs.len() == 1 {
+            assert!(separate.size().width >= ligated.size().width - 0.01);
+        }
+    }
+
+    #[test]
+    fn unkerned_glyphs_are_placed_by_their_advances() {
+        let mut text = system_text();
+        let kerned = text.new_text_layout("AVAV").build().unwrap();
+        let unkerned = text.new_text_layout("AVAV").kerning(false).build().unwrap();
+        let glyphs = &unkerned.lines[0].glyphs;
+        let advances: f32 = glyphs
+            .glyphs
+            .iter()
+            .map(|glyph| glyph_advance(glyph, glyphs.size))
+            .sum();
+        assert!((unkerned.size().width - advances as f64).abs() < 0.01);
+        // "AV" is kerned closer together by most fonts that kern at all.
+        assert!(unkerned.size().width >= kerned.size().width - 0.01);
+    }
+
+    #[test]
+    fn superscripts_are_raised_above_the_baseline() {
+        let mut text = system_text();
+        let plain = text
+            .new_text_layout("x2 y")
+            .font(FontFamily::SANS_SERIF, 20.0)
+            .build()
+            .unwrap();
+        let raised = text
+            .new_text_layout("x2 y")
+            .font(FontFamily::SANS_SERIF, 20.0)
+            .baseline_shift(1..2, 0.5)
+            .build()
+            .unwrap();
+        let glyphs = &raised.lines[0].glyphs.glyphs;
+        assert!((glyphs[1].offset.y() - (glyphs[0].offset.y() - 10.0)).abs() < 0.01);
+        assert!((glyphs[2].offset.y() - glyphs[0].offset.y()).abs() < 0.01);
+
+        let outside = raised.hit_test_text_position(0).point.y;
+        let inside = raised.hit_test_text_position(1).point.y;
+        assert!((outside - inside - 10.0).abs() < 0.01);
+        assert_eq!(raised.line_count(), 1);
+        let (plain_metric, raised_metric) = (plain.line_metric(0), raised.line_metric(0));
+        assert_eq!(plain_metric.unwrap().height, raised_metric.unwrap().height);
+    }
+
+    #[test]
+    fn layout_image_holds_the_glyphs_in_their_color() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("Hello")
+            .text_color(Color::rgb8(255, 0, 0))
+            .build()
+            .unwrap();
+        let bounds = layout.image_bounds();
+        let image = layout.to_image(2.0).unwrap();
+        let raw = image.raw();
+        assert_eq!(raw.width(), (bounds.width() * 2.0).ceil() as u32);
+        assert_eq!(raw.height(), (bounds.height() * 2.0).ceil() as u32);
+        assert!(raw.pixels().any(|pixel| pixel[3] == 255));
+        for pixel in raw.pixels().filter(|pixel| pixel[3] >= 128) {
+            assert!(
+                pixel[0] >= 250 && pixel[1] <= 5 && pixel[2] <= 5,
+                "{:?}",
+                pixel
+            );
+        }
+    }
+
+    #[test]
+    fn layout_images_cover_aligned_and_raised_glyphs() {
+        let mut text = system_text();
+        let layout = text
+            .new_text_layout("Hi2")
+            .font(FontFamily::SANS_SERIF, 20.0)
+            .max_width(200.0)
+            .alignment(TextAlignment::Center)
+            .baseline_shift(2..3, 1.0)
+            .build()
+            .unwrap();
+        let bounds = layout.image_bounds();
+        // the line is centered, and the raised "2" reaches above its top.
+        assert!(bounds.x0 > 50.0, "{:?}", bounds);
+        assert!(bounds.y0 < 0.0, "{:?}", bounds);
+        for origin in layout.glyph_origins() {
+            assert!(bounds.x0 <= origin.x && origin.x < bounds.x1);
+        }
+        let image = layout.to_image(1.0).unwrap();
+        let raw = image.raw();
+        let painted = |x: u32, y: u32| raw.get_pixel(x, y)[3] != 0;
+        assert!((0..raw.width()).any(|x| (0..2).any(|y| painted(x, y))));
+        assert!((0..raw.height()).any(|y| (0..2).any(|x| painted(x, y))));
+        let right = raw.width() - 2;
+        assert!((0..raw.height()).any(|y| (right..raw.width()).any(|x| painted(x, y))));
+    }
+
+    #[test]
+    fn default_font_size_applies_to_unstyled_layouts() {
+        let mut text = system_text();
+        let small = text.new_text_layout("hello").build().unwrap();
+        text.set_default_font(FontFamily::SYSTEM_UI, 24.0);
+        let large = text.new_text_layout("hello").build().unwrap();
+        assert!(large.size().width > small.size().width * 1.5);
+        assert_eq!(large.font_size(), 24.0);
+
+        // Layouts can still set their own size.
+        let styled = text
+            .new_text_layout("hello")
+            .default_attribute(TextAttribute::FontSize(12.0))
+            .build()
+            .unwrap();
+        assert!((styled.size().width - small.size().width).abs() < 0.01);
+    }
+
+    #[test]
+    fn tracking_widens_layout() {
+        let mut text = system_text();
+        let plain = text.new_text_layout("hello").build().unwrap();
+        let tracked = text.new_text_layout("hello").tracking(2.0).build().unwrap();
+        assert!((tracked.size().width - plain.size().width - 10.0).abs() < 0.01);
+
+        let plain_x = plain.hit_test_text_position(3).point.x;
+        let tracked_x = tracked.hit_test_text_position(3).point.x;
+        assert!((tracked_x - plain_x - 6.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,266 @@
+//! Line breaking for text layouts.
+
+use std::ops::Range;
+
+use unicode_segmentation::UnicodeSegmentation;
+use xi_unicode::LineBreakIterator;
+
+use super::WrapMode;
+
+/// Splits `text` into lines no wider than `max_width`, where possible.
+///
+/// Lines are broken at every hard break, and at the last soft break opportunity
+/// that keeps the line within `max_width`. A single word that is wider than
+/// `max_width` is left on its own, overflowing line, unless `wrap` is
+/// [`WrapMode::Character`], which splits it between grapheme clusters instead.
+/// The first line is `first_line_indent` narrower than the others.
+///
+/// Text that ends in a hard break has a final, empty line after it.
+///
+/// `width_of` measures a piece of text; trailing whitespace is never measured,
+/// since it is allowed to hang past the end of the line.
+pub(crate) fn break_lines(
+    text: &str,
+    max_width: f64,
+    first_line_indent: f64,
+    wrap: WrapMode,
+    mut width_of: impl FnMut(&str) -> f64,
+) -> Vec<Range<usize>> {
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut prev_break = 0;
+
+    for (line_break, is_hard_break) in LineBreakIterator::new(text) {
+        let candidate = text[line_start..line_break].trim_end();
+        let available = available_width(&lines, max_width, first_line_indent);
+        if prev_break > line_start && width_of(candidate) > available {
+            lines.push(line_start..prev_break);
+            line_start = prev_break;
+        }
+        if wrap == WrapMode::Character && line_start == prev_break {
+            line_start = split_word(
+                text,
+                line_start..line_break,
+                max_width,
+                first_line_indent,
+                &mut width_of,
+                &mut lines,
+            );
+        }
+        if is_hard_break {
+            lines.push(line_start..line_break);
+            line_start = line_break;
+        }
+        prev_break = line_break;
+    }
+
+    if lines.is_empty() || text.ends_with(is_hard_break) {
+        lines.push(text.len()..text.len());
+    }
+    lines
+}
+
+/// Whether `c` always ends a line, as in Unicode's mandatory break classes.
+fn is_hard_break(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{0b}' | '\u{0c}' | '\u{85}' | '\u{2028}' | '\u{2029}'
+    )
+}
+
+/// The width available to the line after `lines`.
+fn available_width(lines: &[Range<usize>], max_width: f64, first_line_indent: f64) -> f64 {
+    if lines.is_empty() {
+        max_width - first_line_indent
+    } else {
+        max_width
+    }
+}
+
+/// Breaks the word at `range` into lines of whole grapheme clusters, if it is
+/// wider than the lines it's on.
+///
+/// Every full line is pushed to `lines`, and the start of the last piece,
+/// which may still be joined by the words after it, is returned.
+fn split_word(
+    text: &str,
+    range: Range<usize>,
+    max_width: f64,
+    first_line_indent: f64,
+    width_of: &mut impl FnMut(&str) -> f64,
+    lines: &mut Vec<Range<usize>>,
+) -> usize {
+    let mut start = range.start;
+    let fits = |lines: &[Range<usize>], width: f64| {
+        width <= available_width(lines, max_width, first_line_indent)
+    };
+    if fits(lines, width_of(text[range.clone()].trim_end())) {
+        return start;
+    }
+    let mut end = start;
+    for (idx, grapheme) in text[range.clone()].grapheme_indices(true) {
+        let next = range.start + idx + grapheme.len();
+        if end > start && !fits(lines, width_of(text[start..next].trim_end())) {
+            lines.push(start..end);
+            start = end;
+        }
+        end = next;
+    }
+    start
+}
+
+/// The text appended to the last line of a clamped layout.
+pub(crate) const ELLIPSIS: &str = "\u{2026}";
+
+/// Drops the lines of `text` after the first `max_lines`, and ends the last
+/// remaining one with an [`ELLIPSIS`], removing characters from it until the
+/// ellipsis fits within `max_width`.
+///
+/// Returns the truncated text, with `lines` shortened to match, or `None` if
+/// there were no more than `max_lines` lines. At least one line is kept.
+pub(crate) fn clamp_lines(
+    text: &str,
+    lines: &mut Vec<Range<usize>>,
+    max_lines: usize,
+    max_width: f64,
+    mut width_of: impl FnMut(&str) -> f64,
+) -> Option<String> {
+    let max_lines = max_lines.max(1);
+    if lines.len() <= max_lines {
+        return None;
+    }
+    lines.truncate(max_lines);
+    let last = lines.last_mut().unwrap();
+    let mut visible = text[last.clone()].trim_end();
+    while !visible.is_empty() && width_of(&format!("{}{}", visible, ELLIPSIS)) > max_width {
+        let end = visible.char_indices().last().map_or(0, |(idx, _)| idx);
+        visible = visible[..end].trim_end();
+    }
+    let mut clamped = text[..last.start + visible.len()].to_owned();
+    clamped.push_str(ELLIPSIS);
+    last.end = clamped.len();
+    Some(clamped)
+}
+
+/// Returns the number of bytes of trailing whitespace (including any newline) in `text`.
+pub(crate) fn trailing_whitespace(text: &str) -> usize {
+    text.len() - text.trim_end().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every char is 1.0 wide, which keeps these tests independent of fonts.
+    fn char_width(text: &str) -> f64 {
+        text.chars().count() as f64
+    }
+
+    fn line_texts<'a>(text: &'a str, max_width: f64) -> Vec<&'a str> {
+        break_lines(text, max_width, 0.0, WrapMode::Word, char_width)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn no_wrapping_when_wide_enough() {
+        assert_eq!(
+            line_texts("hello world", f64::INFINITY),
+            vec!["hello world"]
+        );
+    }
+
+    #[test]
+    fn wraps_at_soft_breaks() {
+        assert_eq!(
+            line_texts("hello world again", 12.0),
+            vec!["hello world ", "again"]
+        );
+        assert_eq!(
+            line_texts("hello world again", 5.0),
+            vec!["hello ", "world ", "again"]
+        );
+    }
+
+    #[test]
+    fn long_words_overflow() {
+        assert_eq!(
+            line_texts("a verylongword b", 4.0),
+            vec!["a ", "verylongword ", "b"]
+        );
+    }
+
+    #[test]
+    fn character_wrap_splits_long_words() {
+        let text = "a verylongword b";
+        let lines: Vec<&str> = break_lines(text, 4.0, 0.0, WrapMode::Character, char_width)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect();
+        assert_eq!(lines, vec!["a ", "very", "long", "word ", "b"]);
+    }
+
+    #[test]
+    fn character_wrap_keeps_clusters_together() {
+        // "e" followed by a combining acute accent is a single cluster, which
+        // is two chars wide but mustn't be split.
+        let text = "ae\u{301}e\u{301}";
+        assert_eq!(
+            break_lines(text, 2.0, 0.0, WrapMode::Character, char_width),
+            vec![0..1, 1..4, 4..7]
+        );
+    }
+
+    #[test]
+    fn first_line_is_narrower_when_indented() {
+        let text = "one two three";
+        assert_eq!(
+            break_lines(text, 8.0, 2.0, WrapMode::Word, char_width),
+            vec![0..4, 4..8, 8..13]
+        );
+        assert_eq!(
+            break_lines(text, 8.0, 0.0, WrapMode::Word, char_width),
+            vec![0..8, 8..13]
+        );
+    }
+
+    #[test]
+    fn hard_breaks() {
+        assert_eq!(line_texts("one\ntwo", f64::INFINITY), vec!["one\n", "two"]);
+    }
+
+    #[test]
+    fn clamped_lines_end_in_an_ellipsis() {
+        let text = "one two three four";
+        let mut lines = break_lines(text, 8.0, 0.0, WrapMode::Word, char_width);
+        assert_eq!(lines.len(), 3);
+        let clamped = clamp_lines(text, &mut lines, 2, 8.0, char_width).unwrap();
+        assert_eq!(clamped, "one two three\u{2026}");
+        assert_eq!(lines, vec![0..8, 8..clamped.len()]);
+
+        // the last line loses characters to make room for the ellipsis.
+        let mut lines = break_lines(text, 5.0, 0.0, WrapMode::Word, char_width);
+        let clamped = clamp_lines(text, &mut lines, 1, 3.0, char_width).unwrap();
+        assert_eq!(clamped, "on\u{2026}");
+        assert_eq!(
+            clamp_lines(text, &mut vec![0..18], 1, 3.0, char_width),
+            None
+        );
+    }
+
+    #[test]
+    fn trailing_newline_adds_an_empty_line() {
+        assert_eq!(line_texts("a\n", f64::INFINITY), vec!["a\n", ""]);
+        assert_eq!(line_texts("a\r\n", f64::INFINITY), vec!["a\r\n", ""]);
+        assert_eq!(line_texts("\n", f64::INFINITY), vec!["\n", ""]);
+    }
+
+    #[test]
+    fn empty_text_has_one_line() {
+        assert_eq!(
+            break_lines("", 10.0, 0.0, WrapMode::Word, char_width),
+            vec![0..0]
+        );
+    }
+}